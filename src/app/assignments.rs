@@ -3,8 +3,6 @@ use std::{mem, collections::BTreeMap, sync::Arc};
 use anyhow::{anyhow, Result};
 use async_trait::async_trait;
 use crossterm::event::{Event, KeyCode};
-use reqwest::Method;
-use serde::Deserialize;
 use tui::{Frame, backend::Backend};
 
 use crate::{imark::{Globals, Authentication, Journal, Journals, JournalTag, JournalMeta}, ui::{AppPage, journals::JournalsUi, UiPage, assignments::AssignmentsUi}, util::task::{Task, TaskRunner}};
@@ -91,6 +89,7 @@ impl<B: Backend + Send + 'static> AppPage<B> for AppPostAuth<B> {
             None => return Ok(None),
         };
 
+        #[allow(clippy::single_match)]
         match event {
             Event::Key(key) => {
                 match key.code {
@@ -145,38 +144,15 @@ pub struct FetchJournalsTask {
 #[async_trait]
 impl TaskRunner<FetchJournalsOutput> for FetchJournalsTask {
     async fn run(self) -> Result<FetchJournalsOutput> {
-        #[derive(Deserialize, Debug)]
-        pub struct JournalsJson {
-            submissions: BTreeMap<String, Group>,
-        }
-
-        type Group = BTreeMap<String, SubmissionJson>;
-
-        #[derive(Deserialize, Debug)]
-        pub struct SubmissionJson {
-            name: String,
-            provisional_mark: Option<f64>,
-            mark: Option<f64>,
-            notes: Option<String>,
-        }
-
-        let imark_cgi_endpoint = self.globals.cgi_endpoint();
         let auth = self.auth;
         let assignment = self.assignment;
 
-        let imark_full_endpoint = format!("{imark_cgi_endpoint}/api/v1/assignments/{assignment}/submissions/");
-
-        let client = reqwest::Client::new();
-        let resp: JournalsJson = client.request(Method::GET, imark_full_endpoint)
-            .basic_auth(auth.username(), Some(auth.password()))
-            .send()
-            .await?
-            .json()
-            .await?;
+        let submissions = self.globals.imark().list_submissions(&auth, &assignment).await?;
         
         let mut journals = Journals::new(self.globals.clone());
         
-        let mut flattened_journals = resp.submissions.into_iter()
+        #[allow(clippy::map_flatten)]
+        let mut flattened_journals = submissions.into_iter()
             .map(|(group_id, group)| {
                 group.into_iter()
                     .map(|(student_id, submission)| {
@@ -199,6 +175,7 @@ impl TaskRunner<FetchJournalsOutput> for FetchJournalsTask {
             .flatten()
             .collect::<Vec<_>>();
 
+        #[allow(clippy::len_zero)]
         if flattened_journals.len() == 0 {
             return anyhow::Result::Err(anyhow!("No journals found for selected assignment"));
         }
@@ -207,6 +184,8 @@ impl TaskRunner<FetchJournalsOutput> for FetchJournalsTask {
             journals.insert(tag, meta);
         }
 
+        journals.assign_shares(auth.username());
+
         anyhow::Ok(
             FetchJournalsOutput {
                 assignment,
@@ -1,13 +1,14 @@
 use std::mem;
 
-use anyhow::{Result, Context};
+use anyhow::Result;
 use async_trait::async_trait;
-use crossterm::event::Event;
-use reqwest::Method;
+use crossterm::event::{Event, KeyCode};
 use tokio::sync::oneshot;
 use tui::{backend::Backend, Frame};
 use tui_input::{Input, InputResponse, backend::crossterm as tui_input_crossterm};
-use crate::{ui::{AppPage, UiPage, auth::AuthUi}, imark::{Globals, Authentication}, util::task::{Task, TaskRunner, self}};
+use std::sync::Arc;
+
+use crate::{ui::{AppPage, UiPage, auth::AuthUi}, imark::{self, Globals, Authentication, InvalidCredentials, Prepared, client::ImarkClient, credentials::StoredCredentials}, marklog::{MarkLog, PendingMark}, util::task::{Task, TaskRunner, self}};
 
 use super::assignments::AppPostAuth;
 
@@ -18,9 +19,27 @@ pub struct AppPreAuth<B> {
 }
 
 pub enum AppPreAuthState {
-    EnteringZid { zid_input: Input },
-    EnteringPassword { zid: String, password_input: Input },
+    /// `error` is why the last attempt to log in failed, if there was one.
+    EnteringZid { zid_input: Input, error: Option<String> },
+    EnteringPassword { zid: String, password_input: Input, error: Option<String> },
     Authenticating { zid: String, password: String, task: Task<AuthTaskOutput> },
+    ReplayingMarks { auth: Authentication, assignments: Vec<String>, task: Task<Vec<String>> },
+    /// Some marks couldn't be replayed -- `failures` says which and why.
+    ReplayFailed { auth: Authentication, assignments: Vec<String>, failures: Vec<String> },
+}
+
+impl AppPreAuthState {
+    fn authenticate(globals: &Globals, zid: String, password: String) -> Self {
+        let task = Task::new(
+            AuthenticateTask::new(
+                globals.imark().clone(),
+                Authentication::new(zid.to_string(), password.to_string()),
+            ),
+            globals.panic_on_drop(),
+        );
+
+        AppPreAuthState::Authenticating { zid, password, task }
+    }
 }
 
 pub struct AuthTaskOutput {
@@ -28,10 +47,24 @@ pub struct AuthTaskOutput {
 }
 
 impl<B> AppPreAuth<B> {
-    pub fn new(globals: Globals) -> Self {
+    /// Starts at whichever screen is needed for what `credentials` is missing,
+    /// going straight to authenticating if nothing is.
+    pub fn new(globals: Globals, credentials: StoredCredentials) -> Self {
+        let state = match credentials {
+            StoredCredentials { zid: Some(zid), password: Some(password) } => {
+                AppPreAuthState::authenticate(&globals, zid, password)
+            }
+            StoredCredentials { zid: Some(zid), password: None } => {
+                AppPreAuthState::EnteringPassword { zid, password_input: Input::default(), error: None }
+            }
+            StoredCredentials { zid: None, .. } => {
+                AppPreAuthState::EnteringZid { zid_input: Input::default(), error: None }
+            }
+        };
+
         Self {
             globals,
-            state: AppPreAuthState::EnteringZid { zid_input: Input::default() },
+            state,
             ui: AuthUi::new()
         }
     }
@@ -49,58 +82,126 @@ impl<B> AppPreAuth<B> {
 impl<B: Backend + Send + 'static> AppPage<B> for AppPreAuth<B> {
     async fn tick(&mut self, io: Option<Event>) -> Result<Option<Box<dyn AppPage<B>>>> {
         match &mut self.state {
-            AppPreAuthState::EnteringZid { zid_input } => {
+            AppPreAuthState::EnteringZid { zid_input, error } => {
                 if let Some(event) = io {
-                    let submitted = process_input(event, zid_input);
+                    match process_input(event, zid_input) {
+                        InputAction::Submitted => {
+                            self.state = AppPreAuthState::EnteringPassword {
+                                zid: zid_input.value().to_string(),
+                                password_input: Input::default(),
+                                error: error.take(),
+                            };
+                        }
+                        InputAction::Escaped | InputAction::None => {}
+                    }
+                }
+            }
+            AppPreAuthState::EnteringPassword { zid, password_input, error } => {
+                if let Some(event) = io {
+                    match process_input(event, password_input) {
+                        InputAction::Submitted => {
+                            let password = password_input.value().to_string();
 
-                    if submitted {
+                            let zid = mem::take(zid);
+
+                            self.state = AppPreAuthState::authenticate(&self.globals, zid, password);
+                        }
+                        InputAction::Escaped => {
+                            // back to fix the zid
+                            self.state = AppPreAuthState::EnteringZid {
+                                zid_input: Input::default().with_value(mem::take(zid)),
+                                error: error.take(),
+                            };
+                        }
+                        InputAction::None => {}
+                    }
+                }
+            }
+            AppPreAuthState::Authenticating { zid, password, task } => {
+                let output = match task.poll() {
+                    Ok(output) => output,
+                    Err(err) if err.is::<InvalidCredentials>() => {
                         self.state = AppPreAuthState::EnteringPassword {
-                            zid: zid_input.value().to_string(),
+                            zid: mem::take(zid),
                             password_input: Input::default(),
+                            error: Some(String::from("Incorrect zid or password -- try again, or <esc> to change zid")),
+                        };
+
+                        return Ok(None);
+                    }
+                    Err(err) => {
+                        // the credentials may well be fine, so keep them for another go
+                        self.state = AppPreAuthState::EnteringPassword {
+                            zid: mem::take(zid),
+                            password_input: Input::default().with_value(mem::take(password)),
+                            error: Some(format!(
+                                "Couldn't reach imark -- is it down, or is `{}` the wrong cgi endpoint?\n{err:#}",
+                                self.globals.cgi_endpoint(),
+                            )),
                         };
+
+                        return Ok(None);
+                    }
+                };
+
+                if let Some(output) = output {
+                    let auth = Authentication::new(mem::take(zid), mem::take(password));
+
+                    if self.globals.replay().is_empty() {
+                        return Ok(Some(Box::new(
+                            AppPostAuth::new(
+                                self.globals.clone(),
+                                auth,
+                                output.assignments,
+                            )
+                        )));
                     }
+
+                    let task = Task::new(
+                        ReplayMarksTask {
+                            marks: self.globals.replay().to_vec(),
+                            auth: auth.clone(),
+                            imark: self.globals.imark().clone(),
+                            mark_log: self.globals.mark_log().cloned(),
+                        },
+                        self.globals.panic_on_drop(),
+                    );
+
+                    self.state = AppPreAuthState::ReplayingMarks { auth, assignments: output.assignments, task };
                 }
             }
-            AppPreAuthState::EnteringPassword { zid, password_input } => {
-                if let Some(event) = io {
-                    let submitted = process_input(event, password_input);
-                    if submitted {
-                        let password = password_input.value().to_string();
-
-                        let zid = mem::take(zid);
-
-                        self.state = AppPreAuthState::Authenticating {
-                            zid: zid.to_string(),
-                            password: password.to_string(),
-                            task: Task::new(
-                                AuthenticateTask::new(
-                                    self.globals.cgi_endpoint().to_string(),
-                                    zid,
-                                    password,
-                                ),
-                                self.globals.panic_on_drop(),
-                            ),
+            AppPreAuthState::ReplayingMarks { auth, assignments, task } => {
+                match task.poll()? {
+                    Some(failures) if !failures.is_empty() => {
+                        self.state = AppPreAuthState::ReplayFailed {
+                            auth: auth.clone(),
+                            assignments: mem::take(assignments),
+                            failures,
                         };
                     }
+                    Some(_) => {
+                        return Ok(Some(Box::new(
+                            AppPostAuth::new(
+                                self.globals.clone(),
+                                auth.clone(),
+                                mem::take(assignments),
+                            )
+                        )));
+                    }
+                    None => {}
                 }
             }
-            AppPreAuthState::Authenticating { zid, password, task } => {
-                if let Some(output) = task.poll()
-                    .with_context(||
-                        format!(
-                            "Are your credentials incorrect?\n\
-                             Is imark down?\n\
-                             Is `{}` the correct imark cgi endpoint?",
-                            self.globals.cgi_endpoint(),
-                        )
-                    )? {
-                    return Ok(Some(Box::new(
-                        AppPostAuth::new(
-                            self.globals.clone(),
-                            Authentication::new(mem::take(zid), mem::take(password)),
-                            output.assignments,
-                        )
-                    )));
+            AppPreAuthState::ReplayFailed { auth, assignments, .. } => {
+                if let Some(Event::Key(key)) = io {
+                    if key.code == KeyCode::Enter {
+                        return Ok(Some(Box::new(
+                            AppPostAuth::new(
+                                self.globals.clone(),
+                                auth.clone(),
+                                mem::take(assignments),
+                            )
+                        )));
+                    }
                 }
             }
         }
@@ -119,17 +220,15 @@ impl<B: Backend + Send + 'static> AppPage<B> for AppPreAuth<B> {
 }
 
 struct AuthenticateTask {
-    imark_cgi_endpoint: String,
-    zid: String,
-    password: String,
+    imark: Arc<dyn ImarkClient>,
+    auth: Authentication,
 }
 
 impl AuthenticateTask {
-    pub fn new(imark_cgi_endpoint: String, zid: String, password: String) -> Self {
+    pub fn new(imark: Arc<dyn ImarkClient>, auth: Authentication) -> Self {
         Self {
-            imark_cgi_endpoint,
-            zid,
-            password,
+            imark,
+            auth,
         }
     }
 }
@@ -137,31 +236,118 @@ impl AuthenticateTask {
 #[async_trait]
 impl TaskRunner<AuthTaskOutput> for AuthenticateTask {
     async fn run(self) -> Result<AuthTaskOutput> {
-        let imark_cgi_endpoint = self.imark_cgi_endpoint;
-        let zid = self.zid;
-        let password = self.password;
-
-        let client = reqwest::Client::new();
-        let resp: Vec<String> = client.request(Method::GET, format!("{imark_cgi_endpoint}/api/v1/assignments/"))
-            .basic_auth(&zid, Some(&password))
-            .send()
-            .await?
-            .json()
-            .await?;
-
-        anyhow::Ok(AuthTaskOutput { assignments: resp })
+        let assignments = self.imark.list_assignments(&self.auth).await?;
+
+        anyhow::Ok(AuthTaskOutput { assignments })
+    }
+}
+
+struct ReplayMarksTask {
+    marks: Vec<PendingMark>,
+    auth: Authentication,
+    imark: Arc<dyn ImarkClient>,
+    mark_log: Option<MarkLog>,
+}
+
+/// Replays each mark on its own, returning why any couldn't be -- those stay
+/// in the mark log, to be offered again next time.
+#[async_trait]
+impl TaskRunner<Vec<String>> for ReplayMarksTask {
+    async fn run(self) -> Result<Vec<String>> {
+        let mut failures = vec![];
+
+        for pending in self.marks {
+            let student_id = pending.tag.student_id().to_string();
+
+            let pending = match imark::check_mark(self.imark.as_ref(), pending, &self.auth).await {
+                Ok(Prepared::Ready(pending)) => pending,
+                Ok(Prepared::Conflict(conflict)) => {
+                    let by = conflict.theirs.stamp.by.as_deref().unwrap_or("someone else");
+                    failures.push(format!("{student_id}: changed on imark by {by} since -- not replayed, so as not to overwrite it"));
+                    continue;
+                }
+                Err(err) => {
+                    failures.push(format!("{student_id}: {err:#}"));
+                    continue;
+                }
+            };
+
+            if let Err(err) = imark::send_mark(self.imark.as_ref(), &pending, &self.auth).await {
+                failures.push(format!("{student_id}: {err:#}"));
+                continue;
+            }
+
+            if let Some(mark_log) = &self.mark_log {
+                mark_log.record_committed(&pending.id)?;
+            }
+        }
+
+        Ok(failures)
     }
 }
 
-type Submitted = bool;
-fn process_input(event: Event, input: &mut Input) -> Submitted {
+enum InputAction {
+    Submitted,
+    Escaped,
+    None,
+}
+
+fn process_input(event: Event, input: &mut Input) -> InputAction {
     let response = tui_input_crossterm::to_input_request(event)
         .and_then(|req| input.handle(req));
 
     match response {
-        Some(InputResponse::Submitted) => true,
+        Some(InputResponse::Submitted) => InputAction::Submitted,
+        Some(InputResponse::Escaped) => InputAction::Escaped,
         Some(InputResponse::StateChanged(_))
-        | Some(InputResponse::Escaped)
-        | None => false,
+        | None => InputAction::None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::imark::{Journals, tests::*};
+    use super::*;
+
+    async fn prepare(journals: &Journals) -> PendingMark {
+        match journals.prepare_mark(&tag(), submission(), auth()).await.unwrap() {
+            Prepared::Ready(pending) => pending,
+            Prepared::Conflict(_) => panic!("nobody else changed the mark"),
+        }
+    }
+
+    fn replay(imark: Arc<dyn ImarkClient>, marks: Vec<PendingMark>) -> ReplayMarksTask {
+        ReplayMarksTask { marks, auth: auth(), imark, mark_log: None }
+    }
+
+    #[tokio::test]
+    async fn one_failed_replay_does_not_stop_the_rest() {
+        let imark = fake_imark();
+        let journals = loaded_journals(imark.clone()).await;
+
+        let first  = prepare(&journals).await;
+        let second = prepare(&journals).await;
+
+        imark.fail_next_gets(1);
+        let failures = replay(imark.clone(), vec![first, second.clone()]).run().await.unwrap();
+
+        assert_eq!(failures.len(), 1);
+        assert!(failures[0].contains("unreachable"));
+        assert_eq!(remote_text(&imark), second.text);
+    }
+
+    #[tokio::test]
+    async fn does_not_replay_over_another_markers_changes() {
+        let imark = fake_imark();
+        let journals = loaded_journals(imark.clone()).await;
+
+        let pending = prepare(&journals).await;
+        other_marker_writes(&imark, "their feedback\n").await;
+
+        let failures = replay(imark.clone(), vec![pending]).run().await.unwrap();
+
+        assert_eq!(failures.len(), 1);
+        assert!(failures[0].contains("z2222222"));
+        assert_eq!(remote_text(&imark), "their feedback\n");
     }
 }
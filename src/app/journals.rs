@@ -6,7 +6,7 @@ use crossterm::event::{Event, KeyCode};
 use tui::{backend::Backend, Frame};
 use tui_input::{Input, InputResponse, backend::crossterm as tui_input_crossterm};
 
-use crate::{imark::{Globals, Authentication, Journals, JournalTag}, ui::{AppPage, journals::JournalsUi, UiPage}, util::task::{Task, TaskRunner}};
+use crate::{filter::Filter, imark::{Globals, Authentication, Journals, JournalTag, ordering::SortOrder}, ui::{AppPage, journals::JournalsUi, UiPage}, util::task::{Task, TaskRunner}};
 
 use super::marking::{AppMarking, Opened};
 
@@ -18,12 +18,15 @@ pub struct AppJournalList<B> {
     journals_view: Vec<JournalTag>,
     current_index: usize,
     filter: Input,
+    filter_error: Option<String>,
+    /// The last filter that parsed, which the view shows.
+    active_filter: Filter,
     ui: JournalsUi<B>,
 }
 
 impl<B> AppJournalList<B> {
     pub fn new(globals: Globals, auth: Authentication, assignment: String, journals: Journals) -> Self {
-        let journals_view = filter_journals(&journals, "").cloned().collect();
+        let journals_view = filter_journals(&journals, &Filter::All).cloned().collect();
 
         Self {
             globals,
@@ -33,6 +36,8 @@ impl<B> AppJournalList<B> {
             journals_view,
             current_index: 0,
             filter: Input::default(),
+            filter_error: None,
+            active_filter: Filter::All,
             ui: JournalsUi::new(),
         }
     }
@@ -64,22 +69,46 @@ impl<B> AppJournalList<B> {
     pub fn filter(&self) -> &Input {
         &self.filter
     }
+
+    /// Why the filter as typed can't be used, if it can't.
+    pub fn filter_error(&self) -> Option<&str> {
+        self.filter_error.as_deref()
+    }
+
+    fn apply_filter(&mut self) {
+        match Filter::parse(self.filter.value()) {
+            Ok(filter) => {
+                self.journals_view = filter_journals(&self.journals, &filter).cloned().collect();
+                self.current_index = 0;
+                self.filter_error  = None;
+                self.active_filter = filter;
+            }
+            // keep showing the last good filter's journals while the query is half-typed
+            Err(err) => self.filter_error = Some(err.to_string()),
+        }
+    }
+
+    /// Re-sorts the list, keeping the same journal selected.
+    fn set_sort_order(&mut self, sort_order: SortOrder) {
+        let selected = self.journals_view.get(self.current_index).cloned();
+
+        self.journals.set_sort_order(sort_order);
+        self.journals_view = filter_journals(&self.journals, &self.active_filter).cloned().collect();
+        self.current_index = selected
+            .and_then(|selected| self.journals_view.iter().position(|tag| *tag == selected))
+            .unwrap_or(0);
+    }
 }
 
-pub fn filter_journals<'j, 'f: 'j>(journals: &'j Journals, filter: &'f str) -> impl Iterator<Item = &'j JournalTag> {
-    journals.iter()
+pub fn filter_journals<'j>(journals: &'j Journals, filter: &'j Filter) -> impl Iterator<Item = &'j JournalTag> {
+    let show_others = journals.globals().show_others();
+
+    journals.iter_all()
+        .filter(move |(tag, _)| show_others || journals.is_mine(tag))
         .filter(move |(tag, meta)| {
-            tag.student_id().contains(filter)
-            || if let Ok(meta) = meta.try_lock() {
-                let meta = meta.meta();
-
-                meta.name().to_uppercase().contains(&filter.to_uppercase())
-                || meta.mark().map(|m| format!("{:>5.02}", m))
-                    .or(meta.provisional_mark().map(|m| format!("{:>5.02}?", m)))
-                    .map_or(false, |mark| mark.contains(&filter))
-                || meta.notes().map_or(false, |notes| notes.to_uppercase().contains(&filter.to_uppercase()))
-            } else {
-                false
+            match meta.try_lock() {
+                Ok(meta) => filter.matches(tag, meta.meta()),
+                Err(_) => false,
             }
         })
         .map(|(tag, meta)| tag)
@@ -93,20 +122,32 @@ impl<B: Backend + Send + 'static> AppPage<B> for AppJournalList<B> {
             None => return Ok(None),
         };
 
+        #[allow(clippy::single_match)]
         match event {
             Event::Key(key) => {
                 match key.code {
+                    // a filter can match nothing, leaving nothing to move between or open
+                    KeyCode::Down | KeyCode::Up | KeyCode::Enter if self.journals_view.is_empty() => {}
+                    // other markers' journals are only there to look at
+                    KeyCode::Enter if !self.journals.is_mine(&self.journals_view[self.current_index]) => {}
                     KeyCode::Down => {
                         self.current_index = (self.current_index + 1) % self.journals_view.len();
                     }
                     KeyCode::Up => {
                         self.current_index = (self.current_index + self.journals_view.len() - 1) % self.journals_view.len();
                     }
+                    KeyCode::Tab => {
+                        self.set_sort_order(self.journals.sort_order().next());
+                    }
+                    KeyCode::BackTab => {
+                        self.set_sort_order(self.journals.sort_order().previous());
+                    }
                     KeyCode::Enter => {
                         let globals    = self.globals().clone();
                         let auth       = self.auth().clone();
                         let assignment = mem::take(&mut self.assignment);
                         let journals   = mem::take(&mut self.journals);
+                        #[allow(clippy::iter_nth)]
                         let live_journal_tag = self.journals_view.iter()
                             .nth(self.current_index)
                             .expect("journal cannot just disappear")
@@ -129,8 +170,7 @@ impl<B: Backend + Send + 'static> AppPage<B> for AppJournalList<B> {
                             .and_then(|req| self.filter.handle(req)) {
                             match response {
                                 InputResponse::StateChanged(state) if state.value => {
-                                    self.journals_view = filter_journals(&self.journals, &self.filter.value()).cloned().collect();
-                                    self.current_index = 0;
+                                    self.apply_filter();
                                 }
                                 _ => {}
                             }
@@ -158,3 +198,33 @@ impl<B: Backend + Send + 'static> AppPage<B> for AppJournalList<B> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tui::backend::TestBackend;
+
+    use crate::imark::{JournalMeta, ordering::SortKey, tests::*};
+    use super::*;
+
+    #[test]
+    fn resorting_keeps_the_same_journal_selected() {
+        let globals = Globals::headless(String::from("http://imark.test"), String::from(MARK_NAME), false, None, fake_imark());
+        let mut journals = Journals::new(globals.clone());
+
+        for (student_id, name) in [("z5000001", "Dave"), ("z5000002", "Carol"), ("z5000003", "Bob"), ("z5000004", "Alice")] {
+            journals.insert(
+                JournalTag::new(String::from("ass1"), String::from("g1"), student_id.to_string()),
+                JournalMeta::new(name.to_string(), None, None, None),
+            );
+        }
+
+        let mut app = AppJournalList::<TestBackend>::new(globals, auth(), String::from("ass1"), journals);
+        app.set_sort_order(SortOrder::new(SortKey::Zid, 0));
+        app.current_index = 1;
+
+        app.set_sort_order(SortOrder::new(SortKey::Name, 0));
+
+        assert_eq!(app.journals_view()[app.current_index()].student_id(), "z5000002");
+        assert_eq!(app.current_index(), 2);
+    }
+}
@@ -6,8 +6,9 @@ use crossterm::event::{Event, KeyCode, KeyModifiers};
 use tmux_interface::{RespawnPane, SplitWindow};
 use tokio::fs::{remove_file, symlink, read_link};
 use tui::{backend::Backend, Frame};
+use tui_input::{Input, InputResponse, backend::crossterm as tui_input_crossterm};
 
-use crate::{imark::{Globals, Authentication, Journals, JournalTag, BidirectionalIterator}, choice::{ChoiceSelections, Choice}, ui::{marking::MarkingUi, AppPage, UiPage}, util::{task::Task, tmux::TmuxPane, HOTKEYS}};
+use crate::{config::{Action, KeyBindings, RETRY_KEY, DISCARD_KEY}, imark::{Globals, Authentication, Journals, JournalTag, BidirectionalIterator, MarkSubmission, submission::Resolution}, choice::{self, ChoiceSelections, Choice, MarkOverride}, ui::{marking::MarkingUi, AppPage, UiPage}, util::{task::Task, tmux::TmuxPane}};
 
 use super::{assignments::{FetchJournalsOutput, FetchJournalsTask}, journals::AppJournalList};
 
@@ -20,6 +21,8 @@ pub struct AppMarking<B> {
     opened: Opened,
     tmux_side_pane: Option<TmuxPane>,
     state: AppMarkingState,
+    /// The resolution picked for the oldest conflict, waiting on <enter>.
+    conflict_choice: Option<Resolution>,
     ui: MarkingUi<B>,
 }
 
@@ -65,12 +68,26 @@ pub enum AppMarkingState {
     JournalReadyToQueue,
     JournalLoading,
     JournalLoaded,
-    Marking { choices: ChoiceSelections },
+    Marking {
+        choices: ChoiceSelections,
+        comment: Input,
+        mark_override: Option<MarkOverride>,
+        stale_lines: Vec<String>,
+        focus: MarkingFocus,
+    },
     WaitingToGoBack { back: JournalTag },
     WaitingToReturn,
     Returning { task: Task<FetchJournalsOutput> },
 }
 
+pub enum MarkingFocus {
+    Choices,
+    Comment,
+    OverrideMark { mark_input: Input, error: Option<String> },
+    OverrideReason { mark: f64, reason_input: Input },
+    Failures { cursor: usize },
+}
+
 impl<B> AppMarking<B> {
     pub async fn new(
         globals: Globals,
@@ -104,6 +121,7 @@ impl<B> AppMarking<B> {
             opened,
             tmux_side_pane,
             state: AppMarkingState::JournalReadyToQueue,
+            conflict_choice: None,
             ui: MarkingUi::new(),
         }
     }
@@ -136,6 +154,18 @@ impl<B> AppMarking<B> {
         self.opened
     }
 
+    pub fn conflict_choice(&self) -> Option<Resolution> {
+        self.conflict_choice
+    }
+
+    /// Whether a conflict is waiting to be settled, and nothing else (e.g. the
+    /// comment being typed) has hold of the keyboard.
+    pub fn conflict_has_focus(&self) -> bool {
+        let busy = matches!(&self.state, AppMarkingState::Marking { focus, .. } if !matches!(focus, MarkingFocus::Choices));
+
+        !busy && self.journals.conflicts().next().is_some()
+    }
+
     async fn calculate_n_journals_till_marked(opened: Opened, journals: &Journals, live_journal_tag: &JournalTag) -> usize {
         if let Opened::Automatically { n_journals_till_marked } = opened {
             return n_journals_till_marked;
@@ -166,7 +196,7 @@ impl<B: Backend + Send + 'static> AppPage<B> for AppMarking<B> {
                 {
                     self.state = AppMarkingState::JournalLoaded;
                 } else if matches!(self.state, AppMarkingState::JournalReadyToQueue) {
-                    self.journals.queue_load(self.live_journal_tag.clone(), self.globals.cgi_endpoint(), self.auth.clone(), self.globals.mark_name());
+                    self.journals.queue_load(self.live_journal_tag.clone(), self.auth.clone(), self.globals.mark_name());
 
                     self.state = AppMarkingState::JournalLoading;
                 }
@@ -194,6 +224,11 @@ impl<B: Backend + Send + 'static> AppPage<B> for AppMarking<B> {
                     shell_command += name;
                 }
 
+                let previous_marking = match journal_data.marking_file(self.globals.mark_name()) {
+                    Some(file) => choice::parse_previous_marking(&file.read_text()?),
+                    None => None,
+                }.unwrap_or_default();
+
                 drop(journal);
 
                 match self.tmux_side_pane.as_ref() {
@@ -205,9 +240,16 @@ impl<B: Backend + Send + 'static> AppPage<B> for AppMarking<B> {
                     }
                 }
     
-                let choice_selections = ChoiceSelections::new(self.globals().choices());
+                let mut choice_selections = ChoiceSelections::new(self.globals().choices());
+                let stale_lines = choice_selections.restore(&previous_marking.mark_lines);
 
-                self.state = AppMarkingState::Marking { choices: choice_selections };
+                self.state = AppMarkingState::Marking {
+                    choices: choice_selections,
+                    comment: Input::default().with_value(previous_marking.comment),
+                    mark_override: previous_marking.mark_override,
+                    stale_lines,
+                    focus: MarkingFocus::Choices,
+                };
 
                 let mut next_journals_iter = self.journals.iter();
                 let _ = next_journals_iter.find(|(tag, _)| *tag == &self.live_journal_tag);
@@ -217,13 +259,14 @@ impl<B: Backend + Send + 'static> AppPage<B> for AppMarking<B> {
                     .collect::<Vec<_>>();
 
                 for next_journal in next_journals {
-                    self.journals.queue_load(next_journal, self.globals.cgi_endpoint(), self.auth.clone(), self.globals.mark_name());
+                    self.journals.queue_load(next_journal, self.auth.clone(), self.globals.mark_name());
                 }
             }
             AppMarkingState::Marking { .. } => {}
             AppMarkingState::WaitingToGoBack { back } => {
                 if self.journals.scan_queue()? == 0 {
                     // slow but safe
+                    #[allow(clippy::needless_borrow)]
                     self.journals.unload(&back).await;
 
                     return Ok(Some(Box::new(
@@ -240,7 +283,8 @@ impl<B: Backend + Send + 'static> AppPage<B> for AppMarking<B> {
                 }
             }
             AppMarkingState::WaitingToReturn => {
-                if self.journals.scan_queue()? == 0 {
+                // conflicts have to be settled here, as the journal list can't show them
+                if self.journals.scan_queue()? == 0 && self.journals.conflicts().next().is_none() {
                     let globals    = self.globals.clone();
                     let auth       = self.auth.clone();
                     let assignment = self.assignment.to_string();
@@ -258,7 +302,10 @@ impl<B: Backend + Send + 'static> AppPage<B> for AppMarking<B> {
                 }
             }
             AppMarkingState::Returning { task } => {
-                if let Some(output) = task.poll()? {
+                if let Some(mut output) = task.poll()? {
+                    output.journals.adopt_failed_submissions(&mut self.journals);
+                    output.journals.set_sort_order(self.journals.sort_order());
+
                     return Ok(Some(Box::new(
                         AppJournalList::new(
                             self.globals.clone(),
@@ -280,27 +327,71 @@ impl<B: Backend + Send + 'static> AppPage<B> for AppMarking<B> {
             None => return Ok(None),
         };
 
+        if self.conflict_has_focus() {
+            process_conflict_input(event, &mut self.conflict_choice, &mut self.journals)?;
+            return Ok(None);
+        }
+
         match &mut self.state {
             AppMarkingState::JournalReadyToQueue
             | AppMarkingState::JournalLoading
             | AppMarkingState::JournalLoaded => {}
-            AppMarkingState::Marking { choices } => {
+            AppMarkingState::Marking { focus, .. } if matches!(focus, MarkingFocus::Failures { .. }) => {
+                let close = match focus {
+                    MarkingFocus::Failures { cursor } => process_failures_input(event, cursor, &mut self.journals, self.globals.keys())?,
+                    _ => unreachable!(),
+                };
+
+                let n_failed = self.journals.failed_submissions().count();
+                match focus {
+                    _ if close || n_failed == 0 => *focus = MarkingFocus::Choices,
+                    MarkingFocus::Failures { cursor } => *cursor = (*cursor).min(n_failed - 1),
+                    _ => unreachable!(),
+                }
+            }
+            AppMarkingState::Marking { comment, mark_override, focus, .. } if !matches!(focus, MarkingFocus::Choices) => {
+                process_prompt_input(event, focus, comment, mark_override, &self.globals);
+            }
+            AppMarkingState::Marking { choices, comment, mark_override, focus, .. } => {
+                #[allow(clippy::single_match)]
                 match event {
                     Event::Key(key) => {
-                        match (key.modifiers, key.code) {
-                            (KeyModifiers::NONE, KeyCode::Down | KeyCode::Char('j'))  => {
+                        let action = match (key.modifiers, key.code) {
+                            (KeyModifiers::NONE, KeyCode::Down)    => Some(Action::Down),
+                            (KeyModifiers::NONE, KeyCode::Up)      => Some(Action::Up),
+                            (KeyModifiers::NONE, KeyCode::Right)   => Some(Action::Toggle),
+                            (KeyModifiers::NONE, KeyCode::Char(c)) => self.globals.keys().action(c),
+                            _ => None,
+                        };
+
+                        match (action, key.modifiers, key.code) {
+                            (Some(Action::Down), _, _) => {
                                 choices.cursor_next();
                             }
-                            (KeyModifiers::NONE, KeyCode::Up | KeyCode::Char('k'))   => {
+                            (Some(Action::Up), _, _) => {
                                 choices.cursor_prev();
                             }
-                            (KeyModifiers::NONE, KeyCode::Char(' ') | KeyCode::Right) => {
+                            (Some(Action::Toggle), _, _) => {
                                 choices.toggle_selection();
                             }
-                            (KeyModifiers::NONE, KeyCode::Char('q')) => {
+                            (Some(Action::Comment), _, _) => {
+                                *focus = MarkingFocus::Comment;
+                            }
+                            (Some(Action::OverrideMark), _, _) => {
+                                let mark_input = match mark_override {
+                                    Some(mark_override) => Input::default().with_value(mark_override.mark.to_string()),
+                                    None => Input::default(),
+                                };
+
+                                *focus = MarkingFocus::OverrideMark { mark_input, error: None };
+                            }
+                            (Some(Action::Failures), _, _) if self.journals.failed_submissions().next().is_some() => {
+                                *focus = MarkingFocus::Failures { cursor: 0 };
+                            }
+                            (Some(Action::Quit), _, _) => {
                                 self.state = AppMarkingState::WaitingToReturn;
                             }
-                            (KeyModifiers::NONE, KeyCode::Char('b')) => {
+                            (Some(Action::Back), _, _) => {
                                 let mut journals_iter = self.journals.iter();
                                 journals_iter.find(|(tag, _)| *tag == self.live_journal_tag());
                                 journals_iter.next_back();
@@ -315,7 +406,7 @@ impl<B: Backend + Send + 'static> AppPage<B> for AppMarking<B> {
                                     }
                                 }
                             }
-                            (KeyModifiers::NONE, KeyCode::Char('s')) => {
+                            (Some(Action::Skip), _, _) => {
                                 match self.opened {
                                     Opened::Automatically { n_journals_till_marked: 0 } => {
                                         self.state = AppMarkingState::WaitingToReturn;
@@ -349,16 +440,20 @@ impl<B: Backend + Send + 'static> AppPage<B> for AppMarking<B> {
                                     }
                                 }
                             }
-                            (KeyModifiers::NONE, KeyCode::Char(c)) if HOTKEYS.contains(c) => {
-                                let char_index = HOTKEYS.find(c).expect("Must be in HOTKEYS.");
-                                if choices.try_cursor_set(char_index) {
+                            (_, KeyModifiers::NONE, KeyCode::Char(c)) => {
+                                if let Some(index) = choices.by_hotkey(c) {
+                                    choices.try_cursor_set(index);
                                     choices.toggle_selection();
                                 }
                             }
-                            (KeyModifiers::NONE, KeyCode::Enter) | (KeyModifiers::CONTROL, KeyCode::Char('j')) => {
+                            (_, KeyModifiers::NONE, KeyCode::Enter) | (_, KeyModifiers::CONTROL, KeyCode::Char('j')) => {
                                 self.journals.queue_mark(
                                     self.live_journal_tag.clone(),
-                                    mem::take(choices),
+                                    MarkSubmission {
+                                        choices:       mem::take(choices),
+                                        comment:       comment.value().to_string(),
+                                        mark_override: mark_override.take(),
+                                    },
                                     self.globals.cgi_endpoint(),
                                     self.auth().clone(),
                                     self.globals.mark_name(),
@@ -424,3 +519,252 @@ impl<B: Backend + Send + 'static> AppPage<B> for AppMarking<B> {
         Ok(())
     }
 }
+
+fn process_prompt_input(
+    event: Event,
+    focus: &mut MarkingFocus,
+    comment: &mut Input,
+    mark_override: &mut Option<MarkOverride>,
+    globals: &Globals,
+) {
+    let input = match focus {
+        MarkingFocus::Choices | MarkingFocus::Failures { .. } => return,
+        MarkingFocus::Comment => comment,
+        MarkingFocus::OverrideMark { mark_input, .. } => mark_input,
+        MarkingFocus::OverrideReason { reason_input, .. } => reason_input,
+    };
+
+    let response = tui_input_crossterm::to_input_request(event)
+        .and_then(|req| input.handle(req));
+
+    match response {
+        Some(InputResponse::Escaped) => {
+            *focus = MarkingFocus::Choices;
+        }
+        Some(InputResponse::Submitted) => {
+            match focus {
+                MarkingFocus::Choices | MarkingFocus::Failures { .. } => unreachable!(),
+                MarkingFocus::Comment => {
+                    *focus = MarkingFocus::Choices;
+                }
+                MarkingFocus::OverrideMark { mark_input, error } => {
+                    if mark_input.value().trim().is_empty() {
+                        *mark_override = None;
+                        *focus = MarkingFocus::Choices;
+                        return;
+                    }
+
+                    match validate_override_mark(mark_input.value(), globals) {
+                        Ok(mark) => {
+                            let reason = mark_override.as_ref()
+                                .map(|mark_override| mark_override.reason.to_string())
+                                .unwrap_or_default();
+
+                            *focus = MarkingFocus::OverrideReason {
+                                mark,
+                                reason_input: Input::default().with_value(reason),
+                            };
+                        }
+                        Err(err) => {
+                            *error = Some(err);
+                        }
+                    }
+                }
+                MarkingFocus::OverrideReason { mark, reason_input } => {
+                    let reason = reason_input.value().trim();
+
+                    // a reason is mandatory -- keep the prompt open until one is given
+                    if !reason.is_empty() {
+                        *mark_override = Some(MarkOverride {
+                            mark:   *mark,
+                            reason: reason.to_string(),
+                        });
+                        *focus = MarkingFocus::Choices;
+                    }
+                }
+            }
+        }
+        Some(InputResponse::StateChanged(_)) | None => {}
+    }
+}
+
+/// Handles input while a conflict has focus. Nothing is picked to begin
+/// with, so keys meant for marking can't settle a conflict by accident.
+fn process_conflict_input(event: Event, choice: &mut Option<Resolution>, journals: &mut Journals) -> Result<()> {
+    const RESOLUTIONS: [Resolution; 3] = [Resolution::KeepTheirs, Resolution::Overwrite, Resolution::Merge];
+
+    let key = match event {
+        Event::Key(key) => key,
+        _ => return Ok(()),
+    };
+
+    let index = choice.and_then(|choice| RESOLUTIONS.iter().position(|&resolution| resolution == choice));
+
+    match key.code {
+        KeyCode::Tab => {
+            *choice = Some(RESOLUTIONS[index.map_or(0, |index| (index + 1) % RESOLUTIONS.len())]);
+        }
+        KeyCode::BackTab => {
+            *choice = Some(RESOLUTIONS[index.map_or(RESOLUTIONS.len() - 1, |index| (index + RESOLUTIONS.len() - 1) % RESOLUTIONS.len())]);
+        }
+        KeyCode::Enter => {
+            if let Some(resolution) = choice.take() {
+                journals.resolve_conflict(resolution)?;
+            }
+        }
+        KeyCode::Esc => {
+            *choice = None;
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+/// Handles input while the failed submissions list is open, returning whether to close it.
+fn process_failures_input(event: Event, cursor: &mut usize, journals: &mut Journals, keys: &KeyBindings) -> Result<bool> {
+    let key = match event {
+        Event::Key(key) if key.modifiers == KeyModifiers::NONE => key,
+        _ => return Ok(false),
+    };
+
+    match key.code {
+        KeyCode::Down => {
+            *cursor = cursor.saturating_add(1);
+        }
+        KeyCode::Up => {
+            *cursor = cursor.saturating_sub(1);
+        }
+        KeyCode::Char(c) if c == keys.down => {
+            *cursor = cursor.saturating_add(1);
+        }
+        KeyCode::Char(c) if c == keys.up => {
+            *cursor = cursor.saturating_sub(1);
+        }
+        KeyCode::Char(c) if c == keys.quit || c == keys.failures => {
+            return Ok(true);
+        }
+        KeyCode::Char(RETRY_KEY) => {
+            journals.retry_failed(*cursor);
+        }
+        KeyCode::Char(DISCARD_KEY) => {
+            journals.discard_failed(*cursor)?;
+        }
+        KeyCode::Esc => {
+            return Ok(true);
+        }
+        _ => {}
+    }
+
+    Ok(false)
+}
+
+fn validate_override_mark(value: &str, globals: &Globals) -> std::result::Result<f64, String> {
+    let mark = value.trim().parse::<f64>()
+        .map_err(|_| format!("`{}` is not a number", value.trim()))?;
+
+    if !mark.is_finite() {
+        return Err(format!("`{}` is not a number", value.trim()));
+    }
+
+    MarkOverride::check_bounds(mark, globals.min_mark(), globals.max_mark())?;
+
+    Ok(mark)
+}
+
+#[cfg(test)]
+mod tests {
+    use crossterm::event::KeyEvent;
+
+    use crate::imark::tests::*;
+    use super::*;
+
+    fn press(code: KeyCode) -> Event {
+        Event::Key(KeyEvent::new(code, KeyModifiers::NONE))
+    }
+
+    #[tokio::test]
+    async fn conflicts_are_only_settled_once_confirmed() {
+        let imark = fake_imark();
+        let mut journals = loaded_journals(imark.clone()).await;
+
+        other_marker_writes(&imark, "their feedback\n").await;
+        queue_mark(&mut journals);
+        settle(&mut journals).await;
+
+        let mut choice = None;
+
+        // hotkeys and submitting the mark mean nothing to the conflict
+        for code in [KeyCode::Char('t'), KeyCode::Char('o'), KeyCode::Char('m'), KeyCode::Enter] {
+            process_conflict_input(press(code), &mut choice, &mut journals).unwrap();
+        }
+        assert_eq!(journals.conflicts().count(), 1);
+
+        process_conflict_input(press(KeyCode::Tab), &mut choice, &mut journals).unwrap();
+        process_conflict_input(press(KeyCode::Tab), &mut choice, &mut journals).unwrap();
+        process_conflict_input(press(KeyCode::BackTab), &mut choice, &mut journals).unwrap();
+        assert!(choice == Some(Resolution::KeepTheirs));
+        assert_eq!(journals.conflicts().count(), 1);
+
+        process_conflict_input(press(KeyCode::Enter), &mut choice, &mut journals).unwrap();
+        settle(&mut journals).await;
+
+        assert!(choice.is_none());
+        assert_eq!(journals.conflicts().count(), 0);
+        assert_eq!(remote_text(&imark), "their feedback\n");
+    }
+
+    #[test]
+    fn override_marks_must_be_numbers_in_range() {
+        let globals = bounded_globals(0.0, Some(10.0));
+
+        assert_eq!(validate_override_mark(" 7.5 ", &globals), Ok(7.5));
+        assert_eq!(validate_override_mark("0", &globals), Ok(0.0));
+        assert_eq!(validate_override_mark("10", &globals), Ok(10.0));
+
+        assert_eq!(validate_override_mark("-1", &globals), Err(String::from("mark must be at least 0")));
+        assert_eq!(validate_override_mark("10.5", &globals), Err(String::from("mark must be at most 10")));
+        assert_eq!(validate_override_mark("seven", &globals), Err(String::from("`seven` is not a number")));
+        assert_eq!(validate_override_mark("NaN", &globals), Err(String::from("`NaN` is not a number")));
+        assert_eq!(validate_override_mark("inf", &globals), Err(String::from("`inf` is not a number")));
+
+        // without an @max, only the floor applies
+        assert_eq!(validate_override_mark("1000", &bounded_globals(0.0, None)), Ok(1000.0));
+    }
+
+    #[test]
+    fn an_override_needs_a_reason() {
+        let globals = bounded_globals(0.0, Some(10.0));
+        let mut comment = Input::default();
+        let mut mark_override = None;
+
+        for reason in ["", "   "] {
+            let mut focus = MarkingFocus::OverrideReason { mark: 5.0, reason_input: Input::default().with_value(reason.to_string()) };
+            process_prompt_input(press(KeyCode::Enter), &mut focus, &mut comment, &mut mark_override, &globals);
+
+            assert!(matches!(focus, MarkingFocus::OverrideReason { .. }));
+            assert!(mark_override.is_none());
+        }
+
+        let mut focus = MarkingFocus::OverrideReason { mark: 5.0, reason_input: Input::default().with_value(String::from(" late penalty waived ")) };
+        process_prompt_input(press(KeyCode::Enter), &mut focus, &mut comment, &mut mark_override, &globals);
+
+        assert!(matches!(focus, MarkingFocus::Choices));
+        let mark_override = mark_override.expect("override was given a reason");
+        assert_eq!(mark_override.mark, 5.0);
+        assert_eq!(mark_override.reason, "late penalty waived");
+    }
+
+    #[test]
+    fn an_invalid_override_mark_keeps_the_prompt_open() {
+        let globals = bounded_globals(0.0, Some(10.0));
+        let mut comment = Input::default();
+        let mut mark_override = None;
+
+        let mut focus = MarkingFocus::OverrideMark { mark_input: Input::default().with_value(String::from("11")), error: None };
+        process_prompt_input(press(KeyCode::Enter), &mut focus, &mut comment, &mut mark_override, &globals);
+
+        assert!(matches!(&focus, MarkingFocus::OverrideMark { error: Some(error), .. } if error == "mark must be at most 10"));
+        assert!(mark_override.is_none());
+    }
+}
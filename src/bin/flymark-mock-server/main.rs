@@ -0,0 +1,239 @@
+//! A stand-in for imark's marking API, for developing and demoing flymark
+//! without going anywhere near real marks.
+//!
+//! Point flymark at it with `flymark -e http://127.0.0.1:8080 ...`.
+
+mod store;
+
+use std::{collections::{BTreeMap, HashMap}, convert::Infallible, net::SocketAddr, path::PathBuf, sync::Arc, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use anyhow::{Result, Context, bail};
+use clap::Parser;
+use hyper::{Body, Method, Request, Response, Server, StatusCode, header, service::{make_service_fn, service_fn}};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use store::{Store, MarkJson};
+
+#[derive(Parser, Debug)]
+#[clap(version, author)]
+struct Args {
+    /// Directory of fixture submissions, laid out as
+    /// <assignment>/<group id>/<student id>/, each with a files/ directory
+    /// and an optional meta.json. Marks are saved to marks.json alongside.
+    fixtures: PathBuf,
+
+    /// Address to listen on.
+    #[clap(short, long, default_value = "127.0.0.1:8080")]
+    listen: SocketAddr,
+
+    /// Only accept these credentials (format: zid:password).
+    /// Can be given more than once. With none, anyone can log in.
+    #[clap(short, long = "user")]
+    users: Vec<String>,
+
+    /// Delay every response by this many milliseconds.
+    #[clap(long, default_value = "0")]
+    latency_ms: u64,
+
+    /// Fraction of requests (0 to 1) to fail with --fail-status.
+    #[clap(long, default_value = "0")]
+    fail_rate: f64,
+
+    /// The status code to fail requests with.
+    #[clap(long, default_value = "502")]
+    fail_status: u16,
+
+    /// Only fail mark PUTs, rather than any request.
+    #[clap(long)]
+    fail_puts_only: bool,
+}
+
+struct State {
+    store: Mutex<Store>,
+    users: HashMap<String, String>,
+    latency: Duration,
+    fail_rate: f64,
+    fail_status: StatusCode,
+    fail_puts_only: bool,
+    rng: Mutex<u64>,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+
+    let store = Store::load(&args.fixtures)
+        .with_context(|| format!("Failed to load fixtures from {}", args.fixtures.display()))?;
+
+    let users = args.users.iter()
+        .map(|user| match user.split_once(':') {
+            Some((zid, password)) => Ok((zid.to_string(), password.to_string())),
+            None => bail!("User `{user}` should be in the format zid:password"),
+        })
+        .collect::<Result<HashMap<_, _>>>()?;
+
+    if !(0.0..=1.0).contains(&args.fail_rate) {
+        bail!("--fail-rate must be between 0 and 1");
+    }
+
+    let state = Arc::new(State {
+        store: Mutex::new(store),
+        users,
+        latency: Duration::from_millis(args.latency_ms),
+        fail_rate: args.fail_rate,
+        fail_status: StatusCode::from_u16(args.fail_status).context("Invalid --fail-status")?,
+        fail_puts_only: args.fail_puts_only,
+        rng: Mutex::new(SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos() as u64 | 1),
+    });
+
+    let make_service = make_service_fn(move |_| {
+        let state = state.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| handle(state.clone(), req)))
+        }
+    });
+
+    println!("Serving mock imark on http://{}", args.listen);
+
+    Server::bind(&args.listen)
+        .serve(make_service)
+        .await?;
+
+    Ok(())
+}
+
+async fn handle(state: Arc<State>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let method = req.method().clone();
+    let path   = req.uri().path().to_string();
+
+    tokio::time::sleep(state.latency).await;
+
+    let response = match route(&state, req).await {
+        Ok(response) => response,
+        Err(err) => reply(StatusCode::INTERNAL_SERVER_ERROR, &ErrorJson { error: format!("{err:#}") }),
+    };
+
+    println!("{method} {path} -> {}", response.status());
+
+    Ok(response)
+}
+
+async fn route(state: &State, req: Request<Body>) -> Result<Response<Body>> {
+    let path = req.uri().path().to_string();
+    let segments = match path.strip_prefix("/api/v1/assignments") {
+        Some(rest) => rest.split('/').filter(|segment| !segment.is_empty()).collect::<Vec<_>>(),
+        None => return Ok(not_found()),
+    };
+
+    if !state.is_authorised(&req) {
+        let mut response = reply(StatusCode::UNAUTHORIZED, &ErrorJson { error: String::from("invalid credentials") });
+        response.headers_mut().insert(header::WWW_AUTHENTICATE, header::HeaderValue::from_static("Basic realm=\"imark\""));
+        return Ok(response);
+    }
+
+    if state.should_fail(req.method()) {
+        return Ok(reply(state.fail_status, &ErrorJson { error: String::from("injected failure") }));
+    }
+
+    match (req.method(), segments.as_slice()) {
+        (&Method::GET, []) => {
+            Ok(reply(StatusCode::OK, &state.store.lock().assignments()))
+        }
+        (&Method::GET, [assignment, "submissions"]) => {
+            #[derive(Serialize)]
+            struct SubmissionsJson<T> {
+                submissions: T,
+            }
+
+            match state.store.lock().submissions(assignment) {
+                Some(submissions) => Ok(reply(StatusCode::OK, &SubmissionsJson { submissions })),
+                None => Ok(not_found()),
+            }
+        }
+        (&Method::GET, [assignment, "submissions", group_id, student_id]) => {
+            match state.store.lock().student(assignment, group_id, student_id) {
+                Some(student) => Ok(reply(StatusCode::OK, &student.submission())),
+                None => Ok(not_found()),
+            }
+        }
+        (&Method::PUT, [assignment, "submissions", group_id, student_id]) => {
+            #[derive(Deserialize)]
+            struct MarkPut {
+                marks: BTreeMap<String, MarkJson>,
+            }
+
+            let (assignment, group_id, student_id) = (assignment.to_string(), group_id.to_string(), student_id.to_string());
+
+            let body = hyper::body::to_bytes(req.into_body()).await?;
+            let put: MarkPut = match serde_json::from_slice(&body) {
+                Ok(put) => put,
+                Err(err) => return Ok(reply(StatusCode::BAD_REQUEST, &ErrorJson { error: err.to_string() })),
+            };
+
+            match state.store.lock().student_mut(&assignment, &group_id, &student_id) {
+                Some(student) => {
+                    student.put_marks(put.marks)?;
+                    Ok(reply(StatusCode::OK, &student.summary()))
+                }
+                None => Ok(not_found()),
+            }
+        }
+        _ => Ok(not_found()),
+    }
+}
+
+impl State {
+    fn is_authorised(&self, req: &Request<Body>) -> bool {
+        let credentials = req.headers().get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Basic "))
+            .and_then(|encoded| base64::decode(encoded).ok())
+            .and_then(|decoded| String::from_utf8(decoded).ok());
+
+        let credentials = match credentials {
+            Some(credentials) => credentials,
+            None => return false,
+        };
+
+        match credentials.split_once(':') {
+            Some((zid, password)) => {
+                self.users.is_empty() || self.users.get(zid).map(String::as_str) == Some(password)
+            }
+            None => false,
+        }
+    }
+
+    fn should_fail(&self, method: &Method) -> bool {
+        if self.fail_rate <= 0.0 || (self.fail_puts_only && method != Method::PUT) {
+            return false;
+        }
+
+        // xorshift -- good enough for deciding which requests to drop
+        let mut rng = self.rng.lock();
+        *rng ^= *rng << 13;
+        *rng ^= *rng >> 7;
+        *rng ^= *rng << 17;
+
+        (*rng as f64 / u64::MAX as f64) < self.fail_rate
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorJson {
+    error: String,
+}
+
+fn reply(status: StatusCode, body: &impl Serialize) -> Response<Body> {
+    let body = serde_json::to_string(body).expect("responses always serialise");
+
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body))
+        .expect("response is well-formed")
+}
+
+fn not_found() -> Response<Body> {
+    reply(StatusCode::NOT_FOUND, &ErrorJson { error: String::from("not found") })
+}
@@ -0,0 +1,212 @@
+use std::{collections::BTreeMap, fs, path::{Path, PathBuf}};
+
+use anyhow::{Result, Context};
+use serde::{Deserialize, Serialize};
+
+/// Submissions loaded from a fixtures directory, laid out as
+/// `<assignment>/<group id>/<student id>/`, each holding:
+///
+/// * `meta.json` (optional) -- `{ "name": .., "provisional_mark": .., "notes": .. }`
+/// * `files/` -- the submitted files
+/// * `marks.json` -- marks put so far, written by the server
+pub struct Store {
+    assignments: BTreeMap<String, BTreeMap<(String, String), Student>>,
+}
+
+pub struct Student {
+    dir: PathBuf,
+    meta: Meta,
+    files: BTreeMap<String, FileJson>,
+    marks: MarksFile,
+}
+
+#[derive(Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Meta {
+    pub name: String,
+    pub provisional_mark: Option<f64>,
+    pub notes: Option<String>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct FileJson {
+    pub name: String,
+    pub contents: String,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct MarkJson {
+    pub name: String,
+    pub at: Option<String>,
+    pub by: Option<String>,
+    #[serde(default)]
+    pub is_final: bool,
+    pub mark: Option<f64>,
+    pub text: Option<String>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+struct MarksFile {
+    /// The most recent final mark.
+    mark: Option<f64>,
+    marks: BTreeMap<String, MarkJson>,
+}
+
+#[derive(Serialize)]
+pub struct SummaryJson {
+    name: String,
+    provisional_mark: Option<f64>,
+    mark: Option<f64>,
+    notes: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct SubmissionJson<'a> {
+    files: &'a BTreeMap<String, FileJson>,
+    marks: &'a BTreeMap<String, MarkJson>,
+    metadata: MetadataJson,
+}
+
+#[derive(Serialize)]
+pub struct MetadataJson {
+    mark: Option<f64>,
+}
+
+impl Store {
+    pub fn load(root: &Path) -> Result<Self> {
+        let mut assignments = BTreeMap::new();
+
+        for assignment in subdirs(root)? {
+            let mut students = BTreeMap::new();
+
+            for group in subdirs(&assignment)? {
+                for student in subdirs(&group)? {
+                    students.insert(
+                        (file_name(&group), file_name(&student)),
+                        Student::load(student.clone())
+                            .with_context(|| format!("Failed to load fixture: {}", student.display()))?,
+                    );
+                }
+            }
+
+            assignments.insert(file_name(&assignment), students);
+        }
+
+        Ok(Self { assignments })
+    }
+
+    pub fn assignments(&self) -> Vec<&str> {
+        self.assignments.keys().map(String::as_str).collect()
+    }
+
+    pub fn submissions(&self, assignment: &str) -> Option<BTreeMap<&str, BTreeMap<&str, SummaryJson>>> {
+        let students = self.assignments.get(assignment)?;
+
+        let mut groups = BTreeMap::<_, BTreeMap<_, _>>::new();
+        for ((group_id, student_id), student) in students {
+            groups.entry(group_id.as_str())
+                .or_default()
+                .insert(student_id.as_str(), student.summary());
+        }
+
+        Some(groups)
+    }
+
+    pub fn student(&self, assignment: &str, group_id: &str, student_id: &str) -> Option<&Student> {
+        self.assignments.get(assignment)?
+            .get(&(group_id.to_string(), student_id.to_string()))
+    }
+
+    pub fn student_mut(&mut self, assignment: &str, group_id: &str, student_id: &str) -> Option<&mut Student> {
+        self.assignments.get_mut(assignment)?
+            .get_mut(&(group_id.to_string(), student_id.to_string()))
+    }
+}
+
+impl Student {
+    fn load(dir: PathBuf) -> Result<Self> {
+        let meta = match fs::read_to_string(dir.join("meta.json")) {
+            Ok(text) => serde_json::from_str(&text).context("Invalid meta.json")?,
+            Err(_) => Meta { name: file_name(&dir), ..Meta::default() },
+        };
+
+        let mut names = match fs::read_dir(dir.join("files")) {
+            Ok(entries) => {
+                entries.map(|entry| Ok(entry?.path()))
+                    .collect::<Result<Vec<_>>>()?
+            }
+            Err(_) => Vec::new(),
+        };
+        names.sort();
+
+        let mut files = BTreeMap::new();
+        for (index, path) in names.iter().filter(|path| path.is_file()).enumerate() {
+            files.insert(
+                (index + 1).to_string(),
+                FileJson {
+                    name:     file_name(path),
+                    contents: String::from_utf8_lossy(&fs::read(path)?).into_owned(),
+                },
+            );
+        }
+
+        let marks = match fs::read_to_string(dir.join("marks.json")) {
+            Ok(text) => serde_json::from_str(&text).context("Invalid marks.json")?,
+            Err(_) => MarksFile::default(),
+        };
+
+        Ok(Self { dir, meta, files, marks })
+    }
+
+    pub fn summary(&self) -> SummaryJson {
+        SummaryJson {
+            name:             self.meta.name.to_string(),
+            provisional_mark: self.meta.provisional_mark,
+            mark:             self.marks.mark,
+            notes:            self.meta.notes.clone(),
+        }
+    }
+
+    pub fn submission(&self) -> SubmissionJson<'_> {
+        SubmissionJson {
+            files:    &self.files,
+            marks:    &self.marks.marks,
+            metadata: MetadataJson { mark: self.marks.mark },
+        }
+    }
+
+    /// Records marks, writing them straight to `marks.json`.
+    pub fn put_marks(&mut self, marks: BTreeMap<String, MarkJson>) -> Result<()> {
+        for (imark_id, mark) in marks {
+            if mark.is_final {
+                self.marks.mark = mark.mark;
+            }
+
+            self.marks.marks.insert(imark_id, mark);
+        }
+
+        fs::write(self.dir.join("marks.json"), serde_json::to_string_pretty(&self.marks)?)
+            .with_context(|| format!("Failed to write {}", self.dir.join("marks.json").display()))
+    }
+}
+
+fn subdirs(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut dirs = Vec::new();
+
+    for entry in fs::read_dir(dir).with_context(|| format!("Failed to read {}", dir.display()))? {
+        let path = entry?.path();
+        if path.is_dir() {
+            dirs.push(path);
+        }
+    }
+
+    dirs.sort();
+    Ok(dirs)
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
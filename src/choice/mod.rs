@@ -1,8 +1,79 @@
+use std::collections::HashMap;
+
 use anyhow::{Result, bail, Context};
 
+use crate::{config::KeyBindings, util::HOTKEYS};
+
 #[derive(Debug, Default)]
 pub struct Choices {
-    pub choices: Vec<Choice>,
+    pub choices:  Vec<Choice>,
+    pub max_mark: Option<f64>,
+    /// The hotkey for each choice (by index into `choices`) that has one.
+    pub hotkeys:  HashMap<usize, char>,
+}
+
+impl Choices {
+    /// The highest mark any combination of choices can produce.
+    pub fn best_mark(&self) -> f64 {
+        let mut unsectioned = 0.0;
+        let mut sections: Vec<(&Section, f64)> = vec![];
+        let mut in_section = false;
+        let mut best_set = f64::NEG_INFINITY;
+        let mut group_best: Option<f64> = None;
+
+        for choice in &self.choices {
+            let subtotal = match sections.last_mut() {
+                Some((_, subtotal)) if in_section => subtotal,
+                _ => &mut unsectioned,
+            };
+
+            match choice {
+                // only one choice in a group can be selected, so only the best one counts
+                Choice::Plus(n, _) if group_best.is_some() => {
+                    group_best = group_best.map(|best| best.max(*n));
+                }
+                Choice::Plus(n, _) => *subtotal += *n,
+                Choice::Set (n, _) => best_set = best_set.max(*n),
+                Choice::Section(section) => {
+                    sections.push((section, 0.0));
+                    in_section = true;
+                }
+                Choice::SectionEnd => in_section = false,
+                Choice::GroupStart(_) => group_best = Some(0.0),
+                Choice::GroupEnd => *subtotal += group_best.take().unwrap_or(0.0),
+                Choice::Minus(_, _) | Choice::Comment(_) => {}
+            }
+        }
+
+        let best_plus = sections.iter()
+            .map(|(section, subtotal)| section.clamp(*subtotal))
+            .sum::<f64>() + unsectioned;
+
+        best_plus.max(best_set)
+    }
+
+    /// Checks the scheme's `@max` (if any) can be reached, and isn't gone past by a `=n` line.
+    pub fn check_max_mark(&self) -> Result<()> {
+        let max_mark = match self.max_mark {
+            Some(max_mark) => max_mark,
+            None => return Ok(()),
+        };
+
+        for choice in &self.choices {
+            if let Choice::Set(n, comment) = choice {
+                if *n > max_mark {
+                    bail!("Choice `={n} {comment}` sets a mark higher than the maximum of {max_mark}");
+                }
+            }
+        }
+
+        let best_mark = self.best_mark();
+        if best_mark < max_mark {
+            bail!("The best possible mark is {best_mark}, which can never reach the maximum of {max_mark}");
+        }
+
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -10,12 +81,76 @@ pub enum Choice {
     Plus (f64, String),
     Minus(f64, String),
     Set  (f64, String),
+    Section(Section),
+    SectionEnd,
+    GroupStart(String),
+    GroupEnd,
     Comment(String),
 }
 
+/// A group of choices whose combined mark is clamped to `min..=max`.
+#[derive(Debug, Clone)]
+pub struct Section {
+    pub name: String,
+    pub min:  f64,
+    pub max:  Option<f64>,
+}
+
+impl Section {
+    pub fn clamp(&self, subtotal: f64) -> f64 {
+        let subtotal = subtotal.max(self.min);
+
+        match self.max {
+            Some(max) => subtotal.min(max),
+            None => subtotal,
+        }
+    }
+}
+
+impl Choice {
+    /// The line recorded in the mark text when this choice is selected.
+    pub fn mark_line(&self) -> Option<String> {
+        match self {
+            Choice::Plus (n, comment) => Some(format!("+{n} {comment}")),
+            Choice::Minus(n, comment) => Some(format!("-{n} {comment}")),
+            Choice::Set  (n, comment) => Some(format!("{n} {comment}")),
+            Choice::Section(_)
+            | Choice::SectionEnd
+            | Choice::GroupStart(_)
+            | Choice::GroupEnd
+            | Choice::Comment(_) => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MarkOverride {
+    pub mark:   f64,
+    pub reason: String,
+}
+
+impl MarkOverride {
+    /// Checks an overriding mark is within the allowed bounds, saying why not if it isn't.
+    pub fn check_bounds(mark: f64, min_mark: f64, max_mark: Option<f64>) -> std::result::Result<(), String> {
+        if mark < min_mark {
+            return Err(format!("mark must be at least {min_mark}"));
+        }
+
+        if let Some(max_mark) = max_mark {
+            if mark > max_mark {
+                return Err(format!("mark must be at most {max_mark}"));
+            }
+        }
+
+        Ok(())
+    }
+}
+
 #[derive(Default)]
 pub struct ChoiceSelections {
     selections: Vec<ChoiceSelection>,
+    sections:   Vec<Section>,
+    max_mark:   Option<f64>,
     cursor:     usize,
 }
 
@@ -23,26 +158,53 @@ pub struct ChoiceSelection {
     choice:     Choice,
     selected:   bool,
     real_index: usize,
+    section:    Option<usize>,
+    group:      Option<usize>,
+    hotkey:     Option<char>,
 }
 
 impl ChoiceSelections {
     pub fn new(choices: &Choices) -> Self {
+        let mut selections = vec![];
+        let mut sections   = vec![];
+        let mut section    = None;
+        let mut n_groups   = 0;
+        let mut group      = None;
+
+        for (index, choice) in choices.choices.iter().enumerate() {
+            match choice {
+                Choice::Plus(_, _) | Choice::Minus(_, _) | Choice::Set(_, _) => {
+                    selections.push(ChoiceSelection {
+                        choice:     choice.clone(),
+                        selected:   false,
+                        real_index: index,
+                        section,
+                        group,
+                        hotkey:     choices.hotkeys.get(&index).copied(),
+                    });
+                }
+                Choice::Section(new_section) => {
+                    section = Some(sections.len());
+                    sections.push(new_section.clone());
+                }
+                Choice::SectionEnd => {
+                    section = None;
+                }
+                Choice::GroupStart(_) => {
+                    group = Some(n_groups);
+                    n_groups += 1;
+                }
+                Choice::GroupEnd => {
+                    group = None;
+                }
+                Choice::Comment(_) => {}
+            }
+        }
+
         Self {
-            selections: choices.choices.iter()
-                .enumerate()
-                .filter_map(|(index, choice)|
-                    match choice {
-                        Choice::Plus(_, _) | Choice::Minus(_, _) | Choice::Set(_, _) => {
-                            Some(ChoiceSelection {
-                                choice:     choice.clone(),
-                                selected:   false,
-                                real_index: index,
-                            })
-                        }
-                        Choice::Comment(_) => None,
-                    }
-                )
-                .collect(),
+            selections,
+            sections,
+            max_mark: choices.max_mark,
             cursor: 0,
         }
     }
@@ -51,36 +213,70 @@ impl ChoiceSelections {
         &self.selections
     }
 
+    pub fn sections(&self) -> &[Section] {
+        &self.sections
+    }
+
+    pub fn max_mark(&self) -> Option<f64> {
+        self.max_mark
+    }
+
     pub fn cursor(&self) -> usize {
         self.cursor
     }
 
+    /// The clamped mark contributed by each section, in scheme order.
+    pub fn section_subtotals(&self) -> Vec<f64> {
+        let mut subtotals = vec![0.0; self.sections.len()];
+
+        for selection in self.selections.iter().filter(|selection| selection.selected) {
+            if let Some(section) = selection.section {
+                match selection.choice() {
+                    Choice::Plus (n, _) => subtotals[section] += *n,
+                    Choice::Minus(n, _) => subtotals[section] -= *n,
+                    _ => {}
+                }
+            }
+        }
+
+        subtotals.into_iter()
+            .zip(&self.sections)
+            .map(|(subtotal, section)| section.clamp(subtotal))
+            .collect()
+    }
+
     pub fn real_cursor(&self) -> usize {
         self.selections[self.cursor].real_index
     }
     
     pub fn toggle_selection(&mut self) {
-        let selection = &mut self.selections[self.cursor];
+        let cursor   = self.cursor;
+        let selected = self.selections[cursor].selected;
+        let group    = self.selections[cursor].group;
 
-        match selection.choice() {
+        match self.selections[cursor].choice() {
             Choice::Plus(_, _) | Choice::Minus(_, _) => {
                 for other in &mut self.selections {
-                    if matches!(other.choice(), Choice::Set(_, _)) {
+                    let same_group = group.is_some() && other.group == group;
+
+                    if same_group || matches!(other.choice(), Choice::Set(_, _)) {
                         other.selected = false;
                     }
                 }
-
-                self.selections[self.cursor].selected = !self.selections[self.cursor].selected;
             }
             Choice::Set(_, _)  => {
                 for other in &mut self.selections {
                     other.selected = false;
                 }
-
-                self.selections[self.cursor].selected = !self.selections[self.cursor].selected;
             }
-            Choice::Comment(_) => unreachable!(),
+            Choice::Section(_)
+            | Choice::SectionEnd
+            | Choice::GroupStart(_)
+            | Choice::GroupEnd
+            | Choice::Comment(_) => unreachable!(),
         }
+
+        self.selections[cursor].selected = !selected;
     }
 
     pub fn cursor_next(&mut self) {
@@ -100,6 +296,72 @@ impl ChoiceSelections {
         }
     }
 
+    /// The mark the current selections produce under the scheme's arithmetic.
+    pub fn mark(&self) -> f64 {
+        let mut mark = 0.0;
+        let mut set  = false;
+
+        for selection in self.selections.iter().filter(|selection| selection.selected) {
+            match (selection.choice(), selection.section) {
+                // sectioned choices are totalled (and clamped) per section below
+                (Choice::Plus(_, _) | Choice::Minus(_, _), Some(_)) => {}
+                (Choice::Plus (n, _), None) => mark += *n,
+                (Choice::Minus(n, _), None) => mark -= *n,
+                (Choice::Set  (n, _), _)    => {
+                    mark = *n;
+                    set  = true;
+                }
+                (Choice::Section(_) | Choice::SectionEnd | Choice::GroupStart(_) | Choice::GroupEnd | Choice::Comment(_), _) => unreachable!(),
+            }
+        }
+
+        // a set mark is absolute, so section floors shouldn't be added on top of it
+        if !set {
+            mark += self.section_subtotals().iter().sum::<f64>();
+        }
+
+        if mark < 0.0 {
+            mark = 0.0;
+        }
+
+        if let Some(max_mark) = self.max_mark {
+            mark = mark.min(max_mark);
+        }
+
+        (mark * 100.0).round() / 100.0
+    }
+
+    pub fn mark_lines(&self) -> Vec<String> {
+        self.selections.iter()
+            .filter(|selection| selection.selected)
+            .filter_map(|selection| selection.choice.mark_line())
+            .collect()
+    }
+
+    /// Selects the choices matching the given previously recorded mark lines,
+    /// returning the lines that no longer match anything in the scheme.
+    pub fn restore(&mut self, mark_lines: &[String]) -> Vec<String> {
+        let mut unmatched = vec![];
+
+        for line in mark_lines {
+            let selection = self.selections.iter_mut()
+                .find(|selection| !selection.selected && selection.choice.mark_line().as_ref() == Some(line));
+
+            match selection {
+                Some(selection) => selection.selected = true,
+                None => unmatched.push(line.to_string()),
+            }
+        }
+
+        unmatched
+    }
+
+    pub fn by_hotkey(&self, hotkey: char) -> Option<usize> {
+        self.selections.iter()
+            .position(|selection| selection.hotkey == Some(hotkey))
+    }
+
+    #[allow(clippy::wrong_self_convention)]
     pub fn from_real_index(&self, real_index: usize) -> Option<(usize, &ChoiceSelection)> {
         self.selections.iter()
             .enumerate()
@@ -123,17 +385,173 @@ impl ChoiceSelection {
     pub fn real_index(&self) -> usize {
         self.real_index
     }
+
+    pub fn section(&self) -> Option<usize> {
+        self.section
+    }
+
+    pub fn group(&self) -> Option<usize> {
+        self.group
+    }
+
+    pub fn hotkey(&self) -> Option<char> {
+        self.hotkey
+    }
+}
+
+/// The feedback flymark records below its header line when marking a journal.
+pub fn feedback_text(selections: &ChoiceSelections, mark_override: Option<&MarkOverride>, comment: &str) -> String {
+    let mut text = String::new();
+
+    for line in selections.mark_lines() {
+        text += &line;
+        text += "\n";
+    }
+
+    if let Some(mark_override) = mark_override {
+        text += &format!("\nmark overridden to {}: {}\n", mark_override.mark, mark_override.reason);
+    }
+
+    if !comment.trim().is_empty() {
+        text += "\n";
+        text += comment.trim();
+        text += "\n";
+    }
+
+    text
+}
+
+/// What flymark recorded the last time it marked a journal.
+#[derive(Debug, Default)]
+pub struct PreviousMarking {
+    pub mark_lines:    Vec<String>,
+    pub mark_override: Option<MarkOverride>,
+    pub comment:       String,
 }
 
-pub fn parse_choices(contents: &str) -> Result<Choices> {
-    let mut choices = vec![];
+/// Parses the most recent flymark block out of a journal's mark text,
+/// or returns `None` if the journal was never marked with flymark.
+pub fn parse_previous_marking(text: &str) -> Option<PreviousMarking> {
+    let lines = text.lines().collect::<Vec<_>>();
+
+    let header_index = lines.iter()
+        .rposition(|line| line.starts_with("marked with flymark by "))?;
+
+    let mut previous = PreviousMarking::default();
+    let mut comment_lines = vec![];
+    let mut in_mark_lines = true;
+
+    // the header is always followed by a single blank line
+    for line in lines.iter().skip(header_index + 2) {
+        let line = line.trim();
+
+        if line.is_empty() {
+            in_mark_lines = false;
+        } else if let Some(rest) = line.strip_prefix("mark overridden to ") {
+            previous.mark_override = rest.split_once(": ")
+                .and_then(|(mark, reason)| {
+                    Some(MarkOverride {
+                        mark:   mark.parse().ok()?,
+                        reason: reason.to_string(),
+                    })
+                });
+        } else if in_mark_lines {
+            previous.mark_lines.push(line.to_string());
+        } else {
+            comment_lines.push(line);
+        }
+    }
+
+    previous.comment = comment_lines.join(" ");
+
+    Some(previous)
+}
+
+enum Directive {
+    Section(Section),
+    SectionEnd,
+    GroupStart(String),
+    GroupEnd,
+    Max(f64),
+}
+
+pub fn parse_choices(contents: &str, keys: &KeyBindings) -> Result<Choices> {
+    let mut choices  = vec![];
+    let mut max_mark = None;
+    let mut in_group = false;
+    let mut in_section = false;
+    let mut explicit_hotkeys: HashMap<char, (usize, usize)> = HashMap::new();
     
     for (line_index, line) in contents.lines().enumerate() {
         let line = line.trim();
         let line_number = line_index + 1;
+
+        if let Some(directive) = line.strip_prefix('@') {
+            let mut fallible = || {
+                match parse_directive(directive)? {
+                    Directive::Section(_) if in_group => {
+                        bail!("Sections can't start inside a @group");
+                    }
+                    Directive::Section(section) => {
+                        in_section = true;
+                        choices.push(Choice::Section(section));
+                    }
+                    Directive::SectionEnd if !in_section => {
+                        bail!("@endsection without a matching @section");
+                    }
+                    Directive::SectionEnd if in_group => {
+                        bail!("Sections can't end inside a @group");
+                    }
+                    Directive::SectionEnd => {
+                        in_section = false;
+                        choices.push(Choice::SectionEnd);
+                    }
+                    Directive::GroupStart(_) if in_group => {
+                        bail!("Groups can't be nested -- missing @endgroup?");
+                    }
+                    Directive::GroupStart(name) => {
+                        in_group = true;
+                        choices.push(Choice::GroupStart(name));
+                    }
+                    Directive::GroupEnd if !in_group => {
+                        bail!("@endgroup without a matching @group");
+                    }
+                    Directive::GroupEnd => {
+                        in_group = false;
+                        choices.push(Choice::GroupEnd);
+                    }
+                    Directive::Max(max) => {
+                        if max_mark.replace(max).is_some() {
+                            bail!("@max specified more than once");
+                        }
+                    }
+                }
+
+                Ok(())
+            };
+
+            fallible()
+                .with_context(|| format!("Choice file error on line {line_number}"))?;
+
+            continue;
+        }
         
+        // `\[a] ...` keeps a line that would otherwise start with a hotkey as it is
+        if let Some(escaped) = line.strip_prefix('\\').filter(|rest| rest.starts_with('[')) {
+            choices.push(Choice::Comment(escaped.to_string()));
+            continue;
+        }
+
+        let (hotkey, line) = match split_hotkey(line) {
+            Some((hotkey, rest)) => (Some(hotkey), rest),
+            None => (None, line),
+        };
+
         let (first_char, second_char) = match <[char; 2]>::try_from(line.chars().take(2).collect::<Vec<char>>()) {
             Ok([first_char, second_char]) => (first_char, second_char),
+            Err(_) if hotkey.is_some() => {
+                bail!("Choice file error on line {line_number}\nOnly choices (+n, -n, =n) can have a hotkey");
+            }
             Err(_) => {
                 // Not a semantic line -- leave it as a comment
                 choices.push(Choice::Comment(line.to_string()));
@@ -175,12 +593,151 @@ pub fn parse_choices(contents: &str) -> Result<Choices> {
         let choice = fallible()
             .with_context(|| format!("Choice file error on line {line_number}"))?;
 
+        if let Some(hotkey) = hotkey {
+            let fallible = || {
+                if !matches!(choice, Choice::Plus(_, _) | Choice::Minus(_, _) | Choice::Set(_, _)) {
+                    bail!("Only choices (+n, -n, =n) can have a hotkey");
+                }
+
+                if keys.is_reserved(hotkey) {
+                    bail!("Hotkey `{hotkey}` is already used by flymark itself");
+                }
+
+                if let Some((_, other_line_number)) = explicit_hotkeys.get(&hotkey) {
+                    bail!("Hotkey `{hotkey}` is already used on line {other_line_number}");
+                }
+
+                Ok(())
+            };
+
+            fallible()
+                .with_context(|| format!("Choice file error on line {line_number}"))?;
+
+            explicit_hotkeys.insert(hotkey, (choices.len(), line_number));
+        }
+
         choices.push(choice);
     }
 
-    Ok(Choices { choices })
+    if in_group {
+        bail!("Choice file ends inside a @group -- missing @endgroup?");
+    }
+
+    let mut hotkeys = explicit_hotkeys.iter()
+        .map(|(hotkey, (index, _))| (*index, *hotkey))
+        .collect::<HashMap<_, _>>();
+
+    // lines without an explicit hotkey get whatever's left over, in order
+    let mut auto_hotkeys = HOTKEYS.chars()
+        .filter(|hotkey| !explicit_hotkeys.contains_key(hotkey) && !keys.is_reserved(*hotkey));
+
+    for (index, choice) in choices.iter().enumerate() {
+        if matches!(choice, Choice::Plus(_, _) | Choice::Minus(_, _) | Choice::Set(_, _)) && !hotkeys.contains_key(&index) {
+            match auto_hotkeys.next() {
+                Some(hotkey) => hotkeys.insert(index, hotkey),
+                None => break,
+            };
+        }
+    }
+
+    Ok(Choices { choices, max_mark, hotkeys })
 }
 
+fn parse_directive(directive: &str) -> Result<Directive> {
+    let mut words = directive.split_whitespace().collect::<Vec<_>>();
+
+    match words.first() {
+        Some(&"section") => {
+            let mut min = None;
+            let mut max = None;
+
+            // bounds are trailing `max n` / `min n` pairs after the section name
+            while words.len() >= 3 {
+                let bound = match words[words.len() - 2] {
+                    "max" => &mut max,
+                    "min" => &mut min,
+                    _ => break,
+                };
+
+                let number = words[words.len() - 1].parse::<f64>()
+                    .with_context(|| format!("Invalid section bound: {}", words[words.len() - 1]))?;
+
+                if bound.replace(number).is_some() {
+                    bail!("Section bound specified more than once");
+                }
+
+                words.truncate(words.len() - 2);
+            }
+
+            let name = words[1..].join(" ");
+            if name.is_empty() {
+                bail!("Sections must have a name, e.g. `@section Style max 3`");
+            }
+
+            let min = min.unwrap_or(0.0);
+            if let Some(max) = max {
+                if min > max {
+                    bail!("Section `{name}` has a minimum ({min}) greater than its maximum ({max})");
+                }
+            }
+
+            Ok(Directive::Section(Section { name, min, max }))
+        }
+        Some(&"endsection") => {
+            if words.len() > 1 {
+                bail!("@endsection doesn't take any arguments");
+            }
+
+            Ok(Directive::SectionEnd)
+        }
+        Some(&"group") => {
+            let name = words[1..].join(" ");
+            if name.is_empty() {
+                bail!("Groups must have a name, e.g. `@group Test results`");
+            }
+
+            Ok(Directive::GroupStart(name))
+        }
+        Some(&"endgroup") => {
+            if words.len() > 1 {
+                bail!("@endgroup doesn't take any arguments");
+            }
+
+            Ok(Directive::GroupEnd)
+        }
+        Some(&"max") => {
+            let max = match words[1..] {
+                [max] => max.parse::<f64>()
+                    .with_context(|| format!("Invalid maximum mark: {max}"))?,
+                _ => bail!("Expected a single maximum mark, e.g. `@max 10`"),
+            };
+
+            Ok(Directive::Max(max))
+        }
+        Some(other) => bail!("Unknown scheme directive: @{other}"),
+        None => bail!("Empty scheme directive"),
+    }
+}
+
+/// Splits a leading `[c]` hotkey off a scheme line. It's only a hotkey if `c`
+/// could be one and whitespace follows, so lines starting with tags like
+/// `[TODO]` or `[a]bc` are left as they are.
+fn split_hotkey(line: &str) -> Option<(char, &str)> {
+    let rest = line.strip_prefix('[')?;
+
+    let mut chars = rest.chars();
+    let hotkey = chars.next()
+        .filter(|hotkey| hotkey.is_ascii_lowercase() || hotkey.is_ascii_digit())?;
+    let rest = chars.as_str().strip_prefix(']')?;
+
+    if !rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+
+    Some((hotkey, rest.trim_start()))
+}
+
+#[allow(clippy::iter_skip_next)]
 fn skip_first_char(line: &str) -> &str {
     match line.char_indices().skip(1).next() {
         Some((index, _)) => &line[index..],
@@ -201,3 +758,235 @@ fn parse_number(line: &str) -> Result<(f64, &str)> {
 
     Ok((number, rest))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(scheme: &str) -> Choices {
+        parse_choices(scheme, &KeyBindings::default()).expect("scheme should parse")
+    }
+
+    #[test]
+    fn endsection_returns_to_the_top_level() {
+        let choices = parse("@section Style max 1\n+1 Formatting\n+1 Comments\n@endsection\n+2 Extension");
+        let mut selections = ChoiceSelections::new(&choices);
+
+        let sections = selections.selections().iter()
+            .map(|selection| selection.section())
+            .collect::<Vec<_>>();
+        assert_eq!(sections, vec![Some(0), Some(0), None]);
+
+        for index in 0..3 {
+            selections.try_cursor_set(index);
+            selections.toggle_selection();
+        }
+
+        assert_eq!(selections.mark(), 3.0);
+        assert_eq!(choices.best_mark(), 3.0);
+    }
+
+    #[test]
+    fn set_lines_after_endsection_are_not_capped() {
+        let choices = parse("@section Style max 1\n+1 Formatting\n@endsection\n=5 Halfway there");
+        let mut selections = ChoiceSelections::new(&choices);
+
+        selections.try_cursor_set(1);
+        selections.toggle_selection();
+
+        assert_eq!(selections.selections()[1].section(), None);
+        assert_eq!(selections.mark(), 5.0);
+    }
+
+    #[test]
+    fn endsection_must_close_a_section() {
+        assert!(parse_choices("+1 Formatting\n@endsection", &KeyBindings::default()).is_err());
+        assert!(parse_choices("@section Style\n@group Tests\n@endsection", &KeyBindings::default()).is_err());
+    }
+
+    #[test]
+    fn bracketed_tags_are_not_hotkeys() {
+        let choices = parse("[TODO] check the marking guide\n[a]bc\n[A] +1 Shouting\n+1 Compiles");
+
+        assert!(matches!(&choices.choices[0], Choice::Comment(text) if text == "[TODO] check the marking guide"));
+        assert!(matches!(&choices.choices[1], Choice::Comment(text) if text == "[a]bc"));
+        assert!(matches!(&choices.choices[2], Choice::Comment(text) if text == "[A] +1 Shouting"));
+        assert_eq!(choices.hotkeys.get(&3), Some(&'1'));
+    }
+
+    #[test]
+    fn an_escaped_bracket_keeps_the_line_as_it_is() {
+        let choices = parse("\\[a] +2 isn't a hotkey");
+
+        assert!(matches!(&choices.choices[0], Choice::Comment(text) if text == "[a] +2 isn't a hotkey"));
+        assert!(choices.hotkeys.is_empty());
+    }
+
+    #[test]
+    fn explicit_hotkeys_are_taken_out_of_the_automatic_ones() {
+        let choices = parse("+1 First\n[1] +1 Second\n+1 Third");
+
+        assert_eq!(choices.hotkeys.get(&0), Some(&'2'));
+        assert_eq!(choices.hotkeys.get(&1), Some(&'1'));
+        assert_eq!(choices.hotkeys.get(&2), Some(&'3'));
+    }
+
+    #[test]
+    fn bound_keys_are_not_assigned_automatically() {
+        let keys = KeyBindings { skip: '1', ..KeyBindings::default() };
+        let choices = parse_choices("+1 First\n+1 Second", &keys).unwrap();
+
+        assert_eq!(choices.hotkeys.get(&0), Some(&'2'));
+        assert_eq!(choices.hotkeys.get(&1), Some(&'3'));
+    }
+
+    #[test]
+    fn hotkeys_cannot_collide() {
+        let error = |scheme: &str| format!("{:#}", parse_choices(scheme, &KeyBindings::default()).unwrap_err());
+
+        assert!(error("[q] +1 Quits").contains("already used by flymark"));
+        assert!(error("[a] +1 First\n[a] +1 Second").contains("already used on line 1"));
+        assert!(error("[a] Not a choice").contains("Only choices"));
+
+        let keys = KeyBindings { skip: 'a', ..KeyBindings::default() };
+        assert!(parse_choices("[a] +1 Skipped", &keys).is_err());
+    }
+
+    #[test]
+    fn sections_clamp_their_subtotal() {
+        let choices = parse("@section Style max 3 min 1\n+2 Formatting\n+2 Comments\n-5 Unreadable\n@endsection\n+1 Extension");
+
+        let mut selections = ChoiceSelections::new(&choices);
+        for index in 0..2 {
+            selections.try_cursor_set(index);
+            selections.toggle_selection();
+        }
+        assert_eq!(selections.mark(), 3.0);
+
+        let mut selections = ChoiceSelections::new(&choices);
+        selections.try_cursor_set(2);
+        selections.toggle_selection();
+        assert_eq!(selections.mark(), 1.0);
+    }
+
+    #[test]
+    fn best_mark_counts_section_caps_and_one_choice_per_group() {
+        let choices = parse("@section Style max 3\n+2 Formatting\n+2 Comments\n@endsection\n@group Tests\n+1 Some\n+4 All\n@endgroup\n+1 Extension\n-1 Late");
+        assert_eq!(choices.best_mark(), 8.0);
+
+        let choices = parse("+1 Compiles\n=10 Perfect");
+        assert_eq!(choices.best_mark(), 10.0);
+    }
+
+    #[test]
+    fn max_mark_must_be_reachable_and_not_exceeded() {
+        assert!(parse("@max 5\n+3 Compiles\n+3 Tests").check_max_mark().is_ok());
+        assert!(parse("+3 Compiles").check_max_mark().is_ok());
+
+        let error = parse("@max 10\n+2 Compiles").check_max_mark().unwrap_err();
+        assert!(error.to_string().contains("can never reach"));
+
+        let error = parse("@max 5\n+5 Compiles\n=6 Bonus").check_max_mark().unwrap_err();
+        assert!(error.to_string().contains("higher than the maximum"));
+    }
+
+    #[test]
+    fn only_one_choice_in_a_group_is_selected() {
+        let choices = parse("@group Tests\n+1 Some pass\n+3 All pass\n@endgroup\n+1 Compiles");
+        let mut selections = ChoiceSelections::new(&choices);
+
+        for index in [0, 1, 2] {
+            selections.try_cursor_set(index);
+            selections.toggle_selection();
+        }
+
+        assert!(!selections.selections()[0].selected());
+        assert!(selections.selections()[1].selected());
+        assert_eq!(selections.mark(), 4.0);
+    }
+
+    #[test]
+    fn groups_must_be_well_formed() {
+        let error = |scheme: &str| format!("{:#}", parse_choices(scheme, &KeyBindings::default()).unwrap_err());
+
+        assert!(error("@group A\n+1 a\n@group B\n+1 b\n@endgroup").contains("can't be nested"));
+        assert!(error("@group A\n+1 a").contains("missing @endgroup"));
+        assert!(error("+1 a\n@endgroup").contains("without a matching @group"));
+        assert!(error("@group\n+1 a\n@endgroup").contains("must have a name"));
+        assert!(error("@group A\n+1 a\n@endgroup A").contains("doesn't take any arguments"));
+        assert!(error("@group A\n@section B\n+1 a\n@endgroup").contains("Sections can't start inside"));
+        assert!(error("@section A\n@group B\n+1 a\n@endsection").contains("Sections can't end inside"));
+    }
+
+    /// The text flymark writes to imark for a marking.
+    fn marked_text(selections: &ChoiceSelections, mark_override: Option<&MarkOverride>, comment: &str) -> String {
+        format!("\nmarked with flymark by z1111111 at 2022-05-01 12:00:00\n\n{}", feedback_text(selections, mark_override, comment))
+    }
+
+    #[test]
+    fn previous_marking_round_trips() {
+        let choices = parse("+2 Compiles\n+3 Tests pass\n-1 Late");
+        let mut selections = ChoiceSelections::new(&choices);
+        for index in [0, 2] {
+            selections.try_cursor_set(index);
+            selections.toggle_selection();
+        }
+
+        let mark_override = MarkOverride { mark: 4.5, reason: String::from("partial credit for tests") };
+        let text = marked_text(&selections, Some(&mark_override), "  Nice work  ");
+
+        let previous = parse_previous_marking(&text).expect("text was marked with flymark");
+        assert_eq!(previous.mark_lines, vec![String::from("+2 Compiles"), String::from("-1 Late")]);
+        assert_eq!(previous.comment, "Nice work");
+
+        let restored_override = previous.mark_override.expect("mark was overridden");
+        assert_eq!(restored_override.mark, 4.5);
+        assert_eq!(restored_override.reason, "partial credit for tests");
+
+        let mut restored = ChoiceSelections::new(&choices);
+        assert!(restored.restore(&previous.mark_lines).is_empty());
+        assert_eq!(restored.mark_lines(), selections.mark_lines());
+        assert_eq!(restored.mark(), 1.0);
+    }
+
+    #[test]
+    fn only_the_latest_marking_is_restored() {
+        let choices = parse("+2 Compiles\n+3 Tests pass");
+
+        let mut first = ChoiceSelections::new(&choices);
+        first.toggle_selection();
+
+        let mut second = ChoiceSelections::new(&choices);
+        second.try_cursor_set(1);
+        second.toggle_selection();
+
+        let text = String::from("hand-written feedback\n") + &marked_text(&first, None, "First go") + &marked_text(&second, None, "");
+
+        let previous = parse_previous_marking(&text).unwrap();
+        assert_eq!(previous.mark_lines, vec![String::from("+3 Tests pass")]);
+        assert!(previous.mark_override.is_none());
+        assert_eq!(previous.comment, "");
+    }
+
+    #[test]
+    fn lines_no_longer_in_the_scheme_are_left_unmatched() {
+        let old_choices = parse("+2 Compiles\n+1 Style");
+        let mut old = ChoiceSelections::new(&old_choices);
+        for index in [0, 1] {
+            old.try_cursor_set(index);
+            old.toggle_selection();
+        }
+
+        let previous = parse_previous_marking(&marked_text(&old, None, "")).unwrap();
+
+        let mut restored = ChoiceSelections::new(&parse("+2 Compiles\n+3 Tests pass"));
+        assert_eq!(restored.restore(&previous.mark_lines), vec![String::from("+1 Style")]);
+        assert_eq!(restored.mark(), 2.0);
+    }
+
+    #[test]
+    fn text_not_marked_with_flymark_has_no_previous_marking() {
+        assert!(parse_previous_marking("").is_none());
+        assert!(parse_previous_marking("+2 Compiles\n\nmarked by hand\n").is_none());
+    }
+}
@@ -0,0 +1,788 @@
+//! Non-interactive commands, printing what's on imark for use from the shell.
+
+use std::{collections::HashSet, fs::File, io::{self, Write}, path::Path};
+
+use anyhow::{Result, Context, bail};
+use clap::ArgEnum;
+use serde::{Deserialize, Serialize, Serializer, ser::SerializeMap};
+use serde_json::{Value, json};
+
+use crate::{app::assignments::FetchJournalsTask, choice::{ChoiceSelections, MarkOverride}, imark::{self, Globals, Authentication, Journals, JournalTag, MarkSubmission, Prepared}, marklog::PendingMark, util::task::TaskRunner};
+
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Column {
+    Zid,
+    Group,
+    Name,
+    Mark,
+    Provisional,
+    Notes,
+    /// Who last wrote the mark (needs every journal to be loaded).
+    Marker,
+    /// The mark's full text (needs every journal to be loaded).
+    Text,
+}
+
+impl Column {
+    pub const DEFAULT: &'static [Column] = &[Column::Zid, Column::Group, Column::Name, Column::Mark, Column::Provisional, Column::Notes];
+
+    fn name(self) -> &'static str {
+        match self {
+            Column::Zid         => "zid",
+            Column::Group       => "group",
+            Column::Name        => "name",
+            Column::Mark        => "mark",
+            Column::Provisional => "provisional",
+            Column::Notes       => "notes",
+            Column::Marker      => "marker",
+            Column::Text        => "text",
+        }
+    }
+
+    fn needs_load(self) -> bool {
+        matches!(self, Column::Marker | Column::Text)
+    }
+}
+
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Csv,
+    Json,
+}
+
+#[derive(Serialize)]
+struct JournalJson<'a> {
+    assignment: &'a str,
+    group_id: &'a str,
+    student_id: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<&'a str>,
+    provisional_mark: Option<f64>,
+    mark: Option<f64>,
+    notes: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct ShowJson<'a> {
+    #[serde(flatten)]
+    journal: JournalJson<'a>,
+    marks: Vec<MarkJson<'a>>,
+    files: Vec<FileJson<'a>>,
+}
+
+#[derive(Serialize)]
+struct MarkJson<'a> {
+    name: &'a str,
+    at: Option<&'a str>,
+    by: Option<&'a str>,
+    text: String,
+}
+
+#[derive(Serialize)]
+struct FileJson<'a> {
+    name: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    contents: Option<String>,
+}
+
+pub async fn assignments(globals: &Globals, auth: &Authentication, json: bool) -> Result<()> {
+    let assignments = globals.imark().list_assignments(auth).await?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&assignments)?);
+    } else {
+        for assignment in assignments {
+            println!("{assignment}");
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn list(globals: &Globals, auth: &Authentication, assignment: &str, json: bool) -> Result<()> {
+    let journals = fetch_journals(globals, auth, assignment).await?;
+
+    let mut rows = vec![];
+    for (tag, journal) in journals.iter() {
+        let journal = journal.lock().await;
+        let meta = journal.meta();
+
+        rows.push((tag.clone(), meta.clone()));
+    }
+
+    if json {
+        let rows = rows.iter()
+            .map(|(tag, meta)| JournalJson {
+                assignment: tag.assignment(),
+                group_id: tag.group_id(),
+                student_id: tag.student_id(),
+                name: Some(meta.name()).filter(|_| !globals.hide_names()),
+                provisional_mark: meta.provisional_mark(),
+                mark: meta.mark(),
+                notes: meta.notes(),
+            })
+            .collect::<Vec<_>>();
+
+        println!("{}", serde_json::to_string_pretty(&rows)?);
+    } else {
+        let rows = rows.iter()
+            .map(|(tag, meta)| {
+                let mut row = vec![
+                    tag.group_id().to_string(),
+                    tag.student_id().to_string(),
+                    meta.name().to_string(),
+                    format_mark(meta.mark()),
+                    format_mark(meta.provisional_mark()),
+                    meta.notes().unwrap_or("").replace('\n', " "),
+                ];
+
+                if globals.hide_names() {
+                    row.remove(2);
+                }
+
+                row
+            })
+            .collect::<Vec<_>>();
+
+        match globals.hide_names() {
+            true  => print_table(&["group", "zid", "mark", "provisional", "notes"], &rows),
+            false => print_table(&["group", "zid", "name", "mark", "provisional", "notes"], &rows),
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn show(globals: &Globals, auth: &Authentication, assignment: &str, zid: &str, files: bool, json: bool) -> Result<()> {
+    let journals = fetch_journals(globals, auth, assignment).await?;
+
+    let tag = match journals.iter().find(|(tag, _)| tag.student_id() == zid) {
+        Some((tag, _)) => tag.clone(),
+        None => bail!("{zid} has no journal for {assignment}"),
+    };
+
+    journals.load(&tag, auth.clone()).await?;
+
+    let journal = journals.get(&tag).await
+        .expect("journal was just found");
+    let meta = journal.meta();
+    let data = journal.data()
+        .expect("journal was just loaded");
+
+    // loading adds an empty file for the mark being marked, which isn't really there
+    let marks = data.marking_files().iter()
+        .filter(|file| file.is_on_imark())
+        .map(|file| Ok(MarkJson {
+            name: file.file_name(),
+            at: file.stamp().and_then(|stamp| stamp.at.as_deref()),
+            by: file.stamp().and_then(|stamp| stamp.by.as_deref()),
+            text: file.read_text()?,
+        }))
+        .collect::<Result<Vec<_>>>()?;
+
+    let files = data.submission_files().iter()
+        .map(|file| Ok(FileJson {
+            name: file.file_name(),
+            contents: if files { Some(file.read_text()?) } else { None },
+        }))
+        .collect::<Result<Vec<_>>>()?;
+
+    if json {
+        let show = ShowJson {
+            journal: JournalJson {
+                assignment: tag.assignment(),
+                group_id: tag.group_id(),
+                student_id: tag.student_id(),
+                name: Some(meta.name()).filter(|_| !globals.hide_names()),
+                provisional_mark: meta.provisional_mark(),
+                mark: meta.mark(),
+                notes: meta.notes(),
+            },
+            marks,
+            files,
+        };
+
+        println!("{}", serde_json::to_string_pretty(&show)?);
+        return Ok(());
+    }
+
+    match globals.hide_names() {
+        true  => println!("{} -- {}, group {}", tag.student_id(), tag.assignment(), tag.group_id()),
+        false => println!("{} ({}) -- {}, group {}", tag.student_id(), meta.name(), tag.assignment(), tag.group_id()),
+    }
+    match meta.mark() {
+        Some(mark) => println!("Mark: {mark}"),
+        None => println!("Mark: unmarked"),
+    }
+
+    if let Some(provisional_mark) = meta.provisional_mark() {
+        println!("Provisional mark: {provisional_mark}");
+    }
+
+    if let Some(notes) = meta.notes() {
+        println!("Notes: {notes}");
+    }
+
+    for mark in &marks {
+        match (mark.by, mark.at) {
+            (Some(by), Some(at)) => println!("\n== {} (by {by} at {at}) ==", mark.name),
+            _ => println!("\n== {} ==", mark.name),
+        }
+
+        println!("{}", mark.text.trim_end());
+    }
+
+    println!("\n== files ==");
+    for file in &files {
+        match &file.contents {
+            Some(contents) => println!("-- {} --\n{}", file.name, contents.trim_end()),
+            None => println!("{}", file.name),
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes the given columns for every journal in an assignment to `output` (or stdout).
+pub async fn export(
+    globals: &Globals,
+    auth: &Authentication,
+    assignment: &str,
+    columns: &[Column],
+    format: ExportFormat,
+    output: Option<&Path>,
+) -> Result<()> {
+    if globals.hide_names() && columns.contains(&Column::Name) {
+        bail!("Can't export names with --hide-names -- leave out the name column");
+    }
+
+    let rows = export_rows(globals, auth, assignment, columns).await?;
+
+    let writer: Box<dyn Write> = match output {
+        Some(path) => Box::new(
+            File::create(path)
+                .with_context(|| format!("Failed to create {}", path.display()))?
+        ),
+        None => Box::new(io::stdout()),
+    };
+
+    write_export(writer, columns, format, &rows)
+}
+
+/// Every journal's values for the given columns, in the journals' order.
+async fn export_rows(globals: &Globals, auth: &Authentication, assignment: &str, columns: &[Column]) -> Result<Vec<Vec<Value>>> {
+    let journals = fetch_journals(globals, auth, assignment).await?;
+    let needs_load = columns.iter().any(|column| column.needs_load());
+
+    let mut rows = vec![];
+    for (index, (tag, journal)) in journals.iter().enumerate() {
+        if needs_load {
+            eprint!("\rLoading marks: {}/{}", index + 1, journals.len());
+
+            journals.load(tag, auth.clone()).await
+                .with_context(|| format!("Failed to load the journal for {}", tag.student_id()))?;
+        }
+
+        let journal = journal.lock().await;
+        let meta = journal.meta();
+        let mark_file = journal.data()
+            .and_then(|data| data.marking_file(globals.mark_name()));
+
+        let row = columns.iter()
+            .map(|column| Ok(match column {
+                Column::Zid         => json!(tag.student_id()),
+                Column::Group       => json!(tag.group_id()),
+                Column::Name        => json!(meta.name()),
+                Column::Mark        => json!(meta.mark()),
+                Column::Provisional => json!(meta.provisional_mark()),
+                Column::Notes       => json!(meta.notes()),
+                Column::Marker      => json!(mark_file.and_then(|file| file.stamp()).and_then(|stamp| stamp.by.as_deref())),
+                Column::Text        => json!(mark_file.map(|file| file.read_text()).transpose()?),
+            }))
+            .collect::<Result<Vec<_>>>()?;
+
+        rows.push(row);
+    }
+
+    if needs_load {
+        eprintln!();
+    }
+
+    Ok(rows)
+}
+
+fn write_export(writer: impl Write, columns: &[Column], format: ExportFormat, rows: &[Vec<Value>]) -> Result<()> {
+    match format {
+        ExportFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(writer);
+
+            writer.write_record(columns.iter().map(|column| column.name()))?;
+
+            for row in rows {
+                writer.write_record(row.iter().map(|value| match value {
+                    Value::Null => String::new(),
+                    Value::String(value) => value.clone(),
+                    value => value.to_string(),
+                }))?;
+            }
+
+            writer.flush()?;
+        }
+        ExportFormat::Json => {
+            let rows = rows.iter()
+                .map(|row| ExportRow { columns, values: row })
+                .collect::<Vec<_>>();
+
+            let mut writer = writer;
+            serde_json::to_writer_pretty(&mut writer, &rows)?;
+            writeln!(writer)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct ImportRow {
+    zid: String,
+    mark: f64,
+    #[serde(default)]
+    comment: String,
+}
+
+/// Sets marks from a CSV of zid, mark and (optionally) comment, after
+/// showing what would change and asking to go ahead.
+#[allow(clippy::too_many_arguments)]
+pub async fn import(
+    globals: &Globals,
+    auth: &Authentication,
+    assignment: &str,
+    path: &Path,
+    min_mark: f64,
+    max_mark: Option<f64>,
+    dry_run: bool,
+    yes: bool,
+) -> Result<()> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_path(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+
+    let mut rows = vec![];
+    let mut seen = HashSet::new();
+
+    for (index, row) in reader.deserialize::<ImportRow>().enumerate() {
+        // the header is line 1
+        let line_number = index + 2;
+
+        let row = row.with_context(|| format!("{} line {line_number}: expected a zid, a mark and optionally a comment", path.display()))?;
+
+        if !row.mark.is_finite() {
+            bail!("{} line {line_number}: `{}` is not a mark", path.display(), row.mark);
+        }
+
+        if let Err(err) = MarkOverride::check_bounds(row.mark, min_mark, max_mark) {
+            bail!("{} line {line_number}: {} for {}, but {err}", path.display(), row.mark, row.zid);
+        }
+
+        if !seen.insert(row.zid.to_string()) {
+            bail!("{} line {line_number}: {} appears more than once", path.display(), row.zid);
+        }
+
+        rows.push(row);
+    }
+
+    let journals = fetch_journals(globals, auth, assignment).await?;
+
+    let mut unknown = vec![];
+    let mut changes = vec![];
+    let mut n_unchanged = 0;
+
+    for row in rows {
+        let tag = match journals.iter().find(|(tag, _)| tag.student_id() == row.zid) {
+            Some((tag, _)) => tag.clone(),
+            None => {
+                unknown.push(row.zid);
+                continue;
+            }
+        };
+
+        let journal = journals.get(&tag).await
+            .expect("journal was just found");
+        let current = journal.meta().mark();
+        let name = journal.meta().name().to_string();
+        drop(journal);
+
+        match current {
+            Some(current) if (current - row.mark).abs() < 0.005 => n_unchanged += 1,
+            _ => changes.push((tag, name, current, row)),
+        }
+    }
+
+    if !unknown.is_empty() {
+        bail!("No journal for {assignment} matches: {}", unknown.join(", "));
+    }
+
+    if changes.is_empty() {
+        println!("Nothing to change -- all {n_unchanged} mark(s) are already as imported");
+        return Ok(());
+    }
+
+    let table = changes.iter()
+        .map(|(tag, name, current, row)| {
+            let mut row = vec![
+                tag.student_id().to_string(),
+                name.to_string(),
+                format_mark(*current),
+                row.mark.to_string(),
+                row.comment.replace('\n', " "),
+            ];
+
+            if globals.hide_names() {
+                row.remove(1);
+            }
+
+            row
+        })
+        .collect::<Vec<_>>();
+
+    match globals.hide_names() {
+        true  => print_table(&["zid", "current", "new", "comment"], &table),
+        false => print_table(&["zid", "name", "current", "new", "comment"], &table),
+    }
+    println!("\n{} mark(s) to change, {n_unchanged} unchanged", changes.len());
+
+    if dry_run {
+        return Ok(());
+    }
+
+    if !yes && !confirm(&format!("Set {} mark(s) on imark? [y/N] ", changes.len()))? {
+        println!("Nothing changed");
+        return Ok(());
+    }
+
+    let reason = format!("imported from {}", path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default());
+
+    // every row is loaded and checked against imark before anything is sent,
+    // so one that can't be imported doesn't leave the rest half applied
+    let mut ready = vec![];
+    let mut problems = vec![];
+
+    for (tag, _, _, row) in changes {
+        let student_id = tag.student_id().to_string();
+
+        let submission = MarkSubmission {
+            choices:       ChoiceSelections::default(),
+            comment:       row.comment,
+            mark_override: Some(MarkOverride { mark: row.mark, reason: reason.to_string() }),
+        };
+
+        let pending = match load_and_log(&journals, &tag, submission, auth).await {
+            Ok(pending) => pending,
+            Err(err) => {
+                problems.push(format!("{student_id}: {err:#}"));
+                continue;
+            }
+        };
+
+        match imark::check_mark(globals.imark().as_ref(), pending.clone(), auth).await {
+            Ok(Prepared::Ready(pending)) => ready.push(pending),
+            Ok(Prepared::Conflict(_)) => {
+                discard(globals, &pending)?;
+                problems.push(format!("{student_id}: the mark changed on imark while importing"));
+            }
+            Err(err) => {
+                discard(globals, &pending)?;
+                problems.push(format!("{student_id}: {err:#}"));
+            }
+        }
+    }
+
+    if !problems.is_empty() {
+        for pending in &ready {
+            discard(globals, pending)?;
+        }
+
+        bail!(
+            "Nothing was changed, as {} row(s) couldn't be imported -- run the import again to see the latest:\n  {}",
+            problems.len(),
+            problems.join("\n  "),
+        );
+    }
+
+    let n_ready = ready.len();
+    let mut failed = vec![];
+
+    for pending in ready {
+        let student_id = pending.tag.student_id().to_string();
+
+        // the mark was logged as pending, so if this fails it's replayed next time flymark marks
+        match imark::send_mark(globals.imark().as_ref(), &pending, auth).await {
+            Ok(()) => {
+                if let Some(mark_log) = globals.mark_log() {
+                    mark_log.record_committed(&pending.id)?;
+                }
+
+                println!("{student_id}: {}", pending.mark);
+            }
+            Err(err) => failed.push(format!("{student_id}: {err:#}")),
+        }
+    }
+
+    println!("\nSet {} mark(s), {} failed", n_ready - failed.len(), failed.len());
+
+    if !failed.is_empty() {
+        bail!("Failed to set {} mark(s):\n  {}", failed.len(), failed.join("\n  "));
+    }
+
+    Ok(())
+}
+
+async fn load_and_log(journals: &Journals, tag: &JournalTag, submission: MarkSubmission, auth: &Authentication) -> Result<PendingMark> {
+    journals.load(tag, auth.clone()).await
+        .context("Failed to load the journal")?;
+
+    journals.log_mark(tag, submission, auth.clone()).await
+}
+
+/// Takes a mark that won't be sent back out of the mark log, so it isn't replayed.
+fn discard(globals: &Globals, pending: &PendingMark) -> Result<()> {
+    match globals.mark_log() {
+        Some(mark_log) => mark_log.record_discarded(&pending.id),
+        None => Ok(()),
+    }
+}
+
+fn confirm(question: &str) -> Result<bool> {
+    print!("{question}");
+    io::stdout().flush()?;
+
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// An exported row as a JSON object, keeping the columns in the order they were asked for.
+struct ExportRow<'a> {
+    columns: &'a [Column],
+    values: &'a [Value],
+}
+
+impl Serialize for ExportRow<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.columns.len()))?;
+
+        for (column, value) in self.columns.iter().zip(self.values) {
+            map.serialize_entry(column.name(), value)?;
+        }
+
+        map.end()
+    }
+}
+
+async fn fetch_journals(globals: &Globals, auth: &Authentication, assignment: &str) -> Result<Journals> {
+    let output = FetchJournalsTask {
+        globals: globals.clone(),
+        auth: auth.clone(),
+        assignment: assignment.to_string(),
+    }
+    .run()
+    .await?;
+
+    Ok(output.journals)
+}
+
+fn format_mark(mark: Option<f64>) -> String {
+    mark.map(|mark| mark.to_string()).unwrap_or_default()
+}
+
+fn print_table(headers: &[&str], rows: &[Vec<String>]) {
+    let mut widths = headers.iter()
+        .map(|header| header.chars().count())
+        .collect::<Vec<_>>();
+
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let print_row = |cells: &mut dyn Iterator<Item = &str>| {
+        let line = cells.zip(&widths)
+            .map(|(cell, width)| format!("{cell:width$}"))
+            .collect::<Vec<_>>()
+            .join("  ");
+
+        println!("{}", line.trim_end());
+    };
+
+    print_row(&mut headers.iter().copied());
+
+    for row in rows {
+        print_row(&mut row.iter().map(String::as_str));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, fs, sync::Arc};
+
+    use reqwest::StatusCode;
+
+    use super::*;
+    use crate::{imark::{client::{FakeImark, SubmissionJson, SubmissionSummary}, tests::{MARK_NAME, auth}}, marklog::MarkLog};
+
+    fn student(zid: &str) -> JournalTag {
+        JournalTag::new(String::from("ass1"), String::from("g1"), zid.to_string())
+    }
+
+    fn two_students() -> Arc<FakeImark> {
+        let summary = SubmissionSummary { name: String::from("Student"), ..Default::default() };
+
+        let fake = FakeImark::new()
+            .with_submission(student("z5000001"), summary.clone(), SubmissionJson::default())
+            .with_submission(student("z5000002"), summary, SubmissionJson::default());
+
+        Arc::new(fake)
+    }
+
+    fn globals(imark: Arc<FakeImark>, mark_log: MarkLog) -> Globals {
+        Globals::headless(String::from("http://imark.test"), String::from(MARK_NAME), false, Some(mark_log), imark)
+    }
+
+    fn mark_of(imark: &FakeImark, zid: &str) -> Option<f64> {
+        imark.submission(&student(zid)).unwrap().metadata.mark
+    }
+
+    async fn export_to_string(imark: Arc<FakeImark>, columns: &[Column], format: ExportFormat) -> String {
+        let globals = Globals::headless(String::from("http://imark.test"), String::from(MARK_NAME), false, None, imark);
+        let rows = export_rows(&globals, &auth(), "ass1", columns).await.unwrap();
+
+        let mut output = vec![];
+        write_export(&mut output, columns, format, &rows).unwrap();
+
+        String::from_utf8(output).unwrap()
+    }
+
+    fn awkward_student() -> Arc<FakeImark> {
+        let summary = SubmissionSummary {
+            name:             String::from("Jo \"JJ\" Smith, Jr"),
+            provisional_mark: Some(3.5),
+            mark:             Some(4.0),
+            notes:            Some(String::from("Late, but\ngood")),
+        };
+
+        Arc::new(FakeImark::new().with_submission(student("z5000001"), summary, SubmissionJson::default()))
+    }
+
+    #[tokio::test]
+    async fn csv_export_quotes_names_and_notes() {
+        let csv = export_to_string(awkward_student(), Column::DEFAULT, ExportFormat::Csv).await;
+
+        assert_eq!(
+            csv,
+            "zid,group,name,mark,provisional,notes\n\
+             z5000001,g1,\"Jo \"\"JJ\"\" Smith, Jr\",4.0,3.5,\"Late, but\ngood\"\n",
+        );
+
+        // and reads back as it was
+        let mut reader = csv::Reader::from_reader(csv.as_bytes());
+        let record = reader.records().next().unwrap().unwrap();
+        assert_eq!(&record[2], "Jo \"JJ\" Smith, Jr");
+        assert_eq!(&record[5], "Late, but\ngood");
+    }
+
+    #[tokio::test]
+    async fn csv_export_leaves_missing_values_empty() {
+        let csv = export_to_string(two_students(), &[Column::Zid, Column::Mark, Column::Notes], ExportFormat::Csv).await;
+
+        assert_eq!(csv, "zid,mark,notes\nz5000001,,\nz5000002,,\n");
+    }
+
+    #[tokio::test]
+    async fn json_export_has_just_the_columns_asked_for_in_order() {
+        let json = export_to_string(awkward_student(), &[Column::Notes, Column::Zid, Column::Mark, Column::Name], ExportFormat::Json).await;
+
+        assert_eq!(
+            json,
+            r#"[
+  {
+    "notes": "Late, but\ngood",
+    "zid": "z5000001",
+    "mark": 4.0,
+    "name": "Jo \"JJ\" Smith, Jr"
+  }
+]
+"#,
+        );
+    }
+
+    #[tokio::test]
+    async fn json_export_writes_missing_values_as_null() {
+        let json = export_to_string(two_students(), &[Column::Zid, Column::Provisional], ExportFormat::Json).await;
+        let rows: Vec<BTreeMap<String, Value>> = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(rows.len(), 2);
+        for row in rows {
+            assert_eq!(row.keys().collect::<Vec<_>>(), ["provisional", "zid"]);
+            assert_eq!(row["provisional"], Value::Null);
+        }
+    }
+
+    #[tokio::test]
+    async fn names_cant_be_exported_when_hidden() {
+        let globals = Globals::headless(String::from("http://imark.test"), String::from(MARK_NAME), true, None, awkward_student());
+
+        assert!(export(&globals, &auth(), "ass1", Column::DEFAULT, ExportFormat::Csv, None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn nothing_is_imported_if_any_row_cant_be() {
+        let dir = tempfile::tempdir().unwrap();
+        let csv = dir.path().join("marks.csv");
+        fs::write(&csv, "zid,mark,comment\nz5000001,4,Good\nz5000002,3,Fine\n").unwrap();
+
+        let imark = two_students();
+        imark.break_submission(&student("z5000002"));
+
+        let (mark_log, _) = MarkLog::open(&dir.path().join("marks.log")).unwrap();
+        let globals = globals(imark.clone(), mark_log);
+
+        let err = import(&globals, &auth(), "ass1", &csv, 0.0, None, false, true).await.unwrap_err();
+        assert!(err.to_string().contains("z5000002"));
+
+        assert_eq!(mark_of(&imark, "z5000001"), None);
+        assert_eq!(mark_of(&imark, "z5000002"), None);
+
+        // and nothing is left in the log to be replayed later
+        drop(globals);
+        let (_, uncommitted) = MarkLog::open(&dir.path().join("marks.log")).unwrap();
+        assert!(uncommitted.is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_failed_send_doesnt_stop_the_rest() {
+        let dir = tempfile::tempdir().unwrap();
+        let csv = dir.path().join("marks.csv");
+        fs::write(&csv, "zid,mark,comment\nz5000001,4,Good\nz5000002,3,Fine\n").unwrap();
+
+        let imark = two_students();
+        imark.reject_next_put(StatusCode::SERVICE_UNAVAILABLE);
+
+        let (mark_log, _) = MarkLog::open(&dir.path().join("marks.log")).unwrap();
+        let globals = globals(imark.clone(), mark_log);
+
+        let err = import(&globals, &auth(), "ass1", &csv, 0.0, None, false, true).await.unwrap_err();
+        assert!(err.to_string().contains("Failed to set 1 mark(s)"));
+
+        assert_eq!(mark_of(&imark, "z5000001"), None);
+        assert_eq!(mark_of(&imark, "z5000002"), Some(3.0));
+
+        // the failed one stays logged, to be replayed
+        drop(globals);
+        let (_, uncommitted) = MarkLog::open(&dir.path().join("marks.log")).unwrap();
+        assert_eq!(uncommitted.len(), 1);
+        assert_eq!(uncommitted[0].tag, student("z5000001"));
+    }
+}
@@ -0,0 +1,351 @@
+use std::{collections::{BTreeMap, HashSet}, fs, path::{Path, PathBuf}};
+
+use anyhow::{Result, Context, bail};
+use serde::Deserialize;
+
+use crate::imark::ordering::SortKey;
+
+/// Settings from `~/.config/flymark/config.toml` and the nearest
+/// `.flymark.toml`, the latter taking precedence.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Config {
+    default_profile: Option<String>,
+    profile: BTreeMap<String, Profile>,
+}
+
+/// A named bundle of settings, so a course can be marked without
+/// retyping every flag. Anything given on the command line wins.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profile {
+    pub cgi_endpoint: Option<String>,
+    pub course: Option<String>,
+    pub session: Option<String>,
+    pub scheme: Option<PathBuf>,
+    pub mark_name: Option<String>,
+    pub pager_command: Option<String>,
+    pub preload: Option<usize>,
+    pub hide_names: Option<bool>,
+    pub sort: Option<SortKey>,
+    pub sort_seed: Option<u64>,
+    pub shard: Option<String>,
+    pub allocation: Option<PathBuf>,
+    pub show_others: Option<bool>,
+    pub zid: Option<String>,
+    pub password_command: Option<String>,
+    pub keys: KeyOverrides,
+}
+
+impl Profile {
+    /// Fills in anything this profile leaves out from `other`.
+    fn or(self, other: Profile) -> Profile {
+        Profile {
+            cgi_endpoint:     self.cgi_endpoint.or(other.cgi_endpoint),
+            course:           self.course.or(other.course),
+            session:          self.session.or(other.session),
+            scheme:           self.scheme.or(other.scheme),
+            mark_name:        self.mark_name.or(other.mark_name),
+            pager_command:    self.pager_command.or(other.pager_command),
+            preload:          self.preload.or(other.preload),
+            hide_names:       self.hide_names.or(other.hide_names),
+            sort:             self.sort.or(other.sort),
+            sort_seed:        self.sort_seed.or(other.sort_seed),
+            shard:            self.shard.or(other.shard),
+            allocation:       self.allocation.or(other.allocation),
+            show_others:      self.show_others.or(other.show_others),
+            zid:              self.zid.or(other.zid),
+            password_command: self.password_command.or(other.password_command),
+            keys:             self.keys.or(other.keys),
+        }
+    }
+}
+
+/// The failed submissions list's keys for retrying and discarding, which
+/// can't be rebound -- so nothing it also listens for can be bound to them.
+pub const RETRY_KEY:   char = 'r';
+pub const DISCARD_KEY: char = 'd';
+
+/// Keys rebound by a profile. Any left out keep their default.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeyOverrides {
+    pub down: Option<char>,
+    pub up: Option<char>,
+    pub toggle: Option<char>,
+    pub comment: Option<char>,
+    pub override_mark: Option<char>,
+    pub failures: Option<char>,
+    pub skip: Option<char>,
+    pub back: Option<char>,
+    pub quit: Option<char>,
+}
+
+impl KeyOverrides {
+    fn or(self, other: KeyOverrides) -> KeyOverrides {
+        KeyOverrides {
+            down:          self.down.or(other.down),
+            up:            self.up.or(other.up),
+            toggle:        self.toggle.or(other.toggle),
+            comment:       self.comment.or(other.comment),
+            override_mark: self.override_mark.or(other.override_mark),
+            failures:      self.failures.or(other.failures),
+            skip:          self.skip.or(other.skip),
+            back:          self.back.or(other.back),
+            quit:          self.quit.or(other.quit),
+        }
+    }
+
+    /// The marking screen's keys, with these rebindings applied.
+    pub fn bindings(&self) -> KeyBindings {
+        let default = KeyBindings::default();
+
+        KeyBindings {
+            down:          self.down.unwrap_or(default.down),
+            up:            self.up.unwrap_or(default.up),
+            toggle:        self.toggle.unwrap_or(default.toggle),
+            comment:       self.comment.unwrap_or(default.comment),
+            override_mark: self.override_mark.unwrap_or(default.override_mark),
+            failures:      self.failures.unwrap_or(default.failures),
+            skip:          self.skip.unwrap_or(default.skip),
+            back:          self.back.unwrap_or(default.back),
+            quit:          self.quit.unwrap_or(default.quit),
+        }
+    }
+}
+
+/// The marking screen's keys.
+#[derive(Debug, Clone)]
+pub struct KeyBindings {
+    pub down: char,
+    pub up: char,
+    pub toggle: char,
+    pub comment: char,
+    pub override_mark: char,
+    pub failures: char,
+    pub skip: char,
+    pub back: char,
+    pub quit: char,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Down,
+    Up,
+    Toggle,
+    Comment,
+    OverrideMark,
+    Failures,
+    Skip,
+    Back,
+    Quit,
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        Self {
+            down:          'j',
+            up:            'k',
+            toggle:        ' ',
+            comment:       'c',
+            override_mark: 'm',
+            failures:      'f',
+            skip:          's',
+            back:          'b',
+            quit:          'q',
+        }
+    }
+}
+
+impl KeyBindings {
+    fn bindings(&self) -> [(char, Action); 9] {
+        [
+            (self.down,          Action::Down),
+            (self.up,            Action::Up),
+            (self.toggle,        Action::Toggle),
+            (self.comment,       Action::Comment),
+            (self.override_mark, Action::OverrideMark),
+            (self.failures,      Action::Failures),
+            (self.skip,          Action::Skip),
+            (self.back,          Action::Back),
+            (self.quit,          Action::Quit),
+        ]
+    }
+
+    pub fn action(&self, key: char) -> Option<Action> {
+        self.bindings().into_iter()
+            .find(|(bound, _)| *bound == key)
+            .map(|(_, action)| action)
+    }
+
+    /// Whether the marking screen already uses a key, so schemes can't use it as a hotkey.
+    pub fn is_reserved(&self, key: char) -> bool {
+        self.action(key).is_some()
+    }
+
+    fn validate(&self) -> Result<()> {
+        let mut seen = HashSet::new();
+
+        for (key, action) in self.bindings() {
+            if !seen.insert(key) {
+                bail!("Key `{key}` is bound more than once (including to {action:?})");
+            }
+        }
+
+        // the failed submissions list listens for these as well as retry and discard
+        for (key, action) in [(self.down, Action::Down), (self.up, Action::Up), (self.failures, Action::Failures), (self.quit, Action::Quit)] {
+            if key == RETRY_KEY || key == DISCARD_KEY {
+                bail!("Key `{key}` can't be bound to {action:?}, as it retries or discards failed submissions");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Loads the chosen profile -- `name` if given, otherwise the config's
+/// `default_profile`, otherwise one named `default`, otherwise nothing.
+pub fn load_profile(name: Option<&str>) -> Result<Profile> {
+    let mut config = Config::default();
+
+    let paths = global_path().map(|path| (path, false)).into_iter()
+        .chain(local_path().map(|path| (path, true)));
+
+    for (path, is_local) in paths {
+        let local = read_config(&path)
+            .with_context(|| format!("Failed to read config file: {}", path.display()))?;
+
+        if is_local {
+            check_local(&local)
+                .with_context(|| format!("Invalid config file: {}", path.display()))?;
+        }
+
+        config.default_profile = local.default_profile.or(config.default_profile);
+
+        for (profile_name, profile) in local.profile {
+            let profile = match config.profile.remove(&profile_name) {
+                Some(global) => profile.or(global),
+                None => profile,
+            };
+
+            config.profile.insert(profile_name, profile);
+        }
+    }
+
+    let name = match name {
+        Some(name) => name.to_string(),
+        None => match config.default_profile {
+            Some(name) => name,
+            None => String::from("default"),
+        },
+    };
+
+    let profile = match config.profile.remove(&name) {
+        Some(profile) => profile,
+        None if config.profile.is_empty() && name == "default" => Profile::default(),
+        None => {
+            let known = config.profile.keys().cloned().collect::<Vec<_>>().join(", ");
+            bail!("No profile named `{name}` (known profiles: {known})");
+        }
+    };
+
+    profile.keys.bindings().validate()
+        .with_context(|| format!("Invalid keys in profile `{name}`"))?;
+
+    Ok(profile)
+}
+
+/// A `.flymark.toml` is picked up from any directory flymark is run in, checked-out
+/// repos included, so it can't set anything flymark would run as a command --
+/// nor where flymark sends the credentials it finds.
+fn check_local(config: &Config) -> Result<()> {
+    for (name, profile) in &config.profile {
+        let commands = [
+            ("pager_command",    profile.pager_command.is_some()),
+            ("password_command", profile.password_command.is_some()),
+            ("cgi_endpoint",     profile.cgi_endpoint.is_some()),
+        ];
+
+        if let Some((key, _)) = commands.iter().find(|(_, is_set)| *is_set) {
+            bail!("`{key}` (in profile `{name}`) can only be set in ~/.config/flymark/config.toml or on the command line");
+        }
+    }
+
+    Ok(())
+}
+
+fn read_config(path: &Path) -> Result<Config> {
+    let mut config: Config = toml::from_str(&fs::read_to_string(path)?)?;
+
+    // schemes (and allocations) are relative to the config that names them, not wherever flymark is run from
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    for profile in config.profile.values_mut() {
+        if let Some(scheme) = &mut profile.scheme {
+            *scheme = dir.join(&scheme);
+        }
+
+        if let Some(allocation) = &mut profile.allocation {
+            *allocation = dir.join(&allocation);
+        }
+    }
+
+    Ok(config)
+}
+
+fn global_path() -> Option<PathBuf> {
+    let config_dir = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+
+    Some(config_dir.join("flymark/config.toml"))
+        .filter(|path| path.exists())
+}
+
+/// The nearest `.flymark.toml` in the current directory or any above it.
+fn local_path() -> Option<PathBuf> {
+    let cwd = std::env::current_dir().ok()?;
+
+    cwd.ancestors()
+        .map(|dir| dir.join(".flymark.toml"))
+        .find(|path| path.exists())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Config {
+        toml::from_str(text).unwrap()
+    }
+
+    #[test]
+    fn keys_merge_key_by_key() {
+        let global = parse("[profile.x.keys]\nquit = 'x'\nskip = 'n'\n").profile.remove("x").unwrap();
+        let local  = parse("[profile.x.keys]\nquit = 'z'\n").profile.remove("x").unwrap();
+
+        let keys = local.or(global).keys.bindings();
+
+        assert_eq!(keys.quit, 'z');
+        assert_eq!(keys.skip, 'n');
+        assert_eq!(keys.back, KeyBindings::default().back);
+    }
+
+    #[test]
+    fn keys_cant_shadow_the_failures_list() {
+        let keys = KeyOverrides { quit: Some(RETRY_KEY), ..Default::default() };
+        assert!(keys.bindings().validate().is_err());
+
+        // the failures list doesn't listen for skip, so it can share a key with discard
+        let keys = KeyOverrides { skip: Some(DISCARD_KEY), ..Default::default() };
+        assert!(keys.bindings().validate().is_ok());
+    }
+
+    #[test]
+    fn local_config_cant_set_commands() {
+        assert!(check_local(&parse("[profile.x]\npager_command = 'rm -rf ~'\n")).is_err());
+        assert!(check_local(&parse("[profile.x]\npassword_command = 'curl evil.example'\n")).is_err());
+        assert!(check_local(&parse("[profile.x]\ncgi_endpoint = 'https://evil.example'\n")).is_err());
+        assert!(check_local(&parse("[profile.x]\nscheme = 'lab01.txt'\npreload = 3\n")).is_ok());
+    }
+}
@@ -0,0 +1,497 @@
+use std::{iter::Peekable, str::Chars};
+
+use anyhow::{Result, bail};
+
+use crate::imark::{JournalMeta, JournalTag};
+
+/// A parsed journal list filter, e.g. `mark:unmarked OR (prov:>=8 AND NOT notes:"late")`.
+///
+/// Terms next to each other must all match. A word without a field
+/// matches the zid, name or notes.
+#[derive(Debug, Clone)]
+pub enum Filter {
+    All,
+    Term(Term),
+    Not(Box<Filter>),
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+}
+
+#[derive(Debug, Clone)]
+pub enum Term {
+    Mark(MarkField, MarkTest),
+    Text(TextField, Pattern),
+    Any(Pattern),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum MarkField {
+    Mark,
+    Provisional,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum MarkTest {
+    Unmarked,
+    Marked,
+    Compare(Comparison, f64),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Comparison {
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Equal,
+    NotEqual,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum TextField {
+    Zid,
+    Group,
+    Name,
+    Notes,
+}
+
+/// Case-insensitive text to look for. Without a `*` it can appear
+/// anywhere; with one, it has to match the whole value.
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    text: String,
+}
+
+const FIELDS: &str = "mark, prov, notes, name, zid or group";
+
+impl Filter {
+    pub fn parse(filter: &str) -> Result<Self> {
+        let mut parser = Parser { tokens: tokenize(filter)?, position: 0 };
+
+        if parser.tokens.is_empty() {
+            return Ok(Filter::All);
+        }
+
+        let filter = parser.parse_or()?;
+
+        match parser.next() {
+            None => Ok(filter),
+            Some(Token::RightParen) => bail!("unmatched `)`"),
+            Some(token) => bail!("unexpected {}", token.describe()),
+        }
+    }
+
+    pub fn matches(&self, tag: &JournalTag, meta: &JournalMeta) -> bool {
+        match self {
+            Filter::All => true,
+            Filter::Term(term) => term.matches(tag, meta),
+            Filter::Not(filter) => !filter.matches(tag, meta),
+            Filter::And(filters) => filters.iter().all(|filter| filter.matches(tag, meta)),
+            Filter::Or (filters) => filters.iter().any(|filter| filter.matches(tag, meta)),
+        }
+    }
+}
+
+impl Term {
+    fn parse(field: Option<&str>, value: String) -> Result<Self> {
+        let field = match field {
+            Some(field) => field.to_lowercase(),
+            None => return Ok(Term::Any(Pattern::new(value))),
+        };
+
+        let mark_field = match field.as_str() {
+            "mark" => Some(MarkField::Mark),
+            "prov" | "provisional" => Some(MarkField::Provisional),
+            _ => None,
+        };
+
+        if let Some(mark_field) = mark_field {
+            return Ok(Term::Mark(mark_field, MarkTest::parse(&field, &value)?));
+        }
+
+        let text_field = match field.as_str() {
+            "zid" => TextField::Zid,
+            "group" => TextField::Group,
+            "name" => TextField::Name,
+            "notes" => TextField::Notes,
+            _ => bail!("unknown field `{field}` (try {FIELDS})"),
+        };
+
+        Ok(Term::Text(text_field, Pattern::new(value)))
+    }
+
+    fn matches(&self, tag: &JournalTag, meta: &JournalMeta) -> bool {
+        match self {
+            Term::Mark(field, test) => test.matches(match field {
+                MarkField::Mark        => meta.mark(),
+                MarkField::Provisional => meta.provisional_mark(),
+            }),
+            Term::Text(field, pattern) => match field {
+                TextField::Zid   => pattern.matches(tag.student_id()),
+                TextField::Group => pattern.matches(tag.group_id()),
+                TextField::Name  => pattern.matches(meta.name()),
+                TextField::Notes => pattern.matches(meta.notes().unwrap_or("")),
+            },
+            Term::Any(pattern) => {
+                pattern.matches(tag.student_id())
+                || pattern.matches(meta.name())
+                || meta.notes().is_some_and(|notes| pattern.matches(notes))
+            }
+        }
+    }
+}
+
+impl MarkTest {
+    fn parse(field: &str, value: &str) -> Result<Self> {
+        match value.to_lowercase().as_str() {
+            "unmarked" | "none" => return Ok(MarkTest::Unmarked),
+            "marked" | "any" => return Ok(MarkTest::Marked),
+            _ => {}
+        }
+
+        let (comparison, number) = [
+            ("<=", Comparison::LessEqual),
+            (">=", Comparison::GreaterEqual),
+            ("!=", Comparison::NotEqual),
+            ("<",  Comparison::Less),
+            (">",  Comparison::Greater),
+            ("=",  Comparison::Equal),
+        ]
+            .into_iter()
+            .find_map(|(op, comparison)| value.strip_prefix(op).map(|number| (comparison, number)))
+            .unwrap_or((Comparison::Equal, value));
+
+        match number.trim().parse::<f64>() {
+            Ok(number) if number.is_finite() => Ok(MarkTest::Compare(comparison, number)),
+            _ => bail!("`{field}:` needs a number (e.g. `{field}:<5`), `unmarked` or `marked`, not `{value}`"),
+        }
+    }
+
+    fn matches(&self, mark: Option<f64>) -> bool {
+        match (self, mark) {
+            (MarkTest::Unmarked, mark) => mark.is_none(),
+            (MarkTest::Marked, mark) => mark.is_some(),
+            (MarkTest::Compare(_, _), None) => false,
+            (MarkTest::Compare(comparison, number), Some(mark)) => match comparison {
+                Comparison::Less         => mark < *number,
+                Comparison::LessEqual    => mark <= *number,
+                Comparison::Greater      => mark > *number,
+                Comparison::GreaterEqual => mark >= *number,
+                // marks are shown to two decimal places, so compare them that way
+                Comparison::Equal        => (mark - number).abs() < 0.005,
+                Comparison::NotEqual     => (mark - number).abs() >= 0.005,
+            },
+        }
+    }
+}
+
+impl Pattern {
+    fn new(text: String) -> Self {
+        Self { text: text.to_lowercase() }
+    }
+
+    fn matches(&self, value: &str) -> bool {
+        let value = value.to_lowercase();
+
+        if !self.text.contains('*') {
+            return value.contains(&self.text);
+        }
+
+        let parts = self.text.split('*').collect::<Vec<_>>();
+        let (first, rest) = parts.split_first().expect("split always yields a part");
+        let (last, middle) = rest.split_last().expect("text contains a `*`");
+
+        let mut remaining = match value.strip_prefix(first) {
+            Some(remaining) => remaining,
+            None => return false,
+        };
+
+        for part in middle {
+            match remaining.find(part) {
+                Some(index) => remaining = &remaining[index + part.len()..],
+                None => return false,
+            }
+        }
+
+        remaining.ends_with(last)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    LeftParen,
+    RightParen,
+    And,
+    Or,
+    Not,
+    Term { field: Option<String>, value: String },
+}
+
+impl Token {
+    fn describe(&self) -> String {
+        match self {
+            Token::LeftParen => String::from("`(`"),
+            Token::RightParen => String::from("`)`"),
+            Token::And => String::from("`AND`"),
+            Token::Or => String::from("`OR`"),
+            Token::Not => String::from("`NOT`"),
+            Token::Term { field: Some(field), value } => format!("`{field}:{value}`"),
+            Token::Term { field: None, value } => format!("`{value}`"),
+        }
+    }
+}
+
+fn tokenize(filter: &str) -> Result<Vec<Token>> {
+    let mut tokens = vec![];
+    let mut chars = filter.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => { chars.next(); }
+            '(' => { chars.next(); tokens.push(Token::LeftParen); }
+            ')' => { chars.next(); tokens.push(Token::RightParen); }
+            '-' | '!' => { chars.next(); tokens.push(Token::Not); }
+            _ => tokens.push(tokenize_term(&mut chars)?),
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// Reads one word, e.g. `notes:"handed in late"`, up to whitespace or a bracket.
+fn tokenize_term(chars: &mut Peekable<Chars>) -> Result<Token> {
+    let mut field = None;
+    let mut value = String::new();
+    let mut quoted = false;
+
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => break,
+            '(' | ')' => break,
+            '"' => {
+                chars.next();
+                quoted = true;
+
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some(c) => value.push(c),
+                        None => bail!("unterminated `\"`"),
+                    }
+                }
+            }
+            ':' if field.is_none() && !quoted => {
+                chars.next();
+                field = Some(std::mem::take(&mut value));
+            }
+            c => {
+                chars.next();
+                value.push(c);
+            }
+        }
+    }
+
+    if field.is_none() && !quoted {
+        match value.as_str() {
+            "AND" | "&&" => return Ok(Token::And),
+            "OR"  | "||" => return Ok(Token::Or),
+            "NOT" => return Ok(Token::Not),
+            _ => {}
+        }
+    }
+
+    if let Some(field) = &field {
+        if value.is_empty() && !quoted {
+            bail!("`{field}:` needs something to match");
+        }
+    }
+
+    Ok(Token::Term { field, value })
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn parse_or(&mut self) -> Result<Filter> {
+        let mut filters = vec![self.parse_and()?];
+
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            filters.push(self.parse_and()?);
+        }
+
+        Ok(if filters.len() == 1 { filters.remove(0) } else { Filter::Or(filters) })
+    }
+
+    fn parse_and(&mut self) -> Result<Filter> {
+        let mut filters = vec![self.parse_not()?];
+
+        loop {
+            match self.peek() {
+                Some(Token::And) => {
+                    self.next();
+                    filters.push(self.parse_not()?);
+                }
+                Some(Token::Or | Token::RightParen) | None => break,
+                // terms next to each other are ANDed together
+                Some(_) => filters.push(self.parse_not()?),
+            }
+        }
+
+        Ok(if filters.len() == 1 { filters.remove(0) } else { Filter::And(filters) })
+    }
+
+    fn parse_not(&mut self) -> Result<Filter> {
+        match self.next() {
+            Some(Token::Not) => Ok(Filter::Not(Box::new(self.parse_not()?))),
+            Some(Token::LeftParen) => {
+                let filter = self.parse_or()?;
+
+                match self.next() {
+                    Some(Token::RightParen) => Ok(filter),
+                    _ => bail!("missing `)`"),
+                }
+            }
+            Some(Token::Term { field, value }) => Ok(Filter::Term(Term::parse(field.as_deref(), value)?)),
+            Some(token) => bail!("expected a search term, not {}", token.describe()),
+            None => bail!("expected a search term at the end"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn journal(zid: &str, name: &str, mark: Option<f64>, notes: Option<&str>) -> (JournalTag, JournalMeta) {
+        (
+            JournalTag::new(String::from("ass1"), String::from("g1"), zid.to_string()),
+            JournalMeta::new(name.to_string(), None, mark, notes.map(str::to_string)),
+        )
+    }
+
+    fn matches(filter: &str, (tag, meta): &(JournalTag, JournalMeta)) -> bool {
+        Filter::parse(filter).unwrap().matches(tag, meta)
+    }
+
+    fn error(filter: &str) -> String {
+        Filter::parse(filter).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        let alpha      = journal("z5000001", "alpha", None, None);
+        let beta       = journal("z5000002", "beta", None, None);
+        let beta_gamma = journal("z5000003", "beta gamma", None, None);
+
+        // `alpha OR (beta AND gamma)`, not `(alpha OR beta) AND gamma`
+        assert!( matches("alpha OR beta gamma", &alpha));
+        assert!(!matches("alpha OR beta gamma", &beta));
+        assert!( matches("alpha OR beta gamma", &beta_gamma));
+
+        assert!(!matches("(alpha OR beta) gamma", &alpha));
+        assert!( matches("alpha || beta && gamma", &beta_gamma));
+    }
+
+    #[test]
+    fn not_applies_to_the_next_term() {
+        let alpha = journal("z5000001", "alpha", None, None);
+        let beta  = journal("z5000002", "beta", None, None);
+
+        for filter in ["NOT alpha", "-alpha", "!alpha"] {
+            assert!(!matches(filter, &alpha));
+            assert!( matches(filter, &beta));
+        }
+
+        // `(NOT alpha) AND beta`
+        assert!( matches("NOT alpha beta", &beta));
+        assert!(!matches("NOT alpha beta", &alpha));
+
+        assert!(!matches("-(alpha OR beta)", &beta));
+        assert!( matches("NOT NOT alpha", &alpha));
+    }
+
+    #[test]
+    fn marks_compare_and_match_unmarked() {
+        let low      = journal("z5000001", "low", Some(3.0), None);
+        let high     = journal("z5000002", "high", Some(8.5), None);
+        let unmarked = journal("z5000003", "unmarked", None, None);
+
+        assert!( matches("mark:<5", &low));
+        assert!(!matches("mark:<5", &high));
+        assert!(!matches("mark:<5", &unmarked));
+
+        assert!( matches("mark:unmarked", &unmarked));
+        assert!(!matches("mark:unmarked", &low));
+        assert!( matches("mark:marked", &high));
+
+        assert!( matches("mark:>=8.5", &high));
+        assert!( matches("mark:3", &low));
+        assert!( matches("mark:!=3", &high));
+        assert!( matches("mark:<5 OR mark:unmarked", &unmarked));
+    }
+
+    #[test]
+    fn quoted_values_keep_their_spaces() {
+        let late = journal("z5000001", "Beta Gamma", None, Some("Handed in late, penalty applied"));
+        let other = journal("z5000002", "Delta", None, Some("late start"));
+
+        assert!( matches(r#"notes:"handed in late""#, &late));
+        assert!(!matches(r#"notes:"handed in late""#, &other));
+        assert!( matches(r#""beta gamma""#, &late));
+
+        // without quotes each word is its own term, matching anywhere
+        assert!( matches("notes:handed in late", &late));
+        assert!( matches("notes:late start", &other));
+    }
+
+    #[test]
+    fn globs_match_the_whole_value() {
+        let prefixed = journal("z5120000", "alpha", None, None);
+        let suffixed = journal("z5000512", "beta", None, None);
+        let ending   = journal("z5120009", "gamma", None, None);
+
+        assert!( matches("zid:z512*", &prefixed));
+        assert!(!matches("zid:z512*", &suffixed));
+
+        assert!( matches("zid:z5*9", &ending));
+        assert!(!matches("zid:z5*9", &prefixed));
+        assert!( matches("zid:z5*12*0", &prefixed));
+
+        // without a `*` it can be anywhere
+        assert!( matches("zid:512", &suffixed));
+        assert!( matches("name:a*a", &prefixed));
+        assert!(!matches("name:a*a", &suffixed));
+    }
+
+    #[test]
+    fn bad_filters_say_what_is_wrong() {
+        assert_eq!(error(")"), "expected a search term, not `)`");
+        assert_eq!(error("alpha)"), "unmatched `)`");
+        assert_eq!(error("(alpha"), "missing `)`");
+        assert_eq!(error(r#"notes:"late"#), "unterminated `\"`");
+        assert_eq!(error("mark:"), "`mark:` needs something to match");
+        assert!(error("mark:abc").contains("needs a number"));
+        assert!(error("colour:red").starts_with("unknown field `colour`"));
+        assert_eq!(error("alpha OR"), "expected a search term at the end");
+    }
+
+    #[test]
+    fn an_empty_filter_matches_everything() {
+        assert!(matches("", &journal("z5000001", "alpha", None, None)));
+        assert!(matches("   ", &journal("z5000001", "alpha", None, None)));
+    }
+}
@@ -0,0 +1,104 @@
+use std::{collections::BTreeMap, fs::{self, DirBuilder}, os::unix::fs::DirBuilderExt, path::{Path, PathBuf}};
+
+use anyhow::{Result, Context};
+use serde::{Deserialize, Serialize};
+
+use super::{JournalMeta, JournalTag, client::FileJson};
+
+/// Submitted files saved to disk as they're loaded, so reopening flymark
+/// doesn't mean downloading every journal again.
+///
+/// Each entry is keyed on the journal's listing (name, marks and notes),
+/// so any change to that on imark means the entry is re-fetched. Marks
+/// aren't cached at all: another marker can change a mark's text without
+/// touching the listing, so they're always fetched fresh.
+#[derive(Clone)]
+pub struct SubmissionCache {
+    dir: PathBuf,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CacheEntry {
+    key: String,
+    files: BTreeMap<String, FileJson>,
+}
+
+impl SubmissionCache {
+    pub fn open(dir: PathBuf) -> Result<Self> {
+        // submissions are student work -- keep them private
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&dir)
+            .with_context(|| format!("Failed to create cache directory: {}", dir.display()))?;
+
+        Ok(Self { dir })
+    }
+
+    pub fn clear(dir: &Path) -> Result<()> {
+        if dir.exists() {
+            fs::remove_dir_all(dir)
+                .with_context(|| format!("Failed to clear cache directory: {}", dir.display()))?;
+        }
+
+        Ok(())
+    }
+
+    pub fn key(meta: &JournalMeta) -> String {
+        serde_json::to_string(&(meta.name(), meta.provisional_mark(), meta.mark(), meta.notes()))
+            .expect("journal metadata always serialises")
+    }
+
+    /// The cached submitted files, if there are any and they're still current.
+    pub fn get(&self, tag: &JournalTag, key: &str) -> Option<BTreeMap<String, FileJson>> {
+        let text = fs::read_to_string(self.path(tag)).ok()?;
+        let entry: CacheEntry = serde_json::from_str(&text).ok()?;
+
+        if entry.key == key {
+            Some(entry.files)
+        } else {
+            None
+        }
+    }
+
+    pub fn put(&self, tag: &JournalTag, key: &str, files: &BTreeMap<String, FileJson>) -> Result<()> {
+        let path = self.path(tag);
+
+        if let Some(parent) = path.parent() {
+            DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(parent)?;
+        }
+
+        let entry = CacheEntry { key: key.to_string(), files: files.clone() };
+
+        let temp_path = path.with_extension("json.tmp");
+        fs::write(&temp_path, serde_json::to_vec(&entry)?)?;
+        fs::rename(&temp_path, &path)?;
+
+        Ok(())
+    }
+
+    fn path(&self, tag: &JournalTag) -> PathBuf {
+        self.dir
+            .join(tag.assignment())
+            .join(tag.group_id())
+            .join(format!("{}.json", tag.student_id()))
+    }
+}
+
+/// Where submissions from the given imark are cached, kept apart from
+/// those of any other imark.
+pub fn default_dir(cgi_endpoint: &str) -> Option<PathBuf> {
+    let cache_dir = match std::env::var_os("XDG_CACHE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".cache"),
+    };
+
+    let endpoint = cgi_endpoint.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' { c } else { '_' })
+        .collect::<String>();
+
+    Some(cache_dir.join("flymark/submissions").join(endpoint))
+}
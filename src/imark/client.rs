@@ -0,0 +1,367 @@
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{Result, anyhow, bail};
+use async_trait::async_trait;
+use parking_lot::Mutex;
+use reqwest::{Client, StatusCode};
+use serde::{Deserialize, Serialize};
+
+use super::{Authentication, JournalTag, MarkRejected, InvalidCredentials};
+
+/// Every submission for an assignment, keyed by group id then student id.
+pub type SubmissionList = BTreeMap<String, BTreeMap<String, SubmissionSummary>>;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SubmissionSummary {
+    pub name: String,
+    pub provisional_mark: Option<f64>,
+    pub mark: Option<f64>,
+    pub notes: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SubmissionJson {
+    pub files: BTreeMap<String, FileJson>,
+    pub marks: BTreeMap<String, MarkJson>,
+    pub metadata: MetadataJson,
+}
+
+/// The parts of a submission that other markers can change.
+#[derive(Debug, Clone, Default)]
+pub struct SubmissionMarks {
+    pub marks: BTreeMap<String, MarkJson>,
+    pub metadata: MetadataJson,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FileJson {
+    pub name: String,
+    pub contents: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MarkJson {
+    pub name: String,
+    pub at: Option<String>,
+    pub by: Option<String>,
+    #[serde(default)]
+    pub is_final: bool,
+    pub mark: Option<f64>,
+    pub text: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MetadataJson {
+    pub mark: Option<f64>,
+}
+
+/// The parts of the imark API that flymark uses.
+#[async_trait]
+pub trait ImarkClient: Send + Sync {
+    async fn list_assignments(&self, auth: &Authentication) -> Result<Vec<String>>;
+
+    async fn list_submissions(&self, auth: &Authentication, assignment: &str) -> Result<SubmissionList>;
+
+    async fn get_submission(&self, auth: &Authentication, tag: &JournalTag) -> Result<SubmissionJson>;
+
+    /// Just a submission's marks, for when its files are already cached.
+    /// imark has no marks-only endpoint, so by default this fetches the whole submission.
+    async fn get_marks(&self, auth: &Authentication, tag: &JournalTag) -> Result<SubmissionMarks> {
+        let submission = self.get_submission(auth, tag).await?;
+
+        Ok(SubmissionMarks { marks: submission.marks, metadata: submission.metadata })
+    }
+
+    /// Writes the given marks (keyed by imark id) to a submission, leaving any others alone.
+    async fn put_marks(&self, auth: &Authentication, tag: &JournalTag, marks: BTreeMap<String, MarkJson>) -> Result<()>;
+}
+
+/// Talks to a real imark over HTTP, sharing one connection pool.
+pub struct ReqwestImark {
+    cgi_endpoint: String,
+    client: Client,
+}
+
+impl ReqwestImark {
+    pub fn new(cgi_endpoint: String) -> Self {
+        Self {
+            cgi_endpoint,
+            client: Client::new(),
+        }
+    }
+
+    fn submission_endpoint(&self, tag: &JournalTag) -> String {
+        let imark      = &self.cgi_endpoint;
+        let assignment = tag.assignment();
+        let group_id   = tag.group_id();
+        let student_id = tag.student_id();
+
+        format!("{imark}/api/v1/assignments/{assignment}/submissions/{group_id}/{student_id}/")
+    }
+}
+
+#[async_trait]
+impl ImarkClient for ReqwestImark {
+    async fn list_assignments(&self, auth: &Authentication) -> Result<Vec<String>> {
+        let imark = &self.cgi_endpoint;
+
+        let response = self.client.get(format!("{imark}/api/v1/assignments/"))
+            .basic_auth(auth.username(), Some(auth.password()))
+            .send()
+            .await?;
+
+        if response.status() == StatusCode::UNAUTHORIZED {
+            return Err(InvalidCredentials { zid: auth.username().to_string() }.into());
+        }
+
+        Ok(
+            response
+                .error_for_status()?
+                .json()
+                .await?
+        )
+    }
+
+    async fn list_submissions(&self, auth: &Authentication, assignment: &str) -> Result<SubmissionList> {
+        #[derive(Deserialize)]
+        struct SubmissionsJson {
+            submissions: SubmissionList,
+        }
+
+        let imark = &self.cgi_endpoint;
+
+        let resp: SubmissionsJson = self.client.get(format!("{imark}/api/v1/assignments/{assignment}/submissions/"))
+            .basic_auth(auth.username(), Some(auth.password()))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        Ok(resp.submissions)
+    }
+
+    async fn get_submission(&self, auth: &Authentication, tag: &JournalTag) -> Result<SubmissionJson> {
+        Ok(
+            self.client.get(self.submission_endpoint(tag))
+                .basic_auth(auth.username(), Some(auth.password()))
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?
+        )
+    }
+
+    async fn put_marks(&self, auth: &Authentication, tag: &JournalTag, marks: BTreeMap<String, MarkJson>) -> Result<()> {
+        #[derive(Serialize)]
+        struct MarkPut {
+            marks: BTreeMap<String, MarkJson>,
+            comments: BTreeMap<String, ()>,
+        }
+
+        let resp = self.client.put(self.submission_endpoint(tag))
+            .basic_auth(auth.username(), Some(auth.password()))
+            .json(&MarkPut { marks, comments: BTreeMap::new() })
+            .send()
+            .await?;
+
+        let status = resp.status();
+        let text   = resp.text().await?;
+
+        if !status.is_success() {
+            return Err(MarkRejected { status, body: text.trim().to_string() }.into());
+        }
+
+        if serde_json::from_str::<serde_json::Value>(&text).is_err() {
+            bail!("imark sent an unexpected response to the mark: {}", text.trim());
+        }
+
+        Ok(())
+    }
+}
+
+/// Fills in for a client where one has to exist but is never used, e.g. in
+/// the `Globals` a `mem::take`n `Journals` is left with. Every request fails.
+pub struct NoImark;
+
+#[async_trait]
+impl ImarkClient for NoImark {
+    async fn list_assignments(&self, _auth: &Authentication) -> Result<Vec<String>> {
+        Err(anyhow!("no imark client configured"))
+    }
+
+    async fn list_submissions(&self, _auth: &Authentication, _assignment: &str) -> Result<SubmissionList> {
+        Err(anyhow!("no imark client configured"))
+    }
+
+    async fn get_submission(&self, _auth: &Authentication, _tag: &JournalTag) -> Result<SubmissionJson> {
+        Err(anyhow!("no imark client configured"))
+    }
+
+    async fn put_marks(&self, _auth: &Authentication, _tag: &JournalTag, _marks: BTreeMap<String, MarkJson>) -> Result<()> {
+        Err(anyhow!("no imark client configured"))
+    }
+}
+
+/// An in-memory stand-in for imark, so the state machines can be tested without a server.
+#[cfg(test)]
+#[derive(Default)]
+pub struct FakeImark {
+    users: HashMap<String, String>,
+    assignments: Mutex<BTreeMap<String, BTreeMap<JournalTag, FakeSubmission>>>,
+    /// Statuses to reject the next puts with, in order.
+    rejections: Mutex<Vec<StatusCode>>,
+    /// Text another marker writes straight after the next put lands.
+    interloper: Mutex<Option<String>>,
+    /// How many more submission fetches fail, as if imark were unreachable.
+    failing_gets: Mutex<usize>,
+    /// Submissions that are listed, but can't be fetched.
+    broken: Mutex<Vec<JournalTag>>,
+}
+
+#[cfg(test)]
+#[derive(Clone)]
+struct FakeSubmission {
+    summary: SubmissionSummary,
+    submission: SubmissionJson,
+}
+
+#[cfg(test)]
+impl FakeImark {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only allows the given users in. With no users, any credentials are accepted.
+    pub fn with_user(mut self, username: &str, password: &str) -> Self {
+        self.users.insert(username.to_string(), password.to_string());
+        self
+    }
+
+    pub fn with_submission(self, tag: JournalTag, summary: SubmissionSummary, submission: SubmissionJson) -> Self {
+        self.assignments.lock()
+            .entry(tag.assignment().to_string())
+            .or_default()
+            .insert(tag, FakeSubmission { summary, submission });
+
+        self
+    }
+
+    /// The submission as it currently stands, including any marks put since.
+    pub fn submission(&self, tag: &JournalTag) -> Option<SubmissionJson> {
+        self.assignments.lock()
+            .get(tag.assignment())
+            .and_then(|submissions| submissions.get(tag))
+            .map(|submission| submission.submission.clone())
+    }
+
+    /// Rejects the next put with `status`, after any rejections already queued.
+    pub fn reject_next_put(&self, status: StatusCode) {
+        self.rejections.lock().push(status);
+    }
+
+    /// Has another marker replace the marking text with `text` straight after the next put.
+    pub fn interlope_next_put(&self, text: &str) {
+        *self.interloper.lock() = Some(text.to_string());
+    }
+
+    /// Fails the next `n` submission fetches, as if imark were unreachable.
+    pub fn fail_next_gets(&self, n: usize) {
+        *self.failing_gets.lock() = n;
+    }
+
+    /// Fails every fetch of `tag`'s submission, while still listing it.
+    pub fn break_submission(&self, tag: &JournalTag) {
+        self.broken.lock().push(tag.clone());
+    }
+
+    fn check_auth(&self, auth: &Authentication) -> Result<()> {
+        if self.users.is_empty() || self.users.get(auth.username()).map(String::as_str) == Some(auth.password()) {
+            Ok(())
+        } else {
+            Err(InvalidCredentials { zid: auth.username().to_string() }.into())
+        }
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl ImarkClient for FakeImark {
+    async fn list_assignments(&self, auth: &Authentication) -> Result<Vec<String>> {
+        self.check_auth(auth)?;
+
+        Ok(self.assignments.lock().keys().cloned().collect())
+    }
+
+    async fn list_submissions(&self, auth: &Authentication, assignment: &str) -> Result<SubmissionList> {
+        self.check_auth(auth)?;
+
+        let mut list = SubmissionList::new();
+
+        if let Some(submissions) = self.assignments.lock().get(assignment) {
+            for (tag, submission) in submissions {
+                list.entry(tag.group_id().to_string())
+                    .or_default()
+                    .insert(tag.student_id().to_string(), submission.summary.clone());
+            }
+        }
+
+        Ok(list)
+    }
+
+    async fn get_submission(&self, auth: &Authentication, tag: &JournalTag) -> Result<SubmissionJson> {
+        self.check_auth(auth)?;
+
+        let mut failing_gets = self.failing_gets.lock();
+        if *failing_gets > 0 {
+            *failing_gets -= 1;
+            bail!("imark is unreachable");
+        }
+        drop(failing_gets);
+
+        if self.broken.lock().contains(tag) {
+            bail!("{}'s submission is broken", tag.student_id());
+        }
+
+        match self.submission(tag) {
+            Some(submission) => Ok(submission),
+            None => bail!("no submission for {} in {}", tag.student_id(), tag.assignment()),
+        }
+    }
+
+    async fn put_marks(&self, auth: &Authentication, tag: &JournalTag, marks: BTreeMap<String, MarkJson>) -> Result<()> {
+        self.check_auth(auth)?;
+
+        let mut rejections = self.rejections.lock();
+        if !rejections.is_empty() {
+            let status = rejections.remove(0);
+            return Err(MarkRejected { status, body: String::new() }.into());
+        }
+        drop(rejections);
+
+        let mut assignments = self.assignments.lock();
+        let submission = match assignments.get_mut(tag.assignment()).and_then(|submissions| submissions.get_mut(tag)) {
+            Some(submission) => submission,
+            None => bail!("no submission for {} in {}", tag.student_id(), tag.assignment()),
+        };
+
+        for (imark_id, mark) in marks {
+            if mark.is_final {
+                submission.summary.mark = mark.mark;
+                submission.submission.metadata.mark = mark.mark;
+            }
+
+            submission.submission.marks.insert(imark_id, mark);
+        }
+
+        if let Some(text) = self.interloper.lock().take() {
+            for mark in submission.submission.marks.values_mut() {
+                mark.by   = Some(String::from("interloper"));
+                mark.text = Some(text.to_string());
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,162 @@
+use std::{fs, path::PathBuf, process::Stdio};
+
+use anyhow::{Result, Context, bail};
+use tokio::process::Command;
+
+use super::Authentication;
+
+/// Whatever parts of a login could be found without asking for them.
+#[derive(Debug, Default, Clone)]
+pub struct StoredCredentials {
+    pub zid: Option<String>,
+    pub password: Option<String>,
+}
+
+impl StoredCredentials {
+    /// Looks for credentials in, in order: `zid` (from the command line or
+    /// a profile), `$FLYMARK_ZID` / `$FLYMARK_PASSWORD`, `password_command`,
+    /// then a `.netrc` entry for the cgi endpoint's host.
+    pub async fn find(cgi_endpoint: &str, zid: Option<String>, password_command: Option<&str>) -> Result<Self> {
+        let netrc = netrc_entry(cgi_endpoint)?;
+
+        let zid = zid
+            .or_else(|| env_var("FLYMARK_ZID"))
+            .or_else(|| netrc.as_ref().and_then(|entry| entry.login.clone()));
+
+        let password = match env_var("FLYMARK_PASSWORD") {
+            Some(password) => Some(password),
+            None => match password_command {
+                Some(command) => Some(run_password_command(command).await?),
+                // the netrc's password is only any good for the netrc's login
+                None => netrc
+                    .filter(|entry| entry.login.is_none() || entry.login == zid)
+                    .and_then(|entry| entry.password),
+            },
+        };
+
+        Ok(Self { zid, password })
+    }
+
+    pub fn authentication(&self) -> Option<Authentication> {
+        match (&self.zid, &self.password) {
+            (Some(zid), Some(password)) => Some(Authentication::new(zid.to_string(), password.to_string())),
+            _ => None,
+        }
+    }
+}
+
+fn env_var(name: &str) -> Option<String> {
+    std::env::var(name).ok()
+        .filter(|value| !value.is_empty())
+}
+
+/// Runs e.g. `pass show imark`, taking the first line it prints as the password.
+async fn run_password_command(command: &str) -> Result<String> {
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .output()
+        .await
+        .with_context(|| format!("Failed to run password command: {command}"))?;
+
+    if !output.status.success() {
+        bail!("Password command `{command}` failed ({})", output.status);
+    }
+
+    let stdout = String::from_utf8(output.stdout)
+        .with_context(|| format!("Password command `{command}` printed something other than text"))?;
+
+    match stdout.lines().next() {
+        Some(password) if !password.is_empty() => Ok(password.to_string()),
+        _ => bail!("Password command `{command}` didn't print a password"),
+    }
+}
+
+#[derive(Debug, Default)]
+struct NetrcEntry {
+    login: Option<String>,
+    password: Option<String>,
+}
+
+/// The `.netrc` entry for the endpoint's host, falling back to its `default` entry.
+fn netrc_entry(cgi_endpoint: &str) -> Result<Option<NetrcEntry>> {
+    let host = match reqwest::Url::parse(cgi_endpoint).ok().and_then(|url| url.host_str().map(str::to_string)) {
+        Some(host) => host,
+        None => return Ok(None),
+    };
+
+    let path = match std::env::var_os("NETRC") {
+        Some(path) if !path.is_empty() => PathBuf::from(path),
+        _ => match std::env::var_os("HOME") {
+            Some(home) => PathBuf::from(home).join(".netrc"),
+            None => return Ok(None),
+        },
+    };
+
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let text = fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+
+    // `None` is the `default` entry, for any machine not listed
+    let mut entries: Vec<(Option<String>, NetrcEntry)> = vec![];
+    let mut tokens = netrc_tokens(&text).into_iter();
+
+    while let Some(token) = tokens.next() {
+        match token.as_str() {
+            "machine" => entries.push((tokens.next(), NetrcEntry::default())),
+            "default" => entries.push((None, NetrcEntry::default())),
+            "login" | "password" | "account" => {
+                let value = tokens.next();
+
+                if let Some((_, entry)) = entries.last_mut() {
+                    match token.as_str() {
+                        "login" => entry.login = value,
+                        "password" => entry.password = value,
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let index = entries.iter().position(|(machine, _)| machine.as_deref() == Some(host.as_str()))
+        .or_else(|| entries.iter().position(|(machine, _)| machine.is_none()));
+
+    Ok(index.map(|index| entries.swap_remove(index).1))
+}
+
+/// Splits a `.netrc` into tokens, leaving out `macdef` bodies (which run up to the next blank line).
+fn netrc_tokens(text: &str) -> Vec<String> {
+    let mut tokens = vec![];
+    let mut in_macdef = false;
+
+    for line in text.lines() {
+        if in_macdef {
+            in_macdef = !line.trim().is_empty();
+            continue;
+        }
+
+        let mut words = line.split_whitespace();
+        while let Some(word) = words.next() {
+            if word.starts_with('#') {
+                break;
+            }
+
+            if word == "macdef" {
+                words.next();
+                in_macdef = true;
+                break;
+            }
+
+            tokens.push(word.to_string());
+        }
+    }
+
+    tokens
+}
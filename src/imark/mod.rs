@@ -1,20 +1,28 @@
-use std::{collections::{HashMap, BTreeMap}, sync::Arc, cmp::Ordering, io::{Write, Read, Seek}, mem};
+pub mod cache;
+pub mod client;
+pub mod credentials;
+pub mod ordering;
+pub mod shard;
+pub mod submission;
 
-use anyhow::Result;
+use std::{collections::{HashMap, HashSet, BTreeMap}, sync::Arc, cmp::Ordering, io::{Write, Read, Seek}, mem, os::unix::fs::FileExt, fmt};
+
+use anyhow::{Result, bail, Context};
 use async_trait::async_trait;
 use memfile::{MemFile, CreateOptions, Seal};
-use reqwest::Method;
+use reqwest::StatusCode;
 use serde::{Deserialize, Serialize};
 use tokio::sync::{Mutex, oneshot, MutexGuard};
 
-use crate::{choice::{Choices, ChoiceSelections, Choice}, app::{journals::AppJournalList, marking::AppMarking}, util::task::{TaskRunner, Task}};
+use crate::{config::KeyBindings, choice::{self, Choices, ChoiceSelections, Choice, MarkOverride}, app::{journals::AppJournalList, marking::AppMarking}, marklog::{MarkLog, MarkBase, PendingMark}, util::task::{TaskRunner, Task}};
+
+use self::{submission::{Submission, Resolution}, client::{ImarkClient, MarkJson, SubmissionJson, SubmissionMarks}, cache::SubmissionCache, ordering::SortOrder, shard::Shard};
 
 #[derive(Clone, Default)]
 pub struct Globals {
     inner: Arc<GlobalsInner>,
 }
 
-#[derive(Default)]
 struct GlobalsInner {
     cgi_endpoint:  String,
     pager_command: String,
@@ -23,10 +31,63 @@ struct GlobalsInner {
     preload:       usize,
     panic_on_drop: bool,
     hide_names:    bool,
+    min_mark:      f64,
+    max_mark:      Option<f64>,
+    mark_log:      Option<MarkLog>,
+    replay:        Vec<PendingMark>,
+    imark:         Arc<dyn ImarkClient>,
+    cache:         Option<SubmissionCache>,
+    keys:          KeyBindings,
+    sort_order:    SortOrder,
+    shard:         Shard,
+    show_others:   bool,
+}
+
+impl Default for GlobalsInner {
+    fn default() -> Self {
+        Self {
+            cgi_endpoint:  String::new(),
+            pager_command: String::new(),
+            mark_name:     String::new(),
+            choices:       Choices::default(),
+            preload:       0,
+            panic_on_drop: false,
+            hide_names:    false,
+            min_mark:      0.0,
+            max_mark:      None,
+            mark_log:      None,
+            replay:        Vec::new(),
+            imark:         Arc::new(client::NoImark),
+            cache:         None,
+            keys:          KeyBindings::default(),
+            sort_order:    SortOrder::default(),
+            shard:         Shard::default(),
+            show_others:   false,
+        }
+    }
 }
 
 impl Globals {
-    pub fn new(cgi_endpoint: String, pager_command: String, mark_name: String, choices: Choices, preload: usize, panic_on_drop: bool, hide_names: bool) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        cgi_endpoint: String,
+        pager_command: String,
+        mark_name: String,
+        choices: Choices,
+        preload: usize,
+        panic_on_drop: bool,
+        hide_names: bool,
+        min_mark: f64,
+        max_mark: Option<f64>,
+        mark_log: Option<MarkLog>,
+        replay: Vec<PendingMark>,
+        imark: Arc<dyn ImarkClient>,
+        cache: Option<SubmissionCache>,
+        keys: KeyBindings,
+        sort_order: SortOrder,
+        shard: Shard,
+        show_others: bool,
+    ) -> Self {
         Self {
             inner: Arc::new(GlobalsInner {
                 cgi_endpoint,
@@ -36,6 +97,30 @@ impl Globals {
                 preload,
                 panic_on_drop,
                 hide_names,
+                min_mark,
+                max_mark,
+                mark_log,
+                replay,
+                imark,
+                cache,
+                keys,
+                sort_order,
+                shard,
+                show_others,
+            }),
+        }
+    }
+
+    /// Just enough to fetch and load journals, for commands that don't open the marking UI.
+    pub fn headless(cgi_endpoint: String, mark_name: String, hide_names: bool, mark_log: Option<MarkLog>, imark: Arc<dyn ImarkClient>) -> Self {
+        Self {
+            inner: Arc::new(GlobalsInner {
+                cgi_endpoint,
+                mark_name,
+                hide_names,
+                mark_log,
+                imark,
+                ..GlobalsInner::default()
             }),
         }
     }
@@ -67,6 +152,48 @@ impl Globals {
     pub fn hide_names(&self) -> bool {
         self.inner.hide_names
     }
+
+    pub fn min_mark(&self) -> f64 {
+        self.inner.min_mark
+    }
+
+    pub fn max_mark(&self) -> Option<f64> {
+        self.inner.max_mark
+    }
+
+    pub fn mark_log(&self) -> Option<&MarkLog> {
+        self.inner.mark_log.as_ref()
+    }
+
+    /// Marks left uncommitted by a previous session that should be resent once authenticated.
+    pub fn replay(&self) -> &[PendingMark] {
+        &self.inner.replay
+    }
+
+    pub fn imark(&self) -> &Arc<dyn ImarkClient> {
+        &self.inner.imark
+    }
+
+    pub fn cache(&self) -> Option<&SubmissionCache> {
+        self.inner.cache.as_ref()
+    }
+
+    pub fn keys(&self) -> &KeyBindings {
+        &self.inner.keys
+    }
+
+    pub fn sort_order(&self) -> SortOrder {
+        self.inner.sort_order
+    }
+
+    pub fn shard(&self) -> &Shard {
+        &self.inner.shard
+    }
+
+    /// Whether the journal list shows other markers' shares too (read-only).
+    pub fn show_others(&self) -> bool {
+        self.inner.show_others
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -104,12 +231,17 @@ pub struct Journals {
     database: HashMap<JournalTag, Arc<Mutex<Journal>>>,
     ordering: Vec<(JournalTag, JournalMeta)>,
     queue: Vec<Task<()>>,
+    submissions: Vec<Submission>,
+    sort_order: SortOrder,
+    /// Journals in other markers' shares, which are skipped when marking.
+    others: HashSet<JournalTag>,
     globals: Globals,
 }
 
 pub struct JournalsIter<'a> {
     journals: &'a Journals,
     index: usize,
+    include_others: bool,
 }
 
 pub trait BidirectionalIterator: Iterator {
@@ -120,33 +252,37 @@ impl<'a> Iterator for JournalsIter<'a> {
     type Item = (&'a JournalTag, Arc<Mutex<Journal>>);
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.index >= self.journals.database.len() {
-            return None;
-        }
+        while let Some((tag, _)) = self.journals.ordering.get(self.index) {
+            self.index += 1;
 
-        let (tag, _) = self.journals.ordering.get(self.index)?;
-        let journal = self.journals.database.get(tag)
-            .expect("ordering is out of sync with database");
+            if self.include_others || self.journals.is_mine(tag) {
+                let journal = self.journals.database.get(tag)
+                    .expect("ordering is out of sync with database");
 
-        self.index += 1;
+                return Some((tag, journal.clone()));
+            }
+        }
 
-        Some((tag, journal.clone()))
+        None
     }
 }
 
 impl<'a> BidirectionalIterator for JournalsIter<'a> {
     fn next_back(&mut self) -> Option<Self::Item> {
-        if self.index == 0 {
-            return None;
-        }
+        while self.index > 0 {
+            self.index -= 1;
 
-        self.index -= 1;
+            let (tag, _) = self.journals.ordering.get(self.index)?;
 
-        let (tag, _) = self.journals.ordering.get(self.index)?;
-        let journal = self.journals.database.get(tag)
-            .expect("ordering is out of sync with database");
+            if self.include_others || self.journals.is_mine(tag) {
+                let journal = self.journals.database.get(tag)
+                    .expect("ordering is out of sync with database");
+
+                return Some((tag, journal.clone()));
+            }
+        }
 
-        Some((tag, journal.clone()))
+        None
     }
 }
 
@@ -156,27 +292,71 @@ impl Journals {
             database: HashMap::new(),
             ordering: Vec::new(),
             queue: Vec::new(),
+            submissions: Vec::new(),
+            sort_order: globals.sort_order(),
+            others: HashSet::new(),
             globals,
         }
     }
 
+    pub fn globals(&self) -> &Globals {
+        &self.globals
+    }
+
+    /// The journals in this marker's share, in order.
     pub fn iter(&self) -> impl BidirectionalIterator<Item = (&'_ JournalTag, Arc<Mutex<Journal>>)> {
         JournalsIter {
             journals: self,
             index: 0,
+            include_others: false,
         }
     }
 
+    /// Every journal, including those in other markers' shares.
+    pub fn iter_all(&self) -> impl BidirectionalIterator<Item = (&'_ JournalTag, Arc<Mutex<Journal>>)> {
+        JournalsIter {
+            journals: self,
+            index: 0,
+            include_others: true,
+        }
+    }
+
+    /// Works out which journals are `marker`'s to mark, going by the globals' shard.
+    pub fn assign_shares(&mut self, marker: &str) {
+        let shard = self.globals.shard();
+
+        self.others = self.database.keys()
+            .filter(|tag| !shard.is_mine(tag, marker))
+            .cloned()
+            .collect();
+    }
+
+    pub fn is_mine(&self, tag: &JournalTag) -> bool {
+        !self.others.contains(tag)
+    }
+
     pub fn insert(&mut self, tag: JournalTag, meta: JournalMeta) {
         self.database.insert(tag.clone(), Arc::new(Mutex::new(Journal::Unloaded(UnloadedJournal::new(meta.clone())))));
         
-        match self.ordering.binary_search_by_key(&&meta, |(_, meta)| &meta) {
+        let sort_order = self.sort_order;
+
+        match self.ordering.binary_search_by(|(other_tag, other_meta)| sort_order.compare((other_tag, other_meta), (&tag, &meta))) {
             Ok(index) | Err(index) => {
                 self.ordering.insert(index, (tag, meta));
             }
         }
     }
 
+    pub fn sort_order(&self) -> SortOrder {
+        self.sort_order
+    }
+
+    /// Re-sorts the journals, which changes both how they're listed and the order they're marked in.
+    pub fn set_sort_order(&mut self, sort_order: SortOrder) {
+        self.sort_order = sort_order;
+        self.ordering.sort_by(|(a_tag, a_meta), (b_tag, b_meta)| sort_order.compare((a_tag, a_meta), (b_tag, b_meta)));
+    }
+
     pub fn try_get<'s>(&'s self, tag: &JournalTag) -> Option<MutexGuard<'s, Journal>> {
         match self.database.get(tag) {
             Some(journal) => journal.try_lock().ok(),
@@ -205,20 +385,21 @@ impl Journals {
     pub fn queue_load(
         &mut self,
         tag: JournalTag,
-        cgi_endpoint: &str,
         auth: Authentication,
         mark_name: &str,
     ) -> Result<()> {
         let journal = self.database.get(&tag)
             .ok_or_else(|| anyhow::anyhow!("Tried to load non-existent journal: {tag:?}"))?;
 
+        #[allow(clippy::redundant_field_names)]
         let task = Task::new(
             LoadJournalTask {
-                tag:          tag,
-                journal:      journal.clone(),
-                cgi_endpoint: cgi_endpoint.to_string(),
-                auth:         auth,
-                mark_name:    mark_name.to_string(),
+                tag:       tag,
+                journal:   journal.clone(),
+                imark:     self.globals.imark().clone(),
+                cache:     self.globals.cache().cloned(),
+                auth:      auth,
+                mark_name: mark_name.to_string(),
             },
             self.globals.panic_on_drop(),
         );
@@ -228,10 +409,57 @@ impl Journals {
         Ok(())
     }
 
+    /// Loads a journal straight away, rather than queueing it.
+    pub async fn load(&self, tag: &JournalTag, auth: Authentication) -> Result<()> {
+        let journal = self.database.get(tag)
+            .ok_or_else(|| anyhow::anyhow!("Tried to load non-existent journal: {tag:?}"))?;
+
+        LoadJournalTask {
+            tag:       tag.clone(),
+            journal:   journal.clone(),
+            imark:     self.globals.imark().clone(),
+            cache:     self.globals.cache().cloned(),
+            auth,
+            mark_name: self.globals.mark_name().to_string(),
+        }
+        .run()
+        .await
+    }
+
+    /// Prepares a mark straight away, rather than queueing it. The journal must be loaded.
+    ///
+    /// The mark is logged before it's checked against imark, so if this fails
+    /// after logging it the mark is replayed next time flymark marks.
+    pub async fn prepare_mark(&self, tag: &JournalTag, submission: MarkSubmission, auth: Authentication) -> Result<Prepared> {
+        let pending = self.log_mark(tag, submission, auth.clone()).await?;
+
+        check_mark(self.globals.imark().as_ref(), pending, &auth).await
+    }
+
+    /// Builds the mark for a loaded journal and logs it as pending, without
+    /// checking it against imark -- the first half of [`Journals::prepare_mark`].
+    pub async fn log_mark(&self, tag: &JournalTag, submission: MarkSubmission, auth: Authentication) -> Result<PendingMark> {
+        let journal = self.database.get(tag)
+            .ok_or_else(|| anyhow::anyhow!("Tried to mark non-existent journal: {tag:?}"))?;
+
+        MarkJournalTask {
+            submission,
+            journal_tag:  tag.clone(),
+            journal:      journal.clone(),
+            cgi_endpoint: self.globals.cgi_endpoint().to_string(),
+            auth:         auth.clone(),
+            mark_name:    self.globals.mark_name().to_string(),
+            mark_log:     self.globals.mark_log().cloned(),
+            imark:        self.globals.imark().clone(),
+        }
+        .run()
+        .await
+    }
+
     pub fn queue_mark(
         &mut self,
-        tag:     JournalTag,
-        choices: ChoiceSelections,
+        tag:        JournalTag,
+        submission: MarkSubmission,
         cgi_endpoint: &str,
         auth: Authentication,
         mark_name: &str,
@@ -241,17 +469,21 @@ impl Journals {
 
         let task = Task::new(
             MarkJournalTask {
-                choices:      choices,
-                journal_tag:  tag,
+                submission,
+                journal_tag:  tag.clone(),
                 journal:      journal.clone(),
                 cgi_endpoint: cgi_endpoint.to_string(),
-                auth:         auth,
+                auth:         auth.clone(),
                 mark_name:    mark_name.to_string(),
+                mark_log:     self.globals.mark_log().cloned(),
+                imark:        self.globals.imark().clone(),
             },
             self.globals.panic_on_drop(),
         );
 
-        self.queue.push(task);
+        self.submissions.push(
+            Submission::new(tag, auth, self.globals.imark().clone(), self.globals.mark_log().cloned(), task, self.globals.panic_on_drop())
+        );
 
         Ok(())
     }
@@ -260,6 +492,7 @@ impl Journals {
         let mut happy_to_drop = vec![];
 
         for (index, task) in self.queue.iter_mut().enumerate() {
+            #[allow(clippy::redundant_pattern_matching)]
             if let Some(_) = task.poll()? {
                 happy_to_drop.push(index);
             }
@@ -272,11 +505,72 @@ impl Journals {
             self.queue.remove(index);
         }
 
-        Ok(self.queue.len())
+        for submission in &mut self.submissions {
+            submission.poll()?;
+        }
+
+        self.submissions.retain(|submission| !submission.is_done());
+
+        Ok(self.queue_size())
     }
 
+    /// Loads and mark submissions still in progress -- failed and conflicted
+    /// submissions are waiting on the user, so they aren't counted.
     pub fn queue_size(&self) -> usize {
-        self.queue.len()
+        self.queue.len() + self.submissions.iter().filter(|submission| submission.is_in_flight()).count()
+    }
+
+    pub fn n_retrying(&self) -> usize {
+        self.submissions.iter().filter(|submission| submission.is_retrying()).count()
+    }
+
+    pub fn conflicts(&self) -> impl Iterator<Item = &Submission> {
+        self.submissions.iter().filter(|submission| submission.is_conflicted())
+    }
+
+    /// Resolves the oldest outstanding conflict.
+    pub fn resolve_conflict(&mut self, resolution: Resolution) -> Result<()> {
+        if let Some(submission) = self.submissions.iter_mut().find(|submission| submission.is_conflicted()) {
+            submission.resolve(resolution)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn failed_submissions(&self) -> impl Iterator<Item = &Submission> {
+        self.submissions.iter().filter(|submission| submission.is_failed())
+    }
+
+    pub fn retry_failed(&mut self, index: usize) {
+        if let Some(submission) = self.submissions.iter_mut().filter(|submission| submission.is_failed()).nth(index) {
+            submission.retry();
+        }
+    }
+
+    pub fn discard_failed(&mut self, index: usize) -> Result<()> {
+        if let Some(submission) = self.submissions.iter_mut().filter(|submission| submission.is_failed()).nth(index) {
+            submission.discard()?;
+        }
+
+        Ok(())
+    }
+
+    /// The error holding up the latest submission for a journal, if it's retrying or has failed.
+    pub fn submission_error(&self, tag: &JournalTag) -> Option<&str> {
+        self.submissions.iter()
+            .rev()
+            .find(|submission| submission.tag() == tag)
+            .and_then(|submission| submission.error())
+    }
+
+    /// Carries failed submissions over from an older copy of the journals
+    /// (e.g. when the journal list is re-fetched), so they aren't forgotten.
+    pub fn adopt_failed_submissions(&mut self, other: &mut Journals) {
+        let (failed, rest) = mem::take(&mut other.submissions).into_iter()
+            .partition(|submission| submission.is_failed());
+
+        other.submissions = rest;
+        self.submissions.extend::<Vec<_>>(failed);
     }
 }
 
@@ -306,7 +600,8 @@ impl<B> From<&AppMarking<B>> for JournalLoadApp {
 struct LoadJournalTask {
     tag: JournalTag,
     journal: Arc<Mutex<Journal>>,
-    cgi_endpoint: String,
+    imark: Arc<dyn ImarkClient>,
+    cache: Option<SubmissionCache>,
     auth: Authentication,
     mark_name: String,
 }
@@ -318,46 +613,30 @@ impl TaskRunner<()> for LoadJournalTask {
         if journal.is_loaded() {
             return Ok(());
         }
+        let cache_key = SubmissionCache::key(journal.meta());
         drop(journal);
 
-        #[derive(Deserialize)]
-        pub struct SubmissionJson {
-            files: BTreeMap<String, FileJson>,
-            marks: BTreeMap<String, MarkJson>,
-            metadata: MetadataJson,
-        }
-        
-        #[derive(Deserialize)]
-        pub struct FileJson {
-            name: String,
-            contents: String,
-        }
-        
-        #[derive(Deserialize)]
-        pub struct MarkJson {
-            name: String,
-            text: Option<String>,
-        }
+        let cached = self.cache.as_ref()
+            .and_then(|cache| cache.get(&self.tag, &cache_key));
 
-        #[derive(Deserialize)]
-        pub struct MetadataJson {
-            mark: Option<f64>,
-        }
+        let resp = match cached {
+            // only the files are cached -- the marks always come fresh from imark
+            Some(files) => {
+                let SubmissionMarks { marks, metadata } = self.imark.get_marks(&self.auth, &self.tag).await?;
 
-        let assignment   = self.tag.assignment();
-        let group_id     = self.tag.group_id();
-        let student_id   = self.tag.student_id();
-        let cgi_endpoint = self.cgi_endpoint;
+                SubmissionJson { files, marks, metadata }
+            }
+            None => {
+                let resp = self.imark.get_submission(&self.auth, &self.tag).await?;
 
-        let full_endpoint = format!("{cgi_endpoint}/api/v1/assignments/{assignment}/submissions/{group_id}/{student_id}/");
+                // the cache is only an optimisation, so failing to fill it isn't fatal
+                if let Some(cache) = &self.cache {
+                    let _ = cache.put(&self.tag, &cache_key, &resp.files);
+                }
 
-        let client = reqwest::Client::new();
-        let resp: SubmissionJson = client.request(Method::GET, full_endpoint)
-            .basic_auth(self.auth.username(), Some(self.auth.password()))
-            .send()
-            .await?
-            .json()
-            .await?;
+                resp
+            }
+        };
 
         let mut submission_files = vec![];
         let mut marking_files    = vec![];
@@ -381,9 +660,13 @@ impl TaskRunner<()> for LoadJournalTask {
 
             let imark_id_usize = str::parse::<usize>(&imark_id)?;
 
-            marking_files.push(JournalFile::new(imark_id_usize, file.name, mem_file));
+            marking_files.push(
+                JournalFile::new(imark_id_usize, file.name, mem_file)
+                    .with_stamp(MarkStamp { at: file.at, by: file.by })
+            );
         }
 
+        #[allow(clippy::op_ref)]
         if !marking_files.iter().any(|file| file.file_name() == &self.mark_name) {
             let mut mem_file = MemFile::create("memfile", CreateOptions::new().allow_sealing(true))?;
             mem_file.add_seals(Seal::Write | Seal::Shrink | Seal::Grow)?;
@@ -393,7 +676,7 @@ impl TaskRunner<()> for LoadJournalTask {
                 .max()
                 .unwrap_or(0) + 1;
 
-            marking_files.push(JournalFile::new(imark_id_usize, self.mark_name.to_string(), mem_file));
+            marking_files.push(JournalFile::placeholder(imark_id_usize, self.mark_name.to_string(), mem_file));
         }
 
         let journal_data = JournalData::new(submission_files, marking_files);
@@ -410,54 +693,116 @@ impl TaskRunner<()> for LoadJournalTask {
     }
 }
 
+pub struct MarkSubmission {
+    pub choices:       ChoiceSelections,
+    pub comment:       String,
+    pub mark_override: Option<MarkOverride>,
+}
+
+impl MarkSubmission {
+    pub fn mark(&self) -> f64 {
+        match &self.mark_override {
+            Some(mark_override) => mark_override.mark,
+            None => self.choices.mark(),
+        }
+    }
+
+    pub fn feedback_text(&self) -> String {
+        choice::feedback_text(&self.choices, self.mark_override.as_ref(), &self.comment)
+    }
+}
+
+/// Builds the mark and logs it -- checking it against imark is left to [`check_mark`],
+/// so the mark is never lost to imark being unreachable.
 struct MarkJournalTask {
-    choices:      ChoiceSelections,
+    submission:   MarkSubmission,
     journal_tag:  JournalTag,
     journal:      Arc<Mutex<Journal>>,
     cgi_endpoint: String,
     auth:         Authentication,
     mark_name:    String,
+    mark_log:     Option<MarkLog>,
+    imark:        Arc<dyn ImarkClient>,
 }
 
-#[async_trait]
-impl TaskRunner<()> for MarkJournalTask {
-    async fn run(self) -> Result<()> {
-        let mut mark = 0.0;
-        let mut comments = vec![];
-    
-        for choice in self.choices.selections().iter()
-            .filter(|selection| selection.selected())
-            .map(|selection| selection.choice())
-        {
-            match choice {
-                Choice::Plus(n, comment) => {
-                    mark += *n;
-                    comments.push(format!("+{n} {comment}"));
-                }
-                Choice::Minus(n, comment) => {
-                    mark -= *n;
-                    comments.push(format!("-{n} {comment}"));
-    
-                }
-                Choice::Set(n, comment) => {
-                    mark = *n;
-                    comments.push(format!("{n} {comment}"));
-                }
-                Choice::Comment(_)  => unreachable!(),
-            }
-        }
+/// A mark ready to send, or one that would clobber changes another marker
+/// made since the journal was loaded.
+pub enum Prepared {
+    Ready(PendingMark),
+    Conflict(Conflict),
+}
 
-        if mark < 0.0 {
-            mark = 0.0;
+impl Prepared {
+    /// Checks a logged mark against the marking file as it is on imark now.
+    pub fn new(pending: PendingMark, theirs: Option<RemoteMark>) -> Self {
+        let theirs = match theirs {
+            Some(theirs) => theirs,
+            None => return Self::Ready(pending),
+        };
+
+        let unchanged = match &pending.base {
+            Some(base) => theirs.stamp == base.stamp && theirs.text == base.text,
+            // logged by an older flymark, so there's no telling what it started from
+            None => false,
+        };
+
+        // the mark already landed, e.g. a send whose response never arrived
+        if unchanged || theirs.text == pending.text {
+            return Self::Ready(pending);
         }
 
-        let mark = (mark * 100.0).round() / 100.0;
-    
-        let (imark_id, journal_mark_name, mut journal_mark_text) = {
+        Self::Conflict(Conflict { ours: pending.added_text().to_string(), pending, theirs })
+    }
+}
+
+pub struct Conflict {
+    /// The mark as it would be sent over the top of their changes.
+    pub pending: PendingMark,
+    /// The text this submission adds to the marking file.
+    pub ours: String,
+    pub theirs: RemoteMark,
+}
+
+impl Conflict {
+    /// Our submission as-is, replacing their changes.
+    pub fn overwriting(&self) -> PendingMark {
+        let mut pending = self.pending.clone();
+        pending.base = Some(self.theirs.base());
+        pending
+    }
+
+    /// Our submission, with their text kept in place of what was loaded.
+    pub fn merged(&self) -> PendingMark {
+        let mut pending = self.overwriting();
+        pending.text = self.theirs.text.to_string() + &self.ours;
+        pending
+    }
+}
+
+/// A marking file as it currently is on imark.
+pub struct RemoteMark {
+    pub stamp: MarkStamp,
+    pub text: String,
+    pub mark: Option<f64>,
+}
+
+impl RemoteMark {
+    fn base(&self) -> MarkBase {
+        MarkBase { stamp: self.stamp.clone(), text: self.text.to_string() }
+    }
+}
+
+#[async_trait]
+impl TaskRunner<PendingMark> for MarkJournalTask {
+    async fn run(self) -> Result<PendingMark> {
+        let mark = self.submission.mark();
+
+        let (imark_id, journal_mark_name, journal_mark_text, loaded_stamp) = {
             let mut lock = self.journal.lock().await;
 
             let mut data = lock.data_mut().expect("journal must be loaded to mark");
 
+            #[allow(clippy::op_ref)]
             let mut marking_file = data.marking_files.iter_mut()
                 .find(|file| file.file_name() == &self.mark_name)
                 .unwrap_or_else(|| panic!("{} mark always exists", self.mark_name));
@@ -466,72 +811,160 @@ impl TaskRunner<()> for MarkJournalTask {
             marking_file.file_data.seek(std::io::SeekFrom::Start(0))?;
             marking_file.file_data.read_to_string(&mut text)?;
 
-            (marking_file.imark_id(), marking_file.file_name().to_string(), text)
+            (marking_file.imark_id(), marking_file.file_name().to_string(), text, marking_file.stamp().cloned().unwrap_or_default())
         };
 
-        #[derive(Serialize)]
-        struct MarkPut {
-            marks: BTreeMap<String, Mark>,
-            comments: BTreeMap<String, ()>,
-        }
-
-        #[derive(Serialize)]
-        struct Mark {
-            at: String,
-            by: String,
-            name: String,
-            is_final: bool,
-            mark: f64,
-            text: String,
-        }
-    
-        let mut body = MarkPut {
-            marks: BTreeMap::new(),
-            comments: BTreeMap::new(),
-        };
-    
         let at = chrono::Local::now().format("%F %T%.6f").to_string();
         let by = self.auth.username().to_string();
-    
-        journal_mark_text += &format!("\nmarked with flymark by {by} at {at}\n\n");
-
-        for comment in comments {
-            journal_mark_text += &comment;
-            journal_mark_text += "\n";
-        }
-    
-        body.marks.insert(
-            format!("{imark_id}"),
-            Mark {
-                at,
-                by,
-                is_final: true,
-                mark,
-                name: journal_mark_name,
-                text: journal_mark_text,
-            }
-        );
-    
-        let imark  = self.cgi_endpoint;
-        let assign = &self.journal_tag.assignment;
-        let group  = &self.journal_tag.group_id;
-        let stuid  = &self.journal_tag.student_id;
-        
-        let endpoint = format!("{imark}/api/v1/assignments/{assign}/submissions/{group}/{stuid}/");
-
-        reqwest::Client::new()
-            .put(endpoint)
-            .basic_auth(self.auth.username(), Some(self.auth.password()))
-            .json(&body)
-            .send()
-            .await?
-            .text()
-            .await?;
-        
-        Ok(())
+
+        let ours = format!("\nmarked with flymark by {by} at {at}\n\n") + &self.submission.feedback_text();
+
+        let pending = PendingMark {
+            id:           format!("{}-{}", std::process::id(), chrono::Utc::now().timestamp_nanos()),
+            cgi_endpoint: self.cgi_endpoint,
+            tag:          self.journal_tag,
+            mark_lines:   self.submission.choices.mark_lines(),
+            mark,
+            imark_id,
+            mark_name:    journal_mark_name,
+            text:         journal_mark_text.to_string() + &ours,
+            at,
+            by,
+            base:         Some(MarkBase { stamp: loaded_stamp, text: journal_mark_text }),
+        };
+
+        if let Some(mark_log) = &self.mark_log {
+            mark_log.record_pending(&pending)?;
+        }
+
+        Ok(pending)
+    }
+}
+
+/// Checks whether someone else has changed the mark since it was loaded.
+pub async fn check_mark(imark: &dyn ImarkClient, pending: PendingMark, auth: &Authentication) -> Result<Prepared> {
+    let theirs = fetch_mark(imark, &pending.tag, pending.imark_id, auth).await
+        .context("couldn't check whether someone else has changed the mark")?;
+
+    Ok(Prepared::new(pending, theirs))
+}
+
+async fn fetch_mark(imark: &dyn ImarkClient, tag: &JournalTag, imark_id: usize, auth: &Authentication) -> Result<Option<RemoteMark>> {
+    let SubmissionMarks { mut marks, .. } = imark.get_marks(auth, tag).await?;
+
+    Ok(
+        marks.remove(&imark_id.to_string())
+            .map(|mark| RemoteMark {
+                stamp: MarkStamp { at: mark.at, by: mark.by },
+                text:  mark.text.unwrap_or_default(),
+                mark:  mark.mark,
+            })
+    )
+}
+
+pub async fn send_mark(imark: &dyn ImarkClient, pending: &PendingMark, auth: &Authentication) -> Result<()> {
+    let mut marks = BTreeMap::new();
+
+    marks.insert(
+        format!("{}", pending.imark_id),
+        MarkJson {
+            at:       Some(pending.at.to_string()),
+            by:       Some(pending.by.to_string()),
+            is_final: true,
+            mark:     Some(pending.mark),
+            name:     pending.mark_name.to_string(),
+            text:     Some(pending.text.to_string()),
+        }
+    );
+
+    imark.put_marks(auth, &pending.tag, marks).await?;
+
+    verify_mark(imark, pending, auth).await
+        .context("mark was sent, but couldn't be verified")
+}
+
+/// Re-fetches the submission's marks to check the mark actually landed.
+async fn verify_mark(imark: &dyn ImarkClient, pending: &PendingMark, auth: &Authentication) -> Result<()> {
+    let SubmissionMarks { marks, .. } = imark.get_marks(auth, &pending.tag).await?;
+
+    let mismatch = |message: String| Err(MarkMismatch { message }.into());
+
+    let mark = match marks.get(&pending.imark_id.to_string()) {
+        Some(mark) => mark,
+        None => return mismatch(format!("imark has no `{}` mark", pending.mark_name)),
+    };
+
+    if mark.text.as_deref() != Some(pending.text.as_str()) {
+        return mismatch(format!("imark's `{}` text doesn't match what was sent", pending.mark_name));
+    }
+
+    match mark.mark {
+        Some(actual) if (actual - pending.mark).abs() < 0.005 => Ok(()),
+        Some(actual) => mismatch(format!("imark has a mark of {actual}, expected {}", pending.mark)),
+        None => mismatch(format!("imark has no mark, expected {}", pending.mark)),
+    }
+}
+
+/// imark has something other than the mark that was just sent. That may well
+/// be another marker's changes, so sending the same text again could clobber them.
+#[derive(Debug)]
+pub struct MarkMismatch {
+    message: String,
+}
+
+impl fmt::Display for MarkMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for MarkMismatch {}
+
+/// imark refused a mark outright (e.g. bad credentials or permissions),
+/// so sending it again unchanged won't help.
+#[derive(Debug)]
+pub struct MarkRejected {
+    status: StatusCode,
+    body: String,
+}
+
+impl MarkRejected {
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    /// Whether the rejection might go away by itself, i.e. a server-side error.
+    pub fn is_transient(&self) -> bool {
+        self.status.is_server_error()
+    }
+}
+
+impl fmt::Display for MarkRejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.body.is_empty() {
+            write!(f, "imark rejected the mark ({})", self.status)
+        } else {
+            write!(f, "imark rejected the mark ({}): {}", self.status, self.body)
+        }
+    }
+}
+
+impl std::error::Error for MarkRejected {}
+
+/// imark didn't accept the zid and password.
+#[derive(Debug)]
+pub struct InvalidCredentials {
+    pub zid: String,
+}
+
+impl fmt::Display for InvalidCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid credentials for {}", self.zid)
     }
 }
 
+impl std::error::Error for InvalidCredentials {}
+
 #[derive(Debug)]
 pub enum Journal {
     Unloaded(UnloadedJournal),
@@ -610,7 +1043,7 @@ impl LoadedJournal {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Serialize, Deserialize)]
 pub struct JournalTag {
     assignment: String,
     group_id:   String,
@@ -695,6 +1128,11 @@ impl JournalData {
     pub fn marking_files(&self) -> &[JournalFile] {
         &self.marking_files
     }
+
+    pub fn marking_file(&self, mark_name: &str) -> Option<&JournalFile> {
+        self.marking_files.iter()
+            .find(|file| file.file_name() == mark_name)
+    }
 }
 
 #[derive(Debug)]
@@ -702,6 +1140,8 @@ pub struct JournalFile {
     imark_id: usize,
     file_name: String,
     file_data: MemFile,
+    stamp: Option<MarkStamp>,
+    on_imark: bool,
 }
 
 impl JournalFile {
@@ -710,13 +1150,38 @@ impl JournalFile {
             imark_id,
             file_name,
             file_data,
+            stamp: None,
+            on_imark: true,
+        }
+    }
+
+    /// An empty file standing in for a mark imark doesn't have yet.
+    pub fn placeholder(imark_id: usize, file_name: String, file_data: MemFile) -> Self {
+        Self {
+            on_imark: false,
+            ..Self::new(imark_id, file_name, file_data)
         }
     }
 
+    pub fn with_stamp(mut self, stamp: MarkStamp) -> Self {
+        self.stamp = Some(stamp);
+        self
+    }
+
+    /// Who last wrote a marking file, and when, as of loading it.
+    pub fn stamp(&self) -> Option<&MarkStamp> {
+        self.stamp.as_ref()
+    }
+
     pub fn file_name(&self) -> &str {
         &self.file_name
     }
 
+    /// Whether the file came from imark, rather than being a placeholder.
+    pub fn is_on_imark(&self) -> bool {
+        self.on_imark
+    }
+
     pub fn file_data(&self) -> &MemFile {
         &self.file_data
     }
@@ -724,6 +1189,21 @@ impl JournalFile {
     pub fn imark_id(&self) -> usize {
         self.imark_id
     }
+
+    pub fn read_text(&self) -> Result<String> {
+        let len = self.file_data.metadata()?.len() as usize;
+
+        let mut bytes = vec![0; len];
+        self.file_data.read_exact_at(&mut bytes, 0)?;
+
+        Ok(String::from_utf8(bytes)?)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MarkStamp {
+    pub at: Option<String>,
+    pub by: Option<String>,
 }
 
 impl PartialEq for JournalMeta {
@@ -744,6 +1224,7 @@ impl PartialEq for JournalMeta {
     }
 }
 
+#[allow(clippy::non_canonical_partial_ord_impl)]
 impl PartialOrd for JournalMeta {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         let mark_ordering = match (self.mark, other.mark) {
@@ -772,3 +1253,207 @@ impl Ord for JournalMeta {
         self.partial_cmp(other).expect("partial_cmp is infallible")
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::choice::parse_choices;
+    use self::client::{FakeImark, FileJson, SubmissionJson, SubmissionSummary};
+
+    pub const MARK_NAME: &str = "flymark";
+
+    pub fn tag() -> JournalTag {
+        JournalTag::new(String::from("ass1"), String::from("g1"), String::from("z5000000"))
+    }
+
+    pub fn auth() -> Authentication {
+        Authentication::new(String::from("z1111111"), String::from("hunter2"))
+    }
+
+    pub fn fake_imark() -> Arc<FakeImark> {
+        let mut files = BTreeMap::new();
+        files.insert(String::from("1"), FileJson { name: String::from("main.c"), contents: String::from("int main;") });
+
+        let fake = FakeImark::new()
+            .with_user("z1111111", "hunter2")
+            .with_submission(
+                tag(),
+                SubmissionSummary { name: String::from("Student"), ..Default::default() },
+                SubmissionJson { files, ..Default::default() },
+            );
+
+        Arc::new(fake)
+    }
+
+    /// Globals with nothing set but the range override marks must be in.
+    pub fn bounded_globals(min_mark: f64, max_mark: Option<f64>) -> Globals {
+        Globals {
+            inner: Arc::new(GlobalsInner {
+                min_mark,
+                max_mark,
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// Journals holding just the fake's one submission, already loaded.
+    pub async fn loaded_journals(imark: Arc<FakeImark>) -> Journals {
+        logged_journals(imark, None).await
+    }
+
+    /// As [`loaded_journals`], logging marks to `mark_log`.
+    pub async fn logged_journals(imark: Arc<FakeImark>, mark_log: Option<MarkLog>) -> Journals {
+        let globals = Globals::headless(String::from("http://imark.test"), String::from(MARK_NAME), false, mark_log, imark);
+
+        let mut journals = Journals::new(globals);
+        journals.insert(tag(), JournalMeta::new(String::from("Student"), None, None, None));
+        journals.load(&tag(), auth()).await.unwrap();
+
+        journals
+    }
+
+    /// A submission with just the scheme's first choice selected.
+    pub fn submission() -> MarkSubmission {
+        let choices = parse_choices("+2 Compiles\n+3 Tests pass", &KeyBindings::default()).unwrap();
+
+        let mut choices = ChoiceSelections::new(&choices);
+        choices.toggle_selection();
+
+        MarkSubmission { choices, comment: String::from("Nice work"), mark_override: None }
+    }
+
+    /// Has another marker write `text` to the marking file on imark.
+    pub async fn other_marker_writes(imark: &FakeImark, text: &str) {
+        let mut marks = BTreeMap::new();
+        marks.insert(String::from("1"), MarkJson {
+            name:     String::from(MARK_NAME),
+            at:       Some(String::from("2022-05-01 12:00:00")),
+            by:       Some(String::from("z2222222")),
+            is_final: true,
+            mark:     Some(1.0),
+            text:     Some(text.to_string()),
+        });
+
+        imark.put_marks(&auth(), &tag(), marks).await.unwrap();
+    }
+
+    pub fn queue_mark(journals: &mut Journals) {
+        let cgi_endpoint = journals.globals().cgi_endpoint().to_string();
+        journals.queue_mark(tag(), submission(), &cgi_endpoint, auth(), MARK_NAME).unwrap();
+    }
+
+    /// Polls until every submission is done or waiting on the user.
+    pub async fn settle(journals: &mut Journals) {
+        for _ in 0..1000 {
+            if journals.scan_queue().unwrap() == 0 {
+                return;
+            }
+
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        panic!("submissions never settled");
+    }
+
+    pub fn remote_text(imark: &FakeImark) -> String {
+        imark.submission(&tag()).unwrap().marks["1"].text.clone().unwrap_or_default()
+    }
+
+    #[tokio::test]
+    async fn prepares_a_mark_when_nothing_changed() {
+        let imark = fake_imark();
+        let journals = loaded_journals(imark.clone()).await;
+
+        let pending = match journals.prepare_mark(&tag(), submission(), auth()).await.unwrap() {
+            Prepared::Ready(pending) => pending,
+            Prepared::Conflict(_) => panic!("nobody else changed the mark"),
+        };
+
+        assert_eq!(pending.mark, 2.0);
+        assert_eq!(pending.mark_lines, vec![String::from("+2 Compiles")]);
+        assert!(pending.text.starts_with("\nmarked with flymark by z1111111 at "));
+        assert!(pending.text.ends_with("\n\n+2 Compiles\n\nNice work\n"));
+
+        send_mark(imark.as_ref(), &pending, &auth()).await.unwrap();
+
+        assert_eq!(remote_text(&imark), pending.text);
+        assert_eq!(imark.submission(&tag()).unwrap().metadata.mark, Some(2.0));
+    }
+
+    #[tokio::test]
+    async fn conflicts_when_another_marker_changed_the_mark() {
+        let imark = fake_imark();
+        let journals = loaded_journals(imark.clone()).await;
+
+        other_marker_writes(&imark, "their feedback\n").await;
+
+        let conflict = match journals.prepare_mark(&tag(), submission(), auth()).await.unwrap() {
+            Prepared::Ready(_) => panic!("the mark changed since it was loaded"),
+            Prepared::Conflict(conflict) => conflict,
+        };
+
+        assert_eq!(conflict.theirs.text, "their feedback\n");
+        assert_eq!(conflict.theirs.stamp.by.as_deref(), Some("z2222222"));
+
+        let merged = conflict.merged();
+        assert!(merged.text.starts_with("their feedback\n\nmarked with flymark by z1111111"));
+        assert!(merged.text.ends_with(&conflict.ours));
+    }
+
+    #[tokio::test]
+    async fn cached_journals_still_load_the_latest_marks() {
+        let imark = fake_imark();
+        let dir = tempfile::tempdir().unwrap();
+
+        let globals = Globals {
+            inner: Arc::new(GlobalsInner {
+                mark_name: String::from(MARK_NAME),
+                imark:     imark.clone(),
+                cache:     Some(SubmissionCache::open(dir.path().to_path_buf()).unwrap()),
+                ..GlobalsInner::default()
+            }),
+        };
+
+        let mut journals = Journals::new(globals);
+        journals.insert(tag(), JournalMeta::new(String::from("Student"), None, None, None));
+        journals.load(&tag(), auth()).await.unwrap();
+
+        // their edit leaves the listing (and so the cache key) as it was
+        other_marker_writes(&imark, "their feedback\n").await;
+
+        journals.unload(&tag()).await;
+        journals.load(&tag(), auth()).await.unwrap();
+
+        let journal = journals.get(&tag()).await.unwrap();
+        let data = journal.data().unwrap();
+
+        assert_eq!(data.submission_files()[0].read_text().unwrap(), "int main;");
+        assert_eq!(data.marking_file(MARK_NAME).unwrap().read_text().unwrap(), "their feedback\n");
+    }
+
+    #[tokio::test]
+    async fn only_marks_from_imark_count_as_on_imark() {
+        let imark = fake_imark();
+
+        let journals = loaded_journals(imark.clone()).await;
+        let journal = journals.get(&tag()).await.unwrap();
+        assert!(!journal.data().unwrap().marking_file(MARK_NAME).unwrap().is_on_imark());
+        drop(journal);
+
+        // a mark that's really there but empty still counts
+        other_marker_writes(&imark, "").await;
+
+        let journals = loaded_journals(imark.clone()).await;
+        let journal = journals.get(&tag()).await.unwrap();
+        assert!(journal.data().unwrap().marking_file(MARK_NAME).unwrap().is_on_imark());
+    }
+
+    #[tokio::test]
+    async fn wrong_credentials_are_rejected() {
+        let imark = fake_imark();
+        let wrong = Authentication::new(String::from("z1111111"), String::from("hunter3"));
+
+        let err = imark.list_assignments(&wrong).await.unwrap_err();
+        assert!(err.is::<InvalidCredentials>());
+    }
+}
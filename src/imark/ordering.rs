@@ -0,0 +1,182 @@
+use std::cmp::Ordering;
+
+use clap::ArgEnum;
+use serde::Deserialize;
+
+use crate::util::stable_hash;
+
+use super::{JournalMeta, JournalTag};
+
+/// What journals are sorted by, in the journal list and when marking one after another.
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SortKey {
+    /// Marked journals last, highest mark first, then by name.
+    #[default]
+    Marks,
+    Zid,
+    Name,
+    Group,
+    /// Lowest provisional mark first, with those that have none last.
+    Provisional,
+    /// A shuffle that's the same for the same seed.
+    Random,
+}
+
+const SORT_KEYS: [SortKey; 6] = [
+    SortKey::Marks,
+    SortKey::Zid,
+    SortKey::Name,
+    SortKey::Group,
+    SortKey::Provisional,
+    SortKey::Random,
+];
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SortOrder {
+    key:  SortKey,
+    seed: u64,
+}
+
+impl SortOrder {
+    pub fn new(key: SortKey, seed: u64) -> Self {
+        Self { key, seed }
+    }
+
+    pub fn key(&self) -> SortKey {
+        self.key
+    }
+
+    /// The same order, by the next key along (wrapping around).
+    pub fn next(self) -> Self {
+        self.step(1)
+    }
+
+    pub fn previous(self) -> Self {
+        self.step(SORT_KEYS.len() - 1)
+    }
+
+    fn step(self, by: usize) -> Self {
+        let index = SORT_KEYS.iter().position(|key| *key == self.key)
+            .expect("every key is in SORT_KEYS");
+
+        Self {
+            key: SORT_KEYS[(index + by) % SORT_KEYS.len()],
+            ..self
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match self.key {
+            SortKey::Marks       => String::from("marks"),
+            SortKey::Zid         => String::from("zid"),
+            SortKey::Name        => String::from("name"),
+            SortKey::Group       => String::from("group"),
+            SortKey::Provisional => String::from("provisional mark"),
+            SortKey::Random      => format!("random (seed {})", self.seed),
+        }
+    }
+
+    pub fn compare(&self, (a_tag, a_meta): (&JournalTag, &JournalMeta), (b_tag, b_meta): (&JournalTag, &JournalMeta)) -> Ordering {
+        let ordering = match self.key {
+            SortKey::Marks => a_meta.cmp(b_meta),
+            SortKey::Zid   => a_tag.student_id().cmp(b_tag.student_id()),
+            SortKey::Name  => a_meta.name().cmp(b_meta.name()),
+            SortKey::Group => a_tag.group_id().cmp(b_tag.group_id()),
+            SortKey::Provisional => match (a_meta.provisional_mark(), b_meta.provisional_mark()) {
+                (Some(a), Some(b)) => a.partial_cmp(&b).unwrap_or(Ordering::Equal),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None)    => Ordering::Equal,
+            },
+            SortKey::Random => self.shuffle_key(a_tag).cmp(&self.shuffle_key(b_tag)),
+        };
+
+        // ties are broken by the tag, so the order never depends on the order journals arrived in
+        ordering.then_with(|| a_tag.cmp(b_tag))
+    }
+
+    fn shuffle_key(&self, tag: &JournalTag) -> u64 {
+        stable_hash(self.seed, &[tag.assignment(), tag.group_id(), tag.student_id()])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn journal(group_id: &str, student_id: &str, name: &str, provisional_mark: Option<f64>, mark: Option<f64>) -> (JournalTag, JournalMeta) {
+        (
+            JournalTag::new(String::from("ass1"), group_id.to_string(), student_id.to_string()),
+            JournalMeta::new(name.to_string(), provisional_mark, mark, None),
+        )
+    }
+
+    fn journals() -> Vec<(JournalTag, JournalMeta)> {
+        vec![
+            journal("g2", "z5000003", "Carol", Some(8.0), None),
+            journal("g1", "z5000001", "Alice", None,      Some(7.0)),
+            journal("g3", "z5000002", "Bob",   Some(3.0), Some(9.0)),
+            journal("g1", "z5000004", "Dave",  None,      None),
+        ]
+    }
+
+    /// The student ids of `journals`, sorted by `key`.
+    fn sorted(key: SortKey, seed: u64, mut journals: Vec<(JournalTag, JournalMeta)>) -> Vec<String> {
+        let order = SortOrder::new(key, seed);
+        journals.sort_by(|(a_tag, a_meta), (b_tag, b_meta)| order.compare((a_tag, a_meta), (b_tag, b_meta)));
+
+        journals.iter()
+            .map(|(tag, _)| tag.student_id().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn each_key_sorts_by_what_it_says() {
+        assert_eq!(sorted(SortKey::Marks,       0, journals()), ["z5000004", "z5000003", "z5000002", "z5000001"]);
+        assert_eq!(sorted(SortKey::Zid,         0, journals()), ["z5000001", "z5000002", "z5000003", "z5000004"]);
+        assert_eq!(sorted(SortKey::Name,        0, journals()), ["z5000001", "z5000002", "z5000003", "z5000004"]);
+        assert_eq!(sorted(SortKey::Group,       0, journals()), ["z5000001", "z5000004", "z5000003", "z5000002"]);
+        assert_eq!(sorted(SortKey::Provisional, 0, journals()), ["z5000002", "z5000003", "z5000001", "z5000004"]);
+    }
+
+    #[test]
+    fn random_is_the_same_shuffle_for_the_same_seed() {
+        let mut reversed = journals();
+        reversed.reverse();
+
+        assert_eq!(sorted(SortKey::Random, 42, journals()), sorted(SortKey::Random, 42, reversed));
+
+        let shuffles = (0..8)
+            .map(|seed| sorted(SortKey::Random, seed, journals()))
+            .collect::<Vec<_>>();
+        assert!(shuffles.iter().any(|shuffle| *shuffle != shuffles[0]), "every seed gave the same order");
+    }
+
+    #[test]
+    fn ties_are_broken_by_the_tag() {
+        let twins = vec![
+            journal("g2", "z5000002", "Sam", None, None),
+            journal("g1", "z5000009", "Sam", None, None),
+            journal("g1", "z5000001", "Sam", None, None),
+        ];
+
+        let mut reversed = twins.clone();
+        reversed.reverse();
+
+        // everything but zid and the shuffle sees these as equal
+        for key in SORT_KEYS.into_iter().filter(|key| !matches!(key, SortKey::Zid | SortKey::Random)) {
+            assert_eq!(sorted(key, 0, twins.clone()), ["z5000001", "z5000009", "z5000002"], "sorting by {key:?}");
+            assert_eq!(sorted(key, 0, reversed.clone()), sorted(key, 0, twins.clone()), "sorting by {key:?}");
+        }
+    }
+
+    #[test]
+    fn next_and_previous_wrap_around() {
+        let order = SortOrder::new(SortKey::Random, 7);
+
+        assert_eq!(order.next().key(), SortKey::Marks);
+        assert_eq!(order.next().previous().key(), SortKey::Random);
+        assert_eq!(SortOrder::default().previous().key(), SortKey::Random);
+    }
+}
@@ -0,0 +1,157 @@
+use std::{collections::HashMap, path::Path};
+
+use anyhow::{Result, Context, bail};
+
+use crate::util::stable_hash;
+
+use super::JournalTag;
+
+/// Which journals are this marker's, when a cohort is split between several markers.
+#[derive(Debug, Clone, Default)]
+pub enum Shard {
+    #[default]
+    All,
+    /// The `index`th (counting from 1) of `count` roughly even shares,
+    /// decided by a hash of each journal's assignment and group, so a
+    /// group's members all go to the same marker.
+    Hashed { index: u64, count: u64 },
+    /// Each student's marker, from an allocation file (student zid -> marker zid).
+    Allocated(HashMap<String, String>),
+}
+
+impl Shard {
+    /// Parses e.g. `2/5`, the second of five shares.
+    pub fn hashed(shard: &str) -> Result<Self> {
+        let (index, count) = shard.split_once('/')
+            .and_then(|(index, count)| Some((index.trim().parse::<u64>().ok()?, count.trim().parse::<u64>().ok()?)))
+            .with_context(|| format!("A shard looks like `2/5` (the second of five), not `{shard}`"))?;
+
+        if count == 0 || index == 0 || index > count {
+            bail!("Shard `{shard}` doesn't exist -- it should be from 1/{count} to {count}/{count}", count = count.max(1));
+        }
+
+        Ok(Shard::Hashed { index, count })
+    }
+
+    /// Reads a CSV with `marker` and `zid` columns, one row per student.
+    pub fn allocated(path: &Path) -> Result<Self> {
+        let mut reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_path(path)
+            .with_context(|| format!("Failed to open allocation file: {}", path.display()))?;
+
+        let headers = reader.headers()
+            .with_context(|| format!("Failed to read allocation file: {}", path.display()))?
+            .clone();
+
+        let column = |name: &str| {
+            headers.iter().position(|header| header.eq_ignore_ascii_case(name))
+                .with_context(|| format!("Allocation file {} has no `{name}` column", path.display()))
+        };
+
+        let marker_column = column("marker")?;
+        let zid_column    = column("zid")?;
+
+        let mut allocation = HashMap::new();
+
+        for (line, record) in reader.records().enumerate() {
+            let record = record
+                .with_context(|| format!("Failed to read allocation file: {}", path.display()))?;
+
+            let (marker, student) = match (record.get(marker_column), record.get(zid_column)) {
+                (Some(marker), Some(student)) if !marker.is_empty() && !student.is_empty() => (marker, student),
+                _ => bail!("Row {} of {} needs both a marker and a zid", line + 2, path.display()),
+            };
+
+            if let Some(other) = allocation.insert(normalise_zid(student), normalise_zid(marker)) {
+                if other != normalise_zid(marker) {
+                    bail!("{student} is allocated to both {other} and {marker} in {}", path.display());
+                }
+            }
+        }
+
+        Ok(Shard::Allocated(allocation))
+    }
+
+    pub fn is_all(&self) -> bool {
+        matches!(self, Shard::All)
+    }
+
+    pub fn is_mine(&self, tag: &JournalTag, marker: &str) -> bool {
+        match self {
+            Shard::All => true,
+            Shard::Hashed { index, count } => Self::hashed_index(tag, *count) == *index,
+            Shard::Allocated(allocation) => allocation.get(&normalise_zid(tag.student_id())) == Some(&normalise_zid(marker)),
+        }
+    }
+
+    /// Whose share a journal is in, to show alongside other markers' journals.
+    pub fn owner(&self, tag: &JournalTag) -> String {
+        match self {
+            Shard::All => String::new(),
+            Shard::Hashed { count, .. } => format!("share {}/{count}", Self::hashed_index(tag, *count)),
+            Shard::Allocated(allocation) => match allocation.get(&normalise_zid(tag.student_id())) {
+                Some(marker) => marker.to_string(),
+                None => String::from("unallocated"),
+            },
+        }
+    }
+
+    pub fn describe(&self, marker: &str) -> String {
+        match self {
+            Shard::All => String::from("everyone"),
+            Shard::Hashed { index, count } => format!("share {index}/{count}"),
+            Shard::Allocated(_) => format!("the students allocated to {}", normalise_zid(marker)),
+        }
+    }
+
+    fn hashed_index(tag: &JournalTag, count: u64) -> u64 {
+        stable_hash(0, &[tag.assignment(), tag.group_id()]) % count + 1
+    }
+}
+
+/// So `z5123456`, `Z5123456` and `5123456` are all the same person.
+fn normalise_zid(zid: &str) -> String {
+    let zid = zid.trim().to_lowercase();
+
+    if zid.chars().all(|c| c.is_ascii_digit()) {
+        format!("z{zid}")
+    } else {
+        zid
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tag(group_id: &str, student_id: &str) -> JournalTag {
+        JournalTag::new(String::from("ass1"), group_id.to_string(), student_id.to_string())
+    }
+
+    #[test]
+    fn a_group_is_all_in_one_share() {
+        let shard = Shard::hashed("1/5").unwrap();
+
+        for group in 0..50 {
+            let group_id = format!("g{group}");
+            let owner = shard.owner(&tag(&group_id, "z5000000"));
+
+            for student in 1..10 {
+                assert_eq!(shard.owner(&tag(&group_id, &format!("z500000{student}"))), owner);
+            }
+        }
+    }
+
+    #[test]
+    fn every_journal_is_in_exactly_one_share() {
+        let shards = (1..=4)
+            .map(|index| Shard::hashed(&format!("{index}/4")).unwrap())
+            .collect::<Vec<_>>();
+
+        for group in 0..50 {
+            let tag = tag(&format!("g{group}"), "z5000000");
+            assert_eq!(shards.iter().filter(|shard| shard.is_mine(&tag, "z1111111")).count(), 1);
+        }
+    }
+}
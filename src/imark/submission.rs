@@ -0,0 +1,454 @@
+use std::{mem, sync::Arc, time::{Duration, Instant}};
+
+use anyhow::Result;
+use async_trait::async_trait;
+
+use crate::{marklog::{MarkLog, PendingMark}, util::task::{Task, TaskRunner}};
+
+use super::{Authentication, JournalTag, MarkRejected, MarkMismatch, Prepared, Conflict, client::ImarkClient};
+
+/// How many times a mark is sent before giving up and asking the user.
+const MAX_ATTEMPTS: u32 = 5;
+/// The wait before the first retry, doubled for each retry after that.
+const BASE_BACKOFF: Duration = Duration::from_secs(1);
+
+pub struct Submission {
+    tag: JournalTag,
+    auth: Authentication,
+    imark: Arc<dyn ImarkClient>,
+    mark_log: Option<MarkLog>,
+    panic_on_drop: bool,
+    attempts: u32,
+    state: SubmissionState,
+}
+
+pub enum SubmissionState {
+    Preparing  { task: Task<PendingMark> },
+    Checking   { pending: PendingMark, task: Task<Prepared> },
+    Conflicted { conflict: Box<Conflict> },
+    Sending    { pending: PendingMark, task: Task<()> },
+    Waiting    { pending: PendingMark, retry_at: Instant, error: String },
+    Failed     { pending: PendingMark, error: String },
+    Done,
+}
+
+/// How to settle a submission that conflicts with another marker's changes.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    /// Drop this submission, leaving their changes be.
+    KeepTheirs,
+    /// Send this submission as-is, replacing their changes.
+    Overwrite,
+    /// Keep their changes, adding this submission's text after them.
+    Merge,
+}
+
+impl Submission {
+    pub fn new(tag: JournalTag, auth: Authentication, imark: Arc<dyn ImarkClient>, mark_log: Option<MarkLog>, task: Task<PendingMark>, panic_on_drop: bool) -> Self {
+        Self {
+            tag,
+            auth,
+            imark,
+            mark_log,
+            panic_on_drop,
+            attempts: 0,
+            state: SubmissionState::Preparing { task },
+        }
+    }
+
+    pub fn tag(&self) -> &JournalTag {
+        &self.tag
+    }
+
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    pub fn state(&self) -> &SubmissionState {
+        &self.state
+    }
+
+    /// The error from the most recent failed attempt, if it's waiting to retry or has given up.
+    pub fn error(&self) -> Option<&str> {
+        match &self.state {
+            SubmissionState::Waiting { error, .. } | SubmissionState::Failed { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        matches!(self.state, SubmissionState::Done)
+    }
+
+    pub fn is_failed(&self) -> bool {
+        matches!(self.state, SubmissionState::Failed { .. })
+    }
+
+    pub fn is_conflicted(&self) -> bool {
+        matches!(self.state, SubmissionState::Conflicted { .. })
+    }
+
+    pub fn conflict(&self) -> Option<&Conflict> {
+        match &self.state {
+            SubmissionState::Conflicted { conflict } => Some(conflict),
+            _ => None,
+        }
+    }
+
+    pub fn is_retrying(&self) -> bool {
+        match self.state {
+            SubmissionState::Waiting { .. } => true,
+            SubmissionState::Checking { .. }
+            | SubmissionState::Sending { .. } => self.attempts > 0,
+            _ => false,
+        }
+    }
+
+    /// Whether the submission is still being worked on (i.e. not done, and not waiting on the user).
+    pub fn is_in_flight(&self) -> bool {
+        !self.is_done() && !self.is_failed() && !self.is_conflicted()
+    }
+
+    /// Advances the submission. Only local failures (e.g. being unable to
+    /// write the mark log) are returned -- failures to reach imark are retried.
+    pub fn poll(&mut self) -> Result<()> {
+        match &mut self.state {
+            SubmissionState::Preparing { task } => {
+                if let Some(pending) = task.poll()? {
+                    self.check(pending);
+                }
+            }
+            SubmissionState::Checking { task, .. } => {
+                let result = match task.poll() {
+                    Ok(None) => return Ok(()),
+                    Ok(Some(prepared)) => Ok(prepared),
+                    Err(err) => Err(err),
+                };
+
+                let pending = match mem::replace(&mut self.state, SubmissionState::Done) {
+                    SubmissionState::Checking { pending, .. } => pending,
+                    _ => unreachable!(),
+                };
+
+                match result {
+                    Ok(Prepared::Ready(pending)) => self.send(pending),
+                    Ok(Prepared::Conflict(conflict)) => {
+                        self.state = SubmissionState::Conflicted { conflict: Box::new(conflict) };
+                    }
+                    Err(err) => self.fail(pending, err),
+                }
+            }
+            SubmissionState::Sending { task, .. } => {
+                let result = match task.poll() {
+                    Ok(None) => return Ok(()),
+                    Ok(Some(())) => Ok(()),
+                    Err(err) => Err(err),
+                };
+
+                let pending = match mem::replace(&mut self.state, SubmissionState::Done) {
+                    SubmissionState::Sending { pending, .. } => pending,
+                    _ => unreachable!(),
+                };
+
+                if let Err(err) = result {
+                    self.fail(pending, err);
+                }
+            }
+            SubmissionState::Waiting { retry_at, .. } => {
+                if Instant::now() >= *retry_at {
+                    if let SubmissionState::Waiting { pending, .. } = mem::replace(&mut self.state, SubmissionState::Done) {
+                        self.check(pending);
+                    }
+                }
+            }
+            SubmissionState::Conflicted { .. }
+            | SubmissionState::Failed { .. }
+            | SubmissionState::Done => {}
+        }
+
+        Ok(())
+    }
+
+    pub fn resolve(&mut self, resolution: Resolution) -> Result<()> {
+        let conflict = match &self.state {
+            SubmissionState::Conflicted { conflict } => conflict,
+            _ => return Ok(()),
+        };
+
+        let pending = match resolution {
+            Resolution::KeepTheirs => {
+                if let Some(mark_log) = &self.mark_log {
+                    mark_log.record_discarded(&conflict.pending.id)?;
+                }
+
+                self.state = SubmissionState::Done;
+                return Ok(());
+            }
+            Resolution::Overwrite => conflict.overwriting(),
+            Resolution::Merge     => conflict.merged(),
+        };
+
+        if let Some(mark_log) = &self.mark_log {
+            mark_log.record_pending(&pending)?;
+        }
+
+        self.send(pending);
+
+        Ok(())
+    }
+
+    /// Manually retries a failed submission, starting the backoff afresh.
+    pub fn retry(&mut self) {
+        if let SubmissionState::Failed { .. } = self.state {
+            if let SubmissionState::Failed { pending, .. } = mem::replace(&mut self.state, SubmissionState::Done) {
+                self.attempts = 0;
+                self.check(pending);
+            }
+        }
+    }
+
+    /// Gives up on a failed submission for good.
+    pub fn discard(&mut self) -> Result<()> {
+        if let SubmissionState::Failed { pending, .. } = &self.state {
+            if let Some(mark_log) = &self.mark_log {
+                mark_log.record_discarded(&pending.id)?;
+            }
+
+            self.state = SubmissionState::Done;
+        }
+
+        Ok(())
+    }
+
+    /// Every attempt is checked against imark first -- by the time a retry
+    /// comes around, someone else may have changed the mark.
+    fn check(&mut self, pending: PendingMark) {
+        let task = Task::new(
+            CheckMarkTask {
+                pending: pending.clone(),
+                auth:    self.auth.clone(),
+                imark:   self.imark.clone(),
+            },
+            self.panic_on_drop,
+        );
+
+        self.state = SubmissionState::Checking { pending, task };
+    }
+
+    fn send(&mut self, pending: PendingMark) {
+        let task = Task::new(
+            SendMarkTask {
+                pending:  pending.clone(),
+                auth:     self.auth.clone(),
+                imark:    self.imark.clone(),
+                mark_log: self.mark_log.clone(),
+            },
+            self.panic_on_drop,
+        );
+
+        self.state = SubmissionState::Sending { pending, task };
+    }
+
+    fn fail(&mut self, pending: PendingMark, err: anyhow::Error) {
+        // a mismatch means imark changed under us, so the user has to look at it
+        let transient = !err.is::<MarkMismatch>() && err.downcast_ref::<MarkRejected>()
+            .map(MarkRejected::is_transient)
+            .unwrap_or(true);
+
+        let error = format!("{err:#}");
+
+        self.attempts += 1;
+
+        self.state = if !transient || self.attempts >= MAX_ATTEMPTS {
+            SubmissionState::Failed { pending, error }
+        } else {
+            let backoff = BASE_BACKOFF * 2u32.pow(self.attempts - 1);
+            SubmissionState::Waiting { pending, retry_at: Instant::now() + backoff, error }
+        };
+    }
+}
+
+struct CheckMarkTask {
+    pending: PendingMark,
+    auth:    Authentication,
+    imark:   Arc<dyn ImarkClient>,
+}
+
+#[async_trait]
+impl TaskRunner<Prepared> for CheckMarkTask {
+    async fn run(self) -> Result<Prepared> {
+        super::check_mark(self.imark.as_ref(), self.pending, &self.auth).await
+    }
+}
+
+struct SendMarkTask {
+    pending:  PendingMark,
+    auth:     Authentication,
+    imark:    Arc<dyn ImarkClient>,
+    mark_log: Option<MarkLog>,
+}
+
+#[async_trait]
+impl TaskRunner<()> for SendMarkTask {
+    async fn run(self) -> Result<()> {
+        super::send_mark(self.imark.as_ref(), &self.pending, &self.auth).await?;
+
+        if let Some(mark_log) = &self.mark_log {
+            mark_log.record_committed(&self.pending.id)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::StatusCode;
+
+    use crate::imark::tests::*;
+    use super::*;
+
+    #[tokio::test]
+    async fn sends_a_mark() {
+        let imark = fake_imark();
+        let mut journals = loaded_journals(imark.clone()).await;
+
+        queue_mark(&mut journals);
+        settle(&mut journals).await;
+
+        assert!(journals.submissions.is_empty());
+        assert!(remote_text(&imark).ends_with("+2 Compiles\n\nNice work\n"));
+    }
+
+    #[tokio::test]
+    async fn retries_a_server_error() {
+        let imark = fake_imark();
+        let mut journals = loaded_journals(imark.clone()).await;
+
+        imark.reject_next_put(StatusCode::SERVICE_UNAVAILABLE);
+        queue_mark(&mut journals);
+
+        while journals.n_retrying() == 0 {
+            journals.scan_queue().unwrap();
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(journals.submissions[0].attempts(), 1);
+        assert!(journals.submission_error(&tag()).unwrap().contains("503"));
+
+        settle(&mut journals).await;
+
+        assert!(journals.submissions.is_empty());
+        assert!(remote_text(&imark).ends_with("+2 Compiles\n\nNice work\n"));
+    }
+
+    #[tokio::test]
+    async fn logs_and_retries_a_mark_while_imark_is_unreachable() {
+        let imark = fake_imark();
+        let dir = tempfile::tempdir().unwrap();
+        let log_path = dir.path().join("marks.log");
+        let (mark_log, _) = MarkLog::open(&log_path).unwrap();
+        let mut journals = logged_journals(imark.clone(), Some(mark_log)).await;
+
+        imark.fail_next_gets(1);
+        queue_mark(&mut journals);
+
+        while journals.n_retrying() == 0 {
+            journals.scan_queue().unwrap();
+            tokio::task::yield_now().await;
+        }
+        assert!(journals.submission_error(&tag()).unwrap().contains("unreachable"));
+        assert!(std::fs::read_to_string(&log_path).unwrap().contains(r#""kind":"pending""#));
+
+        settle(&mut journals).await;
+
+        assert!(journals.submissions.is_empty());
+        assert!(remote_text(&imark).ends_with("+2 Compiles\n\nNice work\n"));
+        assert!(std::fs::read_to_string(&log_path).unwrap().contains(r#""kind":"committed""#));
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_a_client_error() {
+        let imark = fake_imark();
+        let mut journals = loaded_journals(imark.clone()).await;
+
+        imark.reject_next_put(StatusCode::FORBIDDEN);
+        queue_mark(&mut journals);
+        settle(&mut journals).await;
+
+        let failed = journals.failed_submissions().collect::<Vec<_>>();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].attempts(), 1);
+    }
+
+    #[tokio::test]
+    async fn does_not_resend_over_a_verification_mismatch() {
+        let imark = fake_imark();
+        let mut journals = loaded_journals(imark.clone()).await;
+
+        imark.interlope_next_put("their feedback\n");
+        queue_mark(&mut journals);
+        settle(&mut journals).await;
+
+        let failed = journals.failed_submissions().collect::<Vec<_>>();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].attempts(), 1);
+        assert!(failed[0].error().unwrap().contains("doesn't match"));
+
+        assert_eq!(remote_text(&imark), "their feedback\n");
+    }
+
+    #[tokio::test]
+    async fn retrying_after_a_mismatch_checks_for_conflicts_again() {
+        let imark = fake_imark();
+        let mut journals = loaded_journals(imark.clone()).await;
+
+        imark.interlope_next_put("their feedback\n");
+        queue_mark(&mut journals);
+        settle(&mut journals).await;
+
+        journals.retry_failed(0);
+        settle(&mut journals).await;
+
+        assert_eq!(journals.failed_submissions().count(), 0);
+        assert_eq!(journals.conflicts().count(), 1);
+        assert_eq!(remote_text(&imark), "their feedback\n");
+    }
+
+    #[tokio::test]
+    async fn waits_on_the_user_to_resolve_a_conflict() {
+        let imark = fake_imark();
+        let mut journals = loaded_journals(imark.clone()).await;
+
+        other_marker_writes(&imark, "their feedback\n").await;
+        queue_mark(&mut journals);
+        settle(&mut journals).await;
+
+        assert_eq!(journals.conflicts().count(), 1);
+        assert_eq!(remote_text(&imark), "their feedback\n");
+
+        journals.resolve_conflict(Resolution::Merge).unwrap();
+        settle(&mut journals).await;
+
+        assert!(journals.submissions.is_empty());
+
+        let text = remote_text(&imark);
+        assert!(text.starts_with("their feedback\n\nmarked with flymark by z1111111"));
+        assert!(text.ends_with("+2 Compiles\n\nNice work\n"));
+    }
+
+    #[tokio::test]
+    async fn keeping_theirs_drops_the_submission() {
+        let imark = fake_imark();
+        let mut journals = loaded_journals(imark.clone()).await;
+
+        other_marker_writes(&imark, "their feedback\n").await;
+        queue_mark(&mut journals);
+        settle(&mut journals).await;
+
+        journals.resolve_conflict(Resolution::KeepTheirs).unwrap();
+        settle(&mut journals).await;
+
+        assert!(journals.submissions.is_empty());
+        assert_eq!(remote_text(&imark), "their feedback\n");
+    }
+}
@@ -2,35 +2,72 @@
 
 mod app;
 mod choice;
+mod cli;
+mod config;
+mod filter;
 mod imark;
+mod marklog;
+mod offline;
 mod term;
 mod ui;
 mod util;
 
-use std::process::Stdio;
+use std::{process::Stdio, io::Write, path::{Path, PathBuf}, sync::Arc, mem, time::{SystemTime, UNIX_EPOCH}};
 
 use anyhow::{Result, bail, Context};
 use choice::{Choices, Choice};
-use clap::Parser;
-use imark::Globals;
+use clap::{Parser, Subcommand};
+use config::{Profile, KeyBindings};
+use imark::{Globals, Authentication, client::{ImarkClient, ReqwestImark}, cache::{self, SubmissionCache}, credentials::StoredCredentials, ordering::{SortKey, SortOrder}, shard::Shard};
+use marklog::{MarkLog, PendingMark};
 use tempfile::TempDir;
 use tokio::{process::Command, fs::File, io::AsyncReadExt};
 
 #[derive(Parser, Debug)]
 #[clap(version, author)]
 pub struct Args {
-    /// The cgi endpoint you will use for marking (overrides the course + session args).
+    #[clap(subcommand)]
+    command: Option<Subcommands>,
+
+    /// The cgi endpoint to use (overrides the course + session args).
     /// Generally not required.
-    #[clap(short('e'), long)]
+    #[clap(short('e'), long, global = true)]
     cgi_endpoint: Option<String>,
 
+    /// The profile to take settings from, out of ~/.config/flymark/config.toml
+    /// and .flymark.toml (default: the config's default_profile).
+    #[clap(long, global = true)]
+    profile: Option<String>,
+
+    /// Your zid, so it doesn't have to be typed in
+    /// (default: $FLYMARK_ZID, or the login in ~/.netrc).
+    #[clap(long, global = true)]
+    zid: Option<String>,
+
+    /// Command that prints your imark password, e.g. `pass show imark`
+    /// (used if $FLYMARK_PASSWORD isn't set; falls back to ~/.netrc).
+    #[clap(long, global = true)]
+    password_command: Option<String>,
+
+    /// Where downloaded journals are kept for offline marking
+    /// (default: $XDG_DATA_HOME/flymark/offline/<course>-<session>).
+    #[clap(long, global = true)]
+    offline_dir: Option<PathBuf>,
+
+    /// Without a subcommand, flymark marks -- `flymark ...` is short for `flymark mark ...`.
+    #[clap(flatten)]
+    mark: MarkArgs,
+}
+
+#[derive(clap::Args, Debug, Default)]
+pub struct MarkArgs {
     /// Command to run the marking pager (default: tries to find bat, falls back to less)
     #[clap(short, long)]
     pager_command: Option<String>,
 
-    /// The name of the mark that we're marking for
-    #[clap(short, long, default_value = "performance")]
-    mark_name: String,
+    /// The name of the mark that we're marking for (default: performance)
+    #[clap(short, long)]
+    mark_name: Option<String>,
 
     /// Don't panic if flymark detects potential data loss.
     /// This is sometimes useful if you are debugging an
@@ -38,83 +75,620 @@ pub struct Args {
     #[clap(long)]
     ignore_lost_data: bool,
 
-    /// How many journals ahead to load in advance.
-    #[clap(long, default_value = "5")]
-    preload: usize,
+    /// How many journals ahead to load in advance (default: 5).
+    #[clap(long)]
+    preload: Option<usize>,
 
     /// Hide student names for increased anonymity.
     #[clap(long)]
     hide_names: bool,
 
+    /// The lowest mark that can be given with a manual override.
+    #[clap(long, default_value = "0")]
+    min_mark: f64,
+
+    /// The highest mark that can be given with a manual override
+    /// (default: the scheme's @max, if it has one).
+    #[clap(long)]
+    max_mark: Option<f64>,
+
+    /// Where to log marks before they're sent, so they can be
+    /// replayed if flymark crashes
+    /// (default: $XDG_STATE_HOME/flymark/marks.log).
+    #[clap(long)]
+    mark_log: Option<PathBuf>,
+
+    /// Don't read or save journals in the on-disk cache.
+    #[clap(long)]
+    no_cache: bool,
+
+    /// Empty the on-disk journal cache before starting.
+    #[clap(long)]
+    clear_cache: bool,
+
+    /// What to sort journals by, both in the journal list and when
+    /// marking one after another (default: marks). Can be changed
+    /// from the journal list with <tab>.
+    #[clap(long, arg_enum)]
+    sort: Option<SortKey>,
+
+    /// The seed for `--sort random`, to get the same order again
+    /// (default: a new one every run).
+    #[clap(long)]
+    sort_seed: Option<u64>,
+
+    /// Only mark one share of the journals, when splitting them between
+    /// markers -- e.g. `2/5` for the second of five. Every marker gets the
+    /// same shares, so each just needs a different number.
+    #[clap(long, value_name = "N/COUNT")]
+    shard: Option<String>,
+
+    /// Only mark the students allocated to you in a CSV with `marker`
+    /// and `zid` columns (one row per student).
+    #[clap(long, conflicts_with = "shard")]
+    allocation: Option<PathBuf>,
+
+    /// With --shard or --allocation, still list other markers' journals
+    /// (but don't let them be marked).
+    #[clap(long)]
+    show_others: bool,
+
+    /// Mark against journals fetched with `flymark download`,
+    /// rather than imark itself. Upload the marks with `flymark sync`.
+    #[clap(long)]
+    offline: bool,
+
     /// The path to the marking scheme you will use
-    scheme: String,
+    scheme: Option<PathBuf>,
 
     /// Course (format: cs1521)
-    course: String,
+    course: Option<String>,
 
     /// Session (format: 22T1)
-    session: String,
+    session: Option<String>,
+}
+
+/// The course to work with, for commands that don't need anything else
+/// positional -- so they can be left to the profile.
+#[derive(clap::Args, Debug)]
+pub struct CourseArgs {
+    /// Course (format: cs1521; default: the profile's)
+    #[clap(short, long)]
+    course: Option<String>,
+
+    /// Session (format: 22T1; default: the profile's)
+    #[clap(short, long)]
+    session: Option<String>,
+}
+
+impl Args {
+    /// Fills in anything not given on the command line from `profile`.
+    fn apply_profile(&mut self, profile: &Profile) {
+        self.cgi_endpoint     = self.cgi_endpoint.take().or_else(|| profile.cgi_endpoint.clone());
+        self.zid              = self.zid.take().or_else(|| profile.zid.clone());
+        self.password_command = self.password_command.take().or_else(|| profile.password_command.clone());
+    }
+}
+
+impl MarkArgs {
+    fn apply_profile(&mut self, profile: &Profile) {
+        self.pager_command = self.pager_command.take().or_else(|| profile.pager_command.clone());
+        self.mark_name     = self.mark_name.take().or_else(|| profile.mark_name.clone());
+        self.preload       = self.preload.or(profile.preload);
+        self.hide_names   |= profile.hide_names.unwrap_or(false);
+        self.sort          = self.sort.or(profile.sort);
+        self.sort_seed     = self.sort_seed.or(profile.sort_seed);
+        self.show_others  |= profile.show_others.unwrap_or(false);
+
+        // a shard on the command line replaces the profile's allocation, and vice versa
+        if self.shard.is_none() && self.allocation.is_none() {
+            self.shard      = profile.shard.clone();
+            self.allocation = profile.allocation.clone();
+        }
+        self.scheme        = self.scheme.take().or_else(|| profile.scheme.clone());
+        self.course        = self.course.take().or_else(|| profile.course.clone());
+        self.session       = self.session.take().or_else(|| profile.session.clone());
+    }
+}
+
+impl CourseArgs {
+    fn apply_profile(&mut self, profile: &Profile) {
+        self.course  = self.course.take().or_else(|| profile.course.clone());
+        self.session = self.session.take().or_else(|| profile.session.clone());
+    }
+
+    fn course_and_session(&self) -> Result<(&str, &str)> {
+        match (&self.course, &self.session) {
+            (Some(course), Some(session)) => Ok((course, session)),
+            _ => bail!("A course and session are required -- give them with --course and --session or set them in a profile"),
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum Subcommands {
+    /// Mark journals (what flymark does when no subcommand is given).
+    Mark(MarkArgs),
+    /// List the assignments you can mark.
+    Assignments {
+        #[clap(flatten)]
+        course: CourseArgs,
+
+        /// Print JSON rather than a list.
+        #[clap(long)]
+        json: bool,
+    },
+    /// List every journal for an assignment, with its marks.
+    List {
+        #[clap(flatten)]
+        course: CourseArgs,
+
+        /// The assignment to list
+        assignment: String,
+
+        /// Print JSON rather than a table.
+        #[clap(long)]
+        json: bool,
+
+        /// Leave student names out.
+        #[clap(long)]
+        hide_names: bool,
+    },
+    /// Show a student's submission and marks for an assignment.
+    Show {
+        #[clap(flatten)]
+        course: CourseArgs,
+
+        /// The assignment the submission is for
+        assignment: String,
+
+        /// The student's zid
+        #[clap(value_name = "ZID")]
+        student: String,
+
+        /// Print the submitted files' contents, not just their names.
+        #[clap(long)]
+        files: bool,
+
+        /// Print JSON rather than text.
+        #[clap(long)]
+        json: bool,
+
+        /// Leave the student's name out.
+        #[clap(long)]
+        hide_names: bool,
+    },
+    /// Export the marks for an assignment as CSV or JSON.
+    Export {
+        #[clap(flatten)]
+        course: CourseArgs,
+
+        /// The assignment to export
+        assignment: String,
+
+        /// The columns to export, in order, separated by commas
+        /// (default: zid,group,name,mark,provisional,notes).
+        #[clap(long, arg_enum, use_value_delimiter = true)]
+        columns: Vec<cli::Column>,
+
+        /// The format to export in.
+        #[clap(long, arg_enum, default_value = "csv")]
+        format: cli::ExportFormat,
+
+        /// Where to write the export (default: stdout).
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+
+        /// The mark to export the marker and text of (default: performance).
+        #[clap(short, long)]
+        mark_name: Option<String>,
+
+        /// Leave student names out.
+        #[clap(long)]
+        hide_names: bool,
+    },
+    /// Set marks from a CSV with zid, mark and (optionally) comment columns.
+    Import {
+        #[clap(flatten)]
+        course: CourseArgs,
+
+        /// The assignment to set marks for
+        assignment: String,
+
+        /// The CSV of marks to import
+        file: PathBuf,
+
+        /// Only show what would change.
+        #[clap(long)]
+        dry_run: bool,
+
+        /// Don't ask before changing marks.
+        #[clap(short, long)]
+        yes: bool,
+
+        /// The mark to set (default: performance).
+        #[clap(short, long)]
+        mark_name: Option<String>,
+
+        /// The lowest mark that can be imported.
+        #[clap(long, default_value = "0")]
+        min_mark: f64,
+
+        /// The highest mark that can be imported
+        /// (default: the scheme's @max, if it has one).
+        #[clap(long)]
+        max_mark: Option<f64>,
+
+        /// The scheme to take @max from (default: the profile's scheme).
+        #[clap(long)]
+        scheme: Option<PathBuf>,
+
+        /// Where to log marks before they're sent
+        /// (default: $XDG_STATE_HOME/flymark/marks.log).
+        #[clap(long)]
+        mark_log: Option<PathBuf>,
+    },
+    /// Download an assignment's journals for marking offline.
+    Download {
+        #[clap(flatten)]
+        course: CourseArgs,
+
+        /// The assignment to download
+        assignment: String,
+    },
+    /// Upload marks made offline, checking for anyone else's changes first.
+    Sync {
+        #[clap(flatten)]
+        course: CourseArgs,
+    },
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let args = Args::parse();
-    let cgi_endpoint = get_cgi_endpoint(&args);
-    let choices  = get_choices(&args.scheme).await
-        .with_context(|| format!("Failed to read scheme file: {}", args.scheme))?;
+    let mut args = Args::parse();
+
+    let mut profile = config::load_profile(args.profile.as_deref())?;
+    args.apply_profile(&profile);
+
+    let command = match args.command.take() {
+        Some(command) => command,
+        None => Subcommands::Mark(mem::take(&mut args.mark)),
+    };
+
+    match command {
+        Subcommands::Mark(mut mark_args) => {
+            mark_args.apply_profile(&profile);
+            mark(&args, mark_args, profile.keys.bindings()).await
+        }
+        Subcommands::Assignments { mut course, json } => {
+            course.apply_profile(&profile);
+            let (globals, auth) = connect(&args, &course, &profile, None).await?;
+
+            cli::assignments(&globals, &auth, json).await
+        }
+        Subcommands::List { mut course, assignment, json, hide_names } => {
+            course.apply_profile(&profile);
+            profile.hide_names = Some(hide_names || profile.hide_names.unwrap_or(false));
+            let (globals, auth) = connect(&args, &course, &profile, None).await?;
+
+            cli::list(&globals, &auth, &assignment, json).await
+        }
+        Subcommands::Show { mut course, assignment, student, files, json, hide_names } => {
+            course.apply_profile(&profile);
+            profile.hide_names = Some(hide_names || profile.hide_names.unwrap_or(false));
+            let (globals, auth) = connect(&args, &course, &profile, None).await?;
+
+            cli::show(&globals, &auth, &assignment, &student, files, json).await
+        }
+        Subcommands::Export { mut course, assignment, columns, format, output, mark_name, hide_names } => {
+            course.apply_profile(&profile);
+            profile.mark_name = mark_name.or(profile.mark_name);
+            profile.hide_names = Some(hide_names || profile.hide_names.unwrap_or(false));
+            let (globals, auth) = connect(&args, &course, &profile, None).await?;
+
+            let columns = match columns.is_empty() {
+                true => cli::Column::DEFAULT.iter()
+                    .copied()
+                    .filter(|column| !(globals.hide_names() && *column == cli::Column::Name))
+                    .collect(),
+                false => columns,
+            };
+
+            cli::export(&globals, &auth, &assignment, &columns, format, output.as_deref()).await
+        }
+        Subcommands::Import { mut course, assignment, file, dry_run, yes, mark_name, min_mark, max_mark, scheme, mark_log } => {
+            course.apply_profile(&profile);
+            profile.mark_name = mark_name.or(profile.mark_name);
+
+            let max_mark = match (max_mark, scheme.or_else(|| profile.scheme.clone())) {
+                (Some(max_mark), _) => Some(max_mark),
+                (None, Some(scheme)) => get_choices(&scheme, &profile.keys.bindings()).await
+                    .with_context(|| format!("Failed to read scheme: {}", scheme.display()))?
+                    .max_mark,
+                (None, None) => None,
+            };
+
+            // marks left over from a marking session stay in the log for it to replay
+            let mark_log = match mark_log.or_else(marklog::default_path) {
+                Some(path) => Some(
+                    MarkLog::open(&path)
+                        .with_context(|| format!("Failed to open mark log: {}", path.display()))?
+                        .0
+                ),
+                None => None,
+            };
+
+            let (globals, auth) = connect(&args, &course, &profile, mark_log).await?;
+
+            cli::import(&globals, &auth, &assignment, &file, min_mark, max_mark, dry_run, yes).await
+        }
+        Subcommands::Download { mut course, assignment } => {
+            course.apply_profile(&profile);
+            let (cgi_endpoint, dir) = offline_target(&args, &course)?;
+            let auth  = prompt_credentials(find_credentials(&args, &cgi_endpoint).await?)?;
+            let imark = ReqwestImark::new(cgi_endpoint);
+
+            offline::download(&imark, &auth, &assignment, &dir).await
+        }
+        Subcommands::Sync { mut course } => {
+            course.apply_profile(&profile);
+            let (cgi_endpoint, dir) = offline_target(&args, &course)?;
+            let auth  = prompt_credentials(find_credentials(&args, &cgi_endpoint).await?)?;
+            let imark = ReqwestImark::new(cgi_endpoint);
+
+            offline::sync(&imark, &auth, &dir).await
+        }
+    }
+}
+
+async fn mark(args: &Args, mark_args: MarkArgs, keys: KeyBindings) -> Result<()> {
+    let (scheme, course, session) = match (&mark_args.scheme, &mark_args.course, &mark_args.session) {
+        (Some(scheme), Some(course), Some(session)) => (scheme.as_path(), course.as_str(), session.as_str()),
+        _ => bail!("A scheme, course and session are required -- give them as arguments or set them in a profile (see --help)"),
+    };
+
+    let (cgi_endpoint, imark): (String, Arc<dyn ImarkClient>) = if mark_args.offline {
+        let dir = get_offline_dir(args, course, session)?;
+
+        // a distinct endpoint keeps offline marks in the mark log from
+        // ever being replayed straight to imark
+        (format!("offline:{}", dir.display()), Arc::new(offline::OfflineImark::open(dir)?))
+    } else {
+        let cgi_endpoint = get_cgi_endpoint(args, course, session);
+        (cgi_endpoint.to_string(), Arc::new(ReqwestImark::new(cgi_endpoint)))
+    };
+
+    let choices  = get_choices(scheme, &keys).await
+        .with_context(|| format!("Failed to read scheme file: {}", scheme.display()))?;
+
+    let cache = open_cache(&mark_args, &cgi_endpoint)?;
+
+    // offline marking never talks to imark, so there's nothing to log in to
+    let credentials = if mark_args.offline {
+        StoredCredentials::default()
+    } else {
+        find_credentials(args, &cgi_endpoint).await?
+    };
 
     ensure_tmux()?;
 
-    let pager_command = locate_pager(&args).await?;
+    let pager_command = locate_pager(&mark_args).await?;
+
+    let max_mark = choices.max_mark;
+
+    let (mark_log, replay) = open_mark_log(&mark_args, &cgi_endpoint)?;
 
     let _work_dir = move_to_work_dir()
         .context("Failed to create temporary work directory")?;
     
+    let sort_seed = mark_args.sort_seed.unwrap_or_else(|| {
+        SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|since| since.as_nanos() as u64 % 1_000_000)
+            .unwrap_or_default()
+    });
+
+    let shard = match (&mark_args.shard, &mark_args.allocation) {
+        (Some(_), Some(_)) => bail!("Give either a shard or an allocation file, not both"),
+        (Some(shard), None) => Shard::hashed(shard)?,
+        (None, Some(allocation)) => Shard::allocated(allocation)?,
+        (None, None) => Shard::All,
+    };
+
     let globals = Globals::new(
         cgi_endpoint,
         pager_command,
-        args.mark_name,
+        mark_args.mark_name.unwrap_or_else(|| String::from("performance")),
         choices,
-        args.preload,
-        !args.ignore_lost_data,
-        args.hide_names,
+        mark_args.preload.unwrap_or(5),
+        !mark_args.ignore_lost_data,
+        mark_args.hide_names,
+        mark_args.min_mark,
+        mark_args.max_mark.or(max_mark),
+        mark_log,
+        replay,
+        imark,
+        cache,
+        keys,
+        SortOrder::new(mark_args.sort.unwrap_or_default(), sort_seed),
+        shard,
+        mark_args.show_others,
     );
     
-    ui::launch(globals).await?;
+    ui::launch(globals, credentials).await?;
 
     println!("Thanks for using flymark!");
     Ok(())
 }
 
-fn get_cgi_endpoint(args: &Args) -> String {
+/// Logs in for the non-interactive commands, asking for whatever credentials weren't stored.
+async fn connect(args: &Args, course: &CourseArgs, profile: &Profile, mark_log: Option<MarkLog>) -> Result<(Globals, Authentication)> {
+    let cgi_endpoint = match &args.cgi_endpoint {
+        Some(cgi_endpoint) => cgi_endpoint.to_string(),
+        None => {
+            let (course, session) = course.course_and_session()?;
+            get_cgi_endpoint(args, course, session)
+        }
+    };
+
+    let auth = prompt_credentials(find_credentials(args, &cgi_endpoint).await?)?;
+
+    let mark_name = profile.mark_name.clone().unwrap_or_else(|| String::from("performance"));
+    let imark = Arc::new(ReqwestImark::new(cgi_endpoint.to_string()));
+
+    let hide_names = profile.hide_names.unwrap_or(false);
+
+    Ok((Globals::headless(cgi_endpoint, mark_name, hide_names, mark_log, imark), auth))
+}
+
+fn get_cgi_endpoint(args: &Args, course: &str, session: &str) -> String {
     args.cgi_endpoint
         .clone()
         .unwrap_or_else(|| {
-            let course  = args.course.as_str();
-            let session = args.session.as_str();
-
             format!("https://cgi.cse.unsw.edu.au/~{course}/{session}/imark/server.cgi/")
         })
 }
 
-async fn get_choices(scheme: &str) -> Result<Choices> {
+fn get_offline_dir(args: &Args, course: &str, session: &str) -> Result<PathBuf> {
+    match args.offline_dir.clone().or_else(|| offline::default_dir(course, session)) {
+        Some(dir) => Ok(dir),
+        None => bail!("Couldn't work out where to keep offline journals (is $HOME set?) -- please specify one with --offline-dir"),
+    }
+}
+
+/// The imark that `download` and `sync` copy journals between, and where the copy lives.
+fn offline_target(args: &Args, course: &CourseArgs) -> Result<(String, PathBuf)> {
+    // with both given explicitly, there's no course to work anything out from
+    if let (Some(cgi_endpoint), Some(dir)) = (&args.cgi_endpoint, &args.offline_dir) {
+        return Ok((cgi_endpoint.to_string(), dir.to_path_buf()));
+    }
+
+    let (course, session) = course.course_and_session()?;
+
+    Ok((get_cgi_endpoint(args, course, session), get_offline_dir(args, course, session)?))
+}
+
+fn open_cache(args: &MarkArgs, cgi_endpoint: &str) -> Result<Option<SubmissionCache>> {
+    // offline journals are already on disk
+    if args.offline {
+        return Ok(None);
+    }
+
+    let dir = match cache::default_dir(cgi_endpoint) {
+        Some(dir) => dir,
+        None => return Ok(None),
+    };
+
+    if args.clear_cache {
+        SubmissionCache::clear(&dir)?;
+    }
+
+    if args.no_cache {
+        return Ok(None);
+    }
+
+    Ok(Some(SubmissionCache::open(dir)?))
+}
+
+async fn find_credentials(args: &Args, cgi_endpoint: &str) -> Result<StoredCredentials> {
+    StoredCredentials::find(cgi_endpoint, args.zid.clone(), args.password_command.as_deref()).await
+}
+
+/// Asks for whichever of the zid and password weren't stored.
+fn prompt_credentials(stored: StoredCredentials) -> Result<Authentication> {
+    let zid = match stored.zid {
+        Some(zid) => zid,
+        None => {
+            print!("zid: ");
+            std::io::stdout().flush()?;
+
+            let mut zid = String::new();
+            std::io::stdin().read_line(&mut zid)?;
+            zid.trim().to_string()
+        }
+    };
+
+    let password = match stored.password {
+        Some(password) => password,
+        None => rpassword::prompt_password_stdout(&format!("password for {zid}: "))?,
+    };
+
+    Ok(Authentication::new(zid, password))
+}
+
+async fn get_choices(scheme: &Path, keys: &KeyBindings) -> Result<Choices> {
     let mut file = File::open(scheme).await?;
 
     let mut contents = String::new();
     file.read_to_string(&mut contents).await?;
 
-    let choices = choice::parse_choices(&contents)?;
+    let choices = choice::parse_choices(&contents, keys)?;
 
     let real_choice = choices.choices.iter()
-        .find(|choice| !matches!(choice, Choice::Comment(_)));
+        .find(|choice| matches!(choice, Choice::Plus(_, _) | Choice::Minus(_, _) | Choice::Set(_, _)));
     
     if real_choice.is_none() {
         bail!("Choice file must contain at least one *actual* choice");
     }
 
+    choices.check_max_mark()?;
+
     Ok(choices)
 }
 
+fn open_mark_log(args: &MarkArgs, cgi_endpoint: &str) -> Result<(Option<MarkLog>, Vec<PendingMark>)> {
+    let path = match args.mark_log.clone().or_else(marklog::default_path) {
+        Some(path) => path,
+        None => {
+            eprintln!("Warning: couldn't work out where to keep the mark log (is $HOME set?) -- marks won't be logged");
+            return Ok((None, vec![]));
+        }
+    };
+
+    let (mark_log, uncommitted) = MarkLog::open(&path)
+        .with_context(|| format!("Failed to open mark log: {}", path.display()))?;
+
+    // marks for other imark instances can only be replayed when marking those
+    let uncommitted = uncommitted.into_iter()
+        .filter(|pending| pending.cgi_endpoint == cgi_endpoint)
+        .collect::<Vec<_>>();
+
+    if uncommitted.is_empty() {
+        return Ok((Some(mark_log), vec![]));
+    }
+
+    println!("Found {} mark(s) from a previous session that may not have reached imark:", uncommitted.len());
+    for pending in &uncommitted {
+        println!(
+            "  {} {} -- {} (marked at {})",
+            pending.tag.assignment(),
+            pending.tag.student_id(),
+            pending.mark,
+            pending.at,
+        );
+    }
+
+    loop {
+        print!("[r]eplay them once logged in, [d]iscard them, or [k]eep them for later? ");
+        std::io::stdout().flush()?;
+
+        let mut answer = String::new();
+        std::io::stdin().read_line(&mut answer)?;
+
+        match answer.trim() {
+            "r" | "R" => return Ok((Some(mark_log), uncommitted)),
+            "d" | "D" => {
+                for pending in &uncommitted {
+                    mark_log.record_discarded(&pending.id)?;
+                }
+
+                return Ok((Some(mark_log), vec![]));
+            }
+            "k" | "K" => return Ok((Some(mark_log), vec![])),
+            _ => {}
+        }
+    }
+}
+
 fn ensure_tmux() -> Result<()> {
     if !std::env::vars().any(|(arg, _)| arg == "TMUX") {
         return Err(anyhow::anyhow!("Not in tmux session (TMUX environment variable not set)"));
@@ -123,7 +697,7 @@ fn ensure_tmux() -> Result<()> {
     Ok(())
 }
 
-async fn locate_pager(args: &Args) -> Result<String> {
+async fn locate_pager(args: &MarkArgs) -> Result<String> {
     if let Some(pager) = args.pager_command.as_ref() {
         return Ok(pager.to_string());
     }
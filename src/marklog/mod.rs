@@ -0,0 +1,210 @@
+use std::{fs::{self, File, OpenOptions}, io::{self, BufRead, BufReader, Write}, os::unix::io::AsRawFd, path::{Path, PathBuf}, sync::Arc};
+
+use anyhow::{Result, Context, bail};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::imark::{JournalTag, MarkStamp};
+
+/// An append-only log of every mark flymark sends, so that marks which
+/// never reached imark (crash, network failure, ...) can be replayed.
+#[derive(Clone)]
+pub struct MarkLog {
+    inner: Arc<MarkLogInner>,
+}
+
+struct MarkLogInner {
+    path: PathBuf,
+    file: Mutex<File>,
+    /// Held for as long as the log is open -- see [`lock`].
+    _lock: File,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingMark {
+    pub id:           String,
+    pub cgi_endpoint: String,
+    pub tag:          JournalTag,
+    pub mark_lines:   Vec<String>,
+    pub mark:         f64,
+    pub imark_id:     usize,
+    pub mark_name:    String,
+    pub text:         String,
+    pub at:           String,
+    pub by:           String,
+    /// The marking file as it was loaded, before this mark was added to it.
+    /// Missing from marks logged by older versions of flymark.
+    #[serde(default)]
+    pub base:         Option<MarkBase>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MarkBase {
+    pub stamp: MarkStamp,
+    pub text:  String,
+}
+
+impl PendingMark {
+    /// The text this mark adds to the marking file.
+    pub fn added_text(&self) -> &str {
+        self.base.as_ref()
+            .and_then(|base| self.text.strip_prefix(base.text.as_str()))
+            .unwrap_or(&self.text)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum Record {
+    Pending(Box<PendingMark>),
+    Committed { id: String },
+    Discarded { id: String },
+}
+
+impl MarkLog {
+    /// Opens (or creates) the log at `path`, returning it along with every
+    /// mark that was logged but never committed. The log is compacted down
+    /// to just those uncommitted marks.
+    ///
+    /// Fails if another flymark instance already has the log open.
+    pub fn open(path: &Path) -> Result<(Self, Vec<PendingMark>)> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let lock = lock(path)?;
+
+        let mut uncommitted: Vec<PendingMark> = vec![];
+
+        if path.exists() {
+            let reader = BufReader::new(File::open(path)?);
+
+            for (line_index, line) in reader.lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+
+                let record: Record = serde_json::from_str(&line)
+                    .with_context(|| format!("Corrupt mark log entry on line {}", line_index + 1))?;
+
+                match record {
+                    // a mark is logged again when a conflict over it is settled
+                    Record::Pending(pending) => {
+                        uncommitted.retain(|logged| logged.id != pending.id);
+                        uncommitted.push(*pending);
+                    }
+                    Record::Committed { id } | Record::Discarded { id } => {
+                        uncommitted.retain(|pending| pending.id != id);
+                    }
+                }
+            }
+        }
+
+        let compacted_path = path.with_extension("compacting");
+        let mut compacted = File::create(&compacted_path)?;
+        for pending in &uncommitted {
+            write_record(&mut compacted, &Record::Pending(Box::new(pending.clone())))?;
+        }
+        drop(compacted);
+        fs::rename(&compacted_path, path)?;
+
+        let file = OpenOptions::new()
+            .append(true)
+            .open(path)?;
+
+        let log = Self {
+            inner: Arc::new(MarkLogInner {
+                path: path.to_path_buf(),
+                file: Mutex::new(file),
+                _lock: lock,
+            }),
+        };
+
+        Ok((log, uncommitted))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.inner.path
+    }
+
+    pub fn record_pending(&self, pending: &PendingMark) -> Result<()> {
+        write_record(&mut self.inner.file.lock(), &Record::Pending(Box::new(pending.clone())))
+    }
+
+    pub fn record_committed(&self, id: &str) -> Result<()> {
+        write_record(&mut self.inner.file.lock(), &Record::Committed { id: id.to_string() })
+    }
+
+    pub fn record_discarded(&self, id: &str) -> Result<()> {
+        write_record(&mut self.inner.file.lock(), &Record::Discarded { id: id.to_string() })
+    }
+}
+
+/// Takes an exclusive lock on a file next to the log for the whole session.
+/// Compacting replaces the log file, so a second instance sharing it would
+/// leave the first appending to a file that no longer exists.
+fn lock(path: &Path) -> Result<File> {
+    let lock_path = path.with_extension("lock");
+    let lock = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)
+        .with_context(|| format!("Failed to open mark log lock: {}", lock_path.display()))?;
+
+    // SAFETY: flock only operates on the descriptor, which `lock` keeps open
+    if unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        let err = io::Error::last_os_error();
+
+        if err.kind() == io::ErrorKind::WouldBlock {
+            bail!(
+                "Mark log {} is in use by another flymark instance -- \
+                 close it or give this one its own log with --mark-log",
+                path.display(),
+            );
+        }
+
+        return Err(err)
+            .with_context(|| format!("Failed to lock mark log: {}", lock_path.display()));
+    }
+
+    Ok(lock)
+}
+
+fn write_record(file: &mut File, record: &Record) -> Result<()> {
+    let mut line = serde_json::to_string(record)?;
+    line.push('\n');
+
+    file.write_all(line.as_bytes())?;
+    file.sync_data()?;
+
+    Ok(())
+}
+
+/// Where the mark log lives when `--mark-log` isn't given.
+pub fn default_path() -> Option<PathBuf> {
+    let state_dir = match std::env::var_os("XDG_STATE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".local/state"),
+    };
+
+    Some(state_dir.join("flymark/marks.log"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_second_instance_cannot_open_a_log_in_use() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("marks.log");
+
+        let (log, _) = MarkLog::open(&path).unwrap();
+        assert!(MarkLog::open(&path).is_err());
+
+        drop(log);
+        assert!(MarkLog::open(&path).is_ok());
+    }
+}
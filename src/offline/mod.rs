@@ -0,0 +1,397 @@
+use std::{collections::BTreeMap, fs, io::Write, path::{Path, PathBuf}};
+
+use anyhow::{Result, Context, bail};
+use async_trait::async_trait;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+
+use crate::imark::{Authentication, JournalTag, client::{ImarkClient, SubmissionList, SubmissionJson, MarkJson}};
+
+/// A local copy of an assignment's submissions, marked against while
+/// offline. Marks are written to the copy straight away, and queued in an
+/// outbox to be uploaded by `flymark sync`.
+///
+/// Layout: `<assignment>/submissions.json` holds the listing, and
+/// `<assignment>/<group id>/<student id>.json` each submission.
+pub struct OfflineImark {
+    dir: PathBuf,
+    lock: Mutex<()>,
+}
+
+/// A mark made offline, along with what imark had when it was downloaded,
+/// so `sync` can tell whether someone else has changed it since.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct OutboxEntry {
+    pub tag: JournalTag,
+    pub imark_id: String,
+    pub base: Option<MarkJson>,
+    pub mark: MarkJson,
+}
+
+impl OfflineImark {
+    pub fn open(dir: PathBuf) -> Result<Self> {
+        if !dir.is_dir() {
+            bail!("Nothing has been downloaded to {} -- run `flymark download` first", dir.display());
+        }
+
+        Ok(Self { dir, lock: Mutex::new(()) })
+    }
+
+    fn listing_path(&self, assignment: &str) -> PathBuf {
+        self.dir.join(assignment).join("submissions.json")
+    }
+
+    fn submission_path(&self, tag: &JournalTag) -> PathBuf {
+        self.dir.join(tag.assignment()).join(tag.group_id()).join(format!("{}.json", tag.student_id()))
+    }
+}
+
+#[async_trait]
+impl ImarkClient for OfflineImark {
+    async fn list_assignments(&self, _auth: &Authentication) -> Result<Vec<String>> {
+        let mut assignments = vec![];
+
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let assignment = entry.file_name().to_string_lossy().into_owned();
+
+            if self.listing_path(&assignment).exists() {
+                assignments.push(assignment);
+            }
+        }
+
+        assignments.sort();
+        Ok(assignments)
+    }
+
+    async fn list_submissions(&self, _auth: &Authentication, assignment: &str) -> Result<SubmissionList> {
+        read_json(&self.listing_path(assignment))
+    }
+
+    async fn get_submission(&self, _auth: &Authentication, tag: &JournalTag) -> Result<SubmissionJson> {
+        read_json(&self.submission_path(tag))
+    }
+
+    async fn put_marks(&self, _auth: &Authentication, tag: &JournalTag, marks: BTreeMap<String, MarkJson>) -> Result<()> {
+        let _lock = self.lock.lock();
+
+        let mut submission: SubmissionJson = read_json(&self.submission_path(tag))?;
+        let mut listing: SubmissionList = read_json(&self.listing_path(tag.assignment()))?;
+        let mut outbox = read_outbox(&self.dir)?;
+
+        for (imark_id, mark) in marks {
+            let base = match outbox.iter().position(|entry| &entry.tag == tag && entry.imark_id == imark_id) {
+                Some(index) => outbox.remove(index).base,
+                None => submission.marks.get(&imark_id).cloned(),
+            };
+
+            if mark.is_final {
+                submission.metadata.mark = mark.mark;
+
+                if let Some(summary) = listing.get_mut(tag.group_id()).and_then(|group| group.get_mut(tag.student_id())) {
+                    summary.mark = mark.mark;
+                }
+            }
+
+            submission.marks.insert(imark_id.to_string(), mark.clone());
+            outbox.push(OutboxEntry { tag: tag.clone(), imark_id, base, mark });
+        }
+
+        // the outbox goes first -- it's what matters if we're interrupted part way
+        write_outbox(&self.dir, &outbox)?;
+        write_json(&self.submission_path(tag), &submission)?;
+        write_json(&self.listing_path(tag.assignment()), &listing)?;
+
+        Ok(())
+    }
+}
+
+/// Copies every submission for an assignment into `dir`, ready for marking offline.
+pub async fn download(imark: &dyn ImarkClient, auth: &Authentication, assignment: &str, dir: &Path) -> Result<()> {
+    let unsynced = read_outbox(dir)?.iter()
+        .filter(|entry| entry.tag.assignment() == assignment)
+        .count();
+
+    if unsynced > 0 {
+        bail!("There are {unsynced} unsynced mark(s) for {assignment} -- run `flymark sync` before downloading it again");
+    }
+
+    let listing = imark.list_submissions(auth, assignment).await?;
+
+    let tags = listing.iter()
+        .flat_map(|(group_id, group)| {
+            group.keys()
+                .map(|student_id| JournalTag::new(assignment.to_string(), group_id.to_string(), student_id.to_string()))
+        })
+        .collect::<Vec<_>>();
+
+    if tags.is_empty() {
+        bail!("No journals found for {assignment}");
+    }
+
+    for (index, tag) in tags.iter().enumerate() {
+        print!("\rDownloading {assignment}: {}/{}", index + 1, tags.len());
+        std::io::stdout().flush()?;
+
+        let submission = imark.get_submission(auth, tag).await
+            .with_context(|| format!("Failed to download {}", tag.student_id()))?;
+
+        write_json(&dir.join(assignment).join(tag.group_id()).join(format!("{}.json", tag.student_id())), &submission)?;
+    }
+
+    println!();
+
+    // written last, so a half-finished download isn't mistaken for a usable one
+    write_json(&dir.join(assignment).join("submissions.json"), &listing)?;
+
+    println!("Downloaded {} journal(s) to {}", tags.len(), dir.display());
+
+    Ok(())
+}
+
+/// Uploads every mark made offline, asking what to do about any that
+/// someone else has changed on imark in the meantime.
+pub async fn sync(imark: &dyn ImarkClient, auth: &Authentication, dir: &Path) -> Result<()> {
+    sync_with(imark, auth, dir, |question| prompt(question, &["o", "m", "s"])).await
+}
+
+/// As [`sync`], with `ask` deciding what to do about each conflict.
+async fn sync_with(imark: &dyn ImarkClient, auth: &Authentication, dir: &Path, mut ask: impl FnMut(&str) -> Result<String>) -> Result<()> {
+    let mut outbox = read_outbox(dir)?;
+
+    if outbox.is_empty() {
+        println!("Nothing to sync");
+        return Ok(());
+    }
+
+    let (mut n_synced, mut n_skipped) = (0, 0);
+
+    let mut index = 0;
+    while index < outbox.len() {
+        let entry = &outbox[index];
+        let student_id = entry.tag.student_id();
+
+        let current = imark.get_submission(auth, &entry.tag).await
+            .with_context(|| format!("Failed to fetch {student_id}"))?
+            .marks
+            .remove(&entry.imark_id);
+
+        let mut mark = entry.mark.clone();
+
+        let already_synced = current.as_ref().map(|current| current.text == mark.text).unwrap_or(false);
+        let conflicted = match &current {
+            Some(current) => !already_synced && stamp(Some(current)) != stamp(entry.base.as_ref()),
+            None => false,
+        };
+
+        if let Some(theirs) = current.as_ref().filter(|_| conflicted) {
+            println!(
+                "{student_id}: `{}` was changed by {} at {} since it was downloaded.",
+                mark.name,
+                theirs.by.as_deref().unwrap_or("someone else"),
+                theirs.at.as_deref().unwrap_or("an unknown time"),
+            );
+
+            match ask("[o]verwrite their changes, [m]erge (add yours after theirs), or [s]kip for now? ")?.as_str() {
+                "o" => {}
+                "m" => {
+                    let base_text = entry.base.as_ref().and_then(|base| base.text.as_deref()).unwrap_or("");
+                    let ours = mark.text.as_deref().unwrap_or("");
+                    let added = ours.strip_prefix(base_text).unwrap_or(ours);
+
+                    mark.text = Some(theirs.text.as_deref().unwrap_or("").to_string() + added);
+                }
+                _ => {
+                    n_skipped += 1;
+                    index += 1;
+                    continue;
+                }
+            }
+        }
+
+        if !already_synced {
+            let tag = entry.tag.clone();
+            let mut marks = BTreeMap::new();
+            marks.insert(entry.imark_id.to_string(), mark.clone());
+
+            imark.put_marks(auth, &tag, marks).await
+                .with_context(|| format!("Failed to upload the mark for {student_id}"))?;
+
+            let landed = imark.get_submission(auth, &tag).await?
+                .marks
+                .remove(&outbox[index].imark_id)
+                .map(|landed| landed.text == mark.text)
+                .unwrap_or(false);
+
+            if !landed {
+                bail!("The mark for {} was uploaded, but imark doesn't have it -- it's been left in the outbox", tag.student_id());
+            }
+
+            println!("{}: synced ({})", tag.student_id(), mark.mark.map(|mark| mark.to_string()).unwrap_or_default());
+        }
+
+        outbox.remove(index);
+        write_outbox(dir, &outbox)?;
+        n_synced += 1;
+    }
+
+    println!("Synced {n_synced} mark(s), {n_skipped} skipped");
+
+    Ok(())
+}
+
+pub fn default_dir(course: &str, session: &str) -> Option<PathBuf> {
+    let data_dir = match std::env::var_os("XDG_DATA_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".local/share"),
+    };
+
+    Some(data_dir.join(format!("flymark/offline/{course}-{session}")))
+}
+
+fn stamp(mark: Option<&MarkJson>) -> Option<(Option<&str>, Option<&str>, Option<&str>)> {
+    mark.map(|mark| (mark.at.as_deref(), mark.by.as_deref(), mark.text.as_deref()))
+}
+
+fn prompt(question: &str, answers: &[&str]) -> Result<String> {
+    loop {
+        print!("{question}");
+        std::io::stdout().flush()?;
+
+        let mut answer = String::new();
+        if std::io::stdin().read_line(&mut answer)? == 0 {
+            bail!("No answer given");
+        }
+
+        let answer = answer.trim().to_lowercase();
+        if answers.contains(&answer.as_str()) {
+            return Ok(answer);
+        }
+    }
+}
+
+fn read_outbox(dir: &Path) -> Result<Vec<OutboxEntry>> {
+    let path = dir.join("outbox.json");
+
+    if path.exists() {
+        read_json(&path)
+    } else {
+        Ok(vec![])
+    }
+}
+
+fn write_outbox(dir: &Path, outbox: &[OutboxEntry]) -> Result<()> {
+    write_json(&dir.join("outbox.json"), &outbox)
+}
+
+fn read_json<T: DeserializeOwned>(path: &Path) -> Result<T> {
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+
+    serde_json::from_str(&text)
+        .with_context(|| format!("Failed to parse {}", path.display()))
+}
+
+/// Writes via a temporary file, so a crash never leaves a half-written file behind.
+fn write_json<T: Serialize + ?Sized>(path: &Path, value: &T) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let temp_path = path.with_extension("json.tmp");
+    fs::write(&temp_path, serde_json::to_vec(value)?)
+        .with_context(|| format!("Failed to write {}", temp_path.display()))?;
+    fs::rename(&temp_path, path)
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::imark::tests::{MARK_NAME, auth, fake_imark, other_marker_writes, tag};
+
+    fn offline_mark(text: &str) -> BTreeMap<String, MarkJson> {
+        let mut marks = BTreeMap::new();
+        marks.insert(String::from("1"), MarkJson {
+            name:     String::from(MARK_NAME),
+            at:       Some(String::from("2022-05-02 09:00:00")),
+            by:       Some(String::from("z1111111")),
+            is_final: true,
+            mark:     Some(4.0),
+            text:     Some(text.to_string()),
+        });
+
+        marks
+    }
+
+    fn never_asked(_question: &str) -> Result<String> {
+        panic!("asked about a conflict that isn't one");
+    }
+
+    #[test]
+    fn opening_needs_a_download() {
+        let dir = tempfile::tempdir().unwrap();
+
+        assert!(OfflineImark::open(dir.path().join("nothing-here")).is_err());
+    }
+
+    #[tokio::test]
+    async fn marks_made_offline_sync_back_to_imark() {
+        let dir = tempfile::tempdir().unwrap();
+        let imark = fake_imark();
+
+        download(&*imark, &auth(), "ass1", dir.path()).await.unwrap();
+
+        let offline = OfflineImark::open(dir.path().to_path_buf()).unwrap();
+        assert_eq!(offline.list_assignments(&auth()).await.unwrap(), vec![String::from("ass1")]);
+        assert_eq!(offline.get_submission(&auth(), &tag()).await.unwrap().files["1"].contents, "int main;");
+
+        offline.put_marks(&auth(), &tag(), offline_mark("Good")).await.unwrap();
+
+        // marked offline, so imark hasn't seen it yet
+        assert_eq!(offline.list_submissions(&auth(), "ass1").await.unwrap()["g1"]["z5000000"].mark, Some(4.0));
+        assert!(imark.submission(&tag()).unwrap().marks.is_empty());
+        assert_eq!(read_outbox(dir.path()).unwrap().len(), 1);
+
+        sync_with(&*imark, &auth(), dir.path(), never_asked).await.unwrap();
+
+        let synced = imark.submission(&tag()).unwrap();
+        assert_eq!(synced.marks["1"].text.as_deref(), Some("Good"));
+        assert_eq!(synced.metadata.mark, Some(4.0));
+        assert!(read_outbox(dir.path()).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn downloading_again_waits_for_unsynced_marks() {
+        let dir = tempfile::tempdir().unwrap();
+        let imark = fake_imark();
+
+        download(&*imark, &auth(), "ass1", dir.path()).await.unwrap();
+        OfflineImark::open(dir.path().to_path_buf()).unwrap()
+            .put_marks(&auth(), &tag(), offline_mark("Good")).await.unwrap();
+
+        assert!(download(&*imark, &auth(), "ass1", dir.path()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn conflicts_are_merged_or_skipped_as_asked() {
+        let dir = tempfile::tempdir().unwrap();
+        let imark = fake_imark();
+
+        download(&*imark, &auth(), "ass1", dir.path()).await.unwrap();
+        OfflineImark::open(dir.path().to_path_buf()).unwrap()
+            .put_marks(&auth(), &tag(), offline_mark("Good")).await.unwrap();
+
+        other_marker_writes(&imark, "Theirs\n").await;
+
+        sync_with(&*imark, &auth(), dir.path(), |_| Ok(String::from("s"))).await.unwrap();
+        assert_eq!(imark.submission(&tag()).unwrap().marks["1"].text.as_deref(), Some("Theirs\n"));
+        assert_eq!(read_outbox(dir.path()).unwrap().len(), 1);
+
+        sync_with(&*imark, &auth(), dir.path(), |_| Ok(String::from("m"))).await.unwrap();
+        assert_eq!(imark.submission(&tag()).unwrap().marks["1"].text.as_deref(), Some("Theirs\nGood"));
+        assert!(read_outbox(dir.path()).unwrap().is_empty());
+    }
+}
@@ -1,6 +1,6 @@
 use std::{marker::PhantomData, num::Wrapping};
 
-use tui::{Frame, backend::Backend, layout::{Layout, Direction, Constraint}, widgets::{Paragraph, Block, Borders}, style::{Style, Color}};
+use tui::{Frame, backend::Backend, layout::{Layout, Direction, Constraint, Rect, Alignment}, widgets::{Paragraph, Block, Borders, Wrap}, style::{Style, Color}};
 
 use crate::app::auth::{AppPreAuth, AppPreAuthState};
 
@@ -56,7 +56,7 @@ impl<B: Backend + Send + 'static> UiPage<B> for AuthUi<B> {
             .split(chunks[1]);
 
         match app.state() {
-            AppPreAuthState::EnteringZid { zid_input } => {
+            AppPreAuthState::EnteringZid { zid_input, error } => {
                 let zid_paragraph = Paragraph::new(zid_input.value())
                     .block(
                         Block::default()
@@ -76,12 +76,14 @@ impl<B: Backend + Send + 'static> UiPage<B> for AuthUi<B> {
 
                 frame.render_widget(password_paragraph, chunks[2]);
 
+                draw_error(frame, error.as_deref(), chunks[2].y + INPUT_HEIGHT);
+
                 frame.set_cursor(
                     chunks[1].x + zid_input.cursor() as u16 + 1,
                     chunks[1].y + 1,
                 )
             }
-            AppPreAuthState::EnteringPassword { zid, password_input } => {
+            AppPreAuthState::EnteringPassword { zid, password_input, error } => {
                 let zid_paragraph = Paragraph::new(zid.as_str())
                     .block(
                         Block::default()
@@ -91,8 +93,9 @@ impl<B: Backend + Send + 'static> UiPage<B> for AuthUi<B> {
 
                 frame.render_widget(zid_paragraph, chunks[1]);
 
+                // one `*` per character, so the cursor lines up with what's been typed
                 let password_paragraph = Paragraph::new(
-                        String::from("*").repeat(password_input.value().len())
+                        String::from("*").repeat(password_input.value().chars().count())
                     )
                     .block(
                         Block::default()
@@ -103,23 +106,32 @@ impl<B: Backend + Send + 'static> UiPage<B> for AuthUi<B> {
 
                 frame.render_widget(password_paragraph, chunks[2]);
 
+                draw_error(frame, error.as_deref(), chunks[2].y + INPUT_HEIGHT);
+
                 frame.set_cursor(
                     chunks[2].x + password_input.cursor() as u16 + 1,
                     chunks[2].y + 1,
                 )
 
             }
-            AppPreAuthState::Authenticating { .. } => {
+            AppPreAuthState::Authenticating { .. } | AppPreAuthState::ReplayingMarks { .. } => {
+                let message = match app.state() {
+                    AppPreAuthState::ReplayingMarks { .. } => {
+                        format!("Replaying {} mark(s) from last session", app.globals().replay().len())
+                    }
+                    _ => String::from("Loading"),
+                };
+
                 const INPUT_HEIGHT: u16 = 1;
-                const INPUT_WIDTH:  u16 = 10;
+                let input_width = message.len() as u16 + 3;
 
                 let chunks = Layout::default()
                     .direction(Direction::Horizontal)
                     .constraints(
                         [
-                            Constraint::Length(size.width.saturating_sub(INPUT_WIDTH) / 2),
-                            Constraint::Length(INPUT_WIDTH + size.width % 2),
-                            Constraint::Length(size.width.saturating_sub(INPUT_WIDTH) / 2),
+                            Constraint::Length(size.width.saturating_sub(input_width) / 2),
+                            Constraint::Length(input_width + size.width % 2),
+                            Constraint::Length(size.width.saturating_sub(input_width) / 2),
                         ]
                     )
                     .split(size);
@@ -135,7 +147,7 @@ impl<B: Backend + Send + 'static> UiPage<B> for AuthUi<B> {
                     )
                     .split(chunks[1]);
 
-                let loading = Paragraph::new(String::from("Loading") + &".".repeat((self.ticker.0 as usize % 81) / 27 + 1))
+                let loading = Paragraph::new(message + &".".repeat((self.ticker.0 as usize % 81) / 27 + 1))
                     .block(
                         Block::default()
                         .borders(Borders::NONE)
@@ -143,6 +155,30 @@ impl<B: Backend + Send + 'static> UiPage<B> for AuthUi<B> {
                 
                 frame.render_widget(loading, chunks[1]);
             }
+            AppPreAuthState::ReplayFailed { failures, .. } => {
+                let text = format!(
+                    "{} of {} mark(s) from last session couldn't be replayed. \
+                    They're still in the mark log, and will be offered again next time.\n\
+                    \n\
+                    {}\n\
+                    \n\
+                    Press <enter> to continue",
+                    failures.len(),
+                    app.globals().replay().len(),
+                    failures.join("\n"),
+                );
+
+                let paragraph = Paragraph::new(text)
+                    .block(
+                        Block::default()
+                            .title("Replaying marks")
+                            .borders(Borders::ALL)
+                            .border_style(Style::default().fg(Color::Red))
+                    )
+                    .wrap(Wrap { trim: false });
+
+                frame.render_widget(paragraph, size);
+            }
         }
     }
 
@@ -150,3 +186,25 @@ impl<B: Backend + Send + 'static> UiPage<B> for AuthUi<B> {
         self.ticker += 1;
     }
 }
+
+/// Why the last login failed, across the width of the screen below the inputs.
+fn draw_error<B: Backend>(frame: &mut Frame<B>, error: Option<&str>, y: u16) {
+    let error = match error {
+        Some(error) => error,
+        None => return,
+    };
+
+    let size = frame.size();
+    if y >= size.height {
+        return;
+    }
+
+    let area = Rect::new(size.x, y + 1, size.width, size.height.saturating_sub(y + 1).min(4));
+
+    let paragraph = Paragraph::new(error)
+        .style(Style::default().fg(Color::Red))
+        .alignment(Alignment::Center)
+        .wrap(Wrap { trim: true });
+
+    frame.render_widget(paragraph, area);
+}
@@ -1,6 +1,6 @@
 use std::{marker::PhantomData, num::Wrapping};
 
-use tui::{Frame, backend::Backend, widgets::{ListItem, List, Block, Borders, ListState, Paragraph}, style::{Style, Color, Modifier}, layout::{Layout, Direction, Constraint, Rect}};
+use tui::{Frame, backend::Backend, widgets::{ListItem, List, Block, Borders, ListState, Paragraph}, text::Span, style::{Style, Color, Modifier}, layout::{Layout, Direction, Constraint, Rect}};
 
 use crate::app::journals::AppJournalList;
 
@@ -40,12 +40,18 @@ impl<B: Backend + Send + 'static> UiPage<B> for JournalsUi<B> {
                     .split(size)
             ).expect("chunk split into two");
 
+        let filter_block = match app.filter_error() {
+            Some(error) => Block::default()
+                .borders(Borders::ALL)
+                .border_style(Style::default().fg(Color::Red))
+                .title(Span::styled(format!("Filter -- {error}"), Style::default().fg(Color::Red))),
+            None => Block::default()
+                .borders(Borders::ALL)
+                .title("Filter (e.g. mark:unmarked, prov:>=8, notes:\"late\", zid:z512* OR NOT group:g1)"),
+        };
+
         let filter_paragraph = Paragraph::new(app.filter().value())
-            .block(
-                Block::default()
-                    .borders(Borders::ALL)
-                    .title("Filter")
-                );
+            .block(filter_block);
 
         frame.set_cursor(
             filter_chunk.x + app.filter().cursor() as u16 + 1,
@@ -64,32 +70,51 @@ impl<B: Backend + Send + 'static> UiPage<B> for JournalsUi<B> {
                 (journal.meta().mark(), journal.meta().provisional_mark(), journal.meta().name().to_string(), journal.meta().notes().map(str::to_string))
             };
 
-            let item = ListItem::new(
-                format!(
-                    "{} | {:6} | {}",
-                    tag.student_id(),
-                    mark.map(|m| format!("{:>5.02}", m))
-                        .or(provisional_mark.map(|m| format!("{:>5.02}?", m)))
-                        .unwrap_or_else(|| "".to_string()),
-                    if app.globals().hide_names() {
-                        String::new()
-                    } else {
-                        format!(
-                            "{:30} | {}",
-                            &name,
-                            notes.unwrap_or(String::new()),
-                        )
-                    },
-                )
+            let row = format!(
+                "{} | {:6} | {}",
+                tag.student_id(),
+                mark.map(|m| format!("{:>5.02}", m))
+                    .or(provisional_mark.map(|m| format!("{:>5.02}?", m)))
+                    .unwrap_or_else(|| "".to_string()),
+                if app.globals().hide_names() {
+                    String::new()
+                } else {
+                    format!(
+                        "{:30} | {}",
+                        &name,
+                        notes.unwrap_or(String::new()),
+                    )
+                },
             );
 
+            let item = match app.journals().submission_error(tag) {
+                Some(error) => ListItem::new(Span::styled(
+                    format!("{row} | mark not saved: {error}"),
+                    Style::default().fg(Color::Red),
+                )),
+                None if !app.journals().is_mine(tag) => ListItem::new(Span::styled(
+                    format!("{row} | {}", app.globals().shard().owner(tag)),
+                    Style::default().fg(Color::DarkGray),
+                )),
+                None => ListItem::new(row),
+            };
+
             list_items.push(item);
         }
 
+        let sort_order = app.journals().sort_order().describe();
+        let title = match app.globals().shard() {
+            shard if shard.is_all() => format!("Choose a journal (sorted by {sort_order} -- <tab> to change)"),
+            shard => format!(
+                "Choose a journal from {} (sorted by {sort_order} -- <tab> to change)",
+                shard.describe(app.auth().username()),
+            ),
+        };
+
         let list = List::new(list_items)
             .block(
                 Block::default()
-                    .title("Choose a journal")
+                    .title(title)
                     .borders(Borders::ALL)
             )
             .style(Style::default().fg(Color::White))
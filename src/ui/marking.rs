@@ -1,8 +1,8 @@
 use std::{marker::PhantomData, num::Wrapping};
 
-use tui::{Frame, backend::Backend, widgets::{ListItem, List, Block, Borders, ListState, Paragraph}, style::{Style, Color, Modifier}, layout::{Layout, Direction, Constraint, Rect}, text::Span};
+use tui::{Frame, backend::Backend, widgets::{ListItem, List, Block, Borders, ListState, Paragraph, Wrap, Clear}, style::{Style, Color, Modifier}, layout::{Layout, Direction, Constraint, Rect}, text::Span};
 
-use crate::{app::marking::{AppMarking, AppMarkingState, Opened}, choice::Choice, util::HOTKEYS};
+use crate::{app::marking::{AppMarking, AppMarkingState, MarkingFocus, Opened}, choice::{self, Choice}, config::{RETRY_KEY, DISCARD_KEY}, imark::submission::{Submission, Resolution}};
 
 use super::UiPage;
 
@@ -69,21 +69,36 @@ impl<B: Backend + Send + 'static> UiPage<B> for MarkingUi<B> {
                 
                 frame.render_widget(loading, chunks[1]);
             }
-            AppMarkingState::Marking { choices: selections } => {
+            AppMarkingState::Marking { choices: selections, comment, mark_override, stale_lines, focus } => {
                 let size = frame.size();
     
-                let info = "Press <space> to toggle a choice\n\
-                Press <up>/<down> to select a choice\n\
-                Press <enter> to submit and move to next journal\n\
-                Press <s> to skip marking this journal\n\
-                Press <b> to go back one journal\n\
-                Press <q> to return to the journal list";
+                let keys = app.globals().keys();
+                let info = format!(
+                    "Press <{}> to toggle a choice\n\
+                    Press <up>/<down> to select a choice\n\
+                    Press <enter> to submit and move to next journal\n\
+                    Press <{}> to skip marking this journal\n\
+                    Press <{}> to edit the comment (<enter> to finish editing)\n\
+                    Press <{}> to override the mark (submit an empty mark to clear)\n\
+                    Press <{}> to review submissions that failed to send\n\
+                    Press <{}> to go back one journal\n\
+                    Press <{}> to return to the journal list",
+                    key_name(keys.toggle),
+                    key_name(keys.skip),
+                    key_name(keys.comment),
+                    key_name(keys.override_mark),
+                    key_name(keys.failures),
+                    key_name(keys.back),
+                    key_name(keys.quit),
+                );
 
                 let info_height = info.lines().count() as u16;
                 const MARGIN: u16 = 1;
     
-                let [journal_info_chunk, _, info_chunk, _, selections_chunk, progress_chunk] = 
-                    <[Rect; 6]>::try_from(
+                const COMMENT_HEIGHT: u16 = 3;
+    
+                let [journal_info_chunk, _, info_chunk, _, selections_chunk, comment_chunk, progress_chunk] = 
+                    <[Rect; 7]>::try_from(
                         Layout::default()
                             .direction(Direction::Vertical)
                             .constraints(
@@ -92,12 +107,13 @@ impl<B: Backend + Send + 'static> UiPage<B> for MarkingUi<B> {
                                     Constraint::Length(MARGIN),
                                     Constraint::Length(info_height),
                                     Constraint::Length(MARGIN),
-                                    Constraint::Length(size.height.saturating_sub(info_height + 2 * MARGIN + 2 + 1)),
+                                    Constraint::Length(size.height.saturating_sub(info_height + 2 * MARGIN + 2 + COMMENT_HEIGHT + 1)),
+                                    Constraint::Length(COMMENT_HEIGHT),
                                     Constraint::Length(1),
                                 ]
                             )
                             .split(size)
-                    ).expect("chunk split into five");
+                    ).expect("chunk split into seven");
 
                 let journal_info = {
                     if let Some(journal) = app.journals().try_get(app.live_journal_tag()) {
@@ -110,18 +126,26 @@ impl<B: Backend + Send + 'static> UiPage<B> for MarkingUi<B> {
                         };
                         let notes = journal.meta().notes();
             
+                        let mark = mark.map(|m| format!("{:>5.02}", m))
+                            .or(provisional_mark.map(|m| format!("{:>5.02}?", m)))
+                            .unwrap_or_else(|| "-".to_string());
+                        let mark = match app.globals().choices().max_mark {
+                            Some(max_mark) => format!("{mark:6} / {max_mark}"),
+                            None => mark,
+                        };
+
                         Paragraph::new(
                             {
                                 let name_len = name.len();
+                                let mark_len = mark.len().max(6);
 
                                 format!(
-                                    "zid     | mark   | {:name_len$} | notes\n\
-                                    {} | {:6} | {} | {}",
+                                    "zid     | {:mark_len$} | {:name_len$} | notes\n\
+                                    {} | {:mark_len$} | {} | {}",
+                                    "mark",
                                     "name",
                                     app.live_journal_tag().student_id(),
-                                    mark.map(|m| format!("{:>5.02}", m))
-                                        .or(provisional_mark.map(|m| format!("{:>5.02}?", m)))
-                                        .unwrap_or_else(|| "".to_string()),
+                                    mark,
                                     name,
                                     notes.unwrap_or(""),
                                 )
@@ -143,19 +167,24 @@ impl<B: Backend + Send + 'static> UiPage<B> for MarkingUi<B> {
     
                 frame.render_widget(info, info_chunk);
 
+                let section_subtotals = selections.section_subtotals();
+
                 let list_items = app.globals().choices().choices.iter()
                     .enumerate()
                     .map(|(index, choice)| {
-                        let hotkey = {
-                            selections.from_real_index(index)
-                                .and_then(|(index, _)| HOTKEYS.chars().nth(index))
-                        };
+                        let hotkey = selections.from_real_index(index)
+                            .and_then(|(_, selection)| selection.hotkey());
 
                         let hotkey_string = match hotkey {
                             Some(hotkey) => format!("[{}] ", hotkey),
                             None => String::new(),
                         };
 
+                        let hotkey_string = match selections.from_real_index(index) {
+                            Some((_, selection)) if selection.group().is_some() => format!("│ {hotkey_string}"),
+                            _ => hotkey_string,
+                        };
+
                         ListItem::new(Span::styled(
                             match choice {
                                 Choice::Plus (n, text) => {
@@ -167,6 +196,27 @@ impl<B: Backend + Send + 'static> UiPage<B> for MarkingUi<B> {
                                 Choice::Set  (n, text) => {
                                     format!("{hotkey_string}{n} {text}")
                                 }
+                                Choice::Section(section) => {
+                                    let section_index = app.globals().choices().choices[..index].iter()
+                                        .filter(|choice| matches!(choice, Choice::Section(_)))
+                                        .count();
+
+                                    let bounds = match section.max {
+                                        Some(max) => format!("min {}, max {max}", section.min),
+                                        None => format!("min {}", section.min),
+                                    };
+
+                                    format!("{}: {} ({bounds})", section.name, section_subtotals[section_index])
+                                }
+                                Choice::SectionEnd => {
+                                    String::new()
+                                }
+                                Choice::GroupStart(name) => {
+                                    format!("┌ {name} (pick one)")
+                                }
+                                Choice::GroupEnd => {
+                                    String::from("└")
+                                }
                                 Choice::Comment(text)  => {
                                     text.to_string()
                                 }
@@ -177,30 +227,144 @@ impl<B: Backend + Send + 'static> UiPage<B> for MarkingUi<B> {
                                         .bg(Color::White)
                                         .fg(Color::Black)
                                 }
+                                _ if matches!(choice, Choice::Section(_)) => {
+                                    Style::default()
+                                        .add_modifier(Modifier::BOLD)
+                                }
                                 _ => Style::default()
                             }
                         ))
                     })
+                    .chain(
+                        stale_lines.iter()
+                            .map(|line| {
+                                ListItem::new(Span::styled(
+                                    format!("[no longer in scheme] {line}"),
+                                    Style::default().fg(Color::Red),
+                                ))
+                            })
+                    )
                     .collect::<Vec<_>>();
     
+                let live_mark = match mark_override {
+                    Some(mark_override) => mark_override.mark,
+                    None => selections.mark(),
+                };
+
+                let live_mark = match selections.max_mark() {
+                    Some(max_mark) => format!("{live_mark} / {max_mark}"),
+                    None => live_mark.to_string(),
+                };
+
+                let list_title = match mark_override {
+                    Some(mark_override) => format!("Mark: {live_mark} [overridden: {}]", mark_override.reason),
+                    None => format!("Mark: {live_mark}"),
+                };
+
+                let [selections_chunk, preview_chunk] =
+                    <[Rect; 2]>::try_from(
+                        Layout::default()
+                            .direction(Direction::Horizontal)
+                            .constraints(
+                                [
+                                    Constraint::Percentage(60),
+                                    Constraint::Percentage(40),
+                                ]
+                            )
+                            .split(selections_chunk)
+                    ).expect("chunk split into two");
+
+                let (list_items, list_title, list_cursor) = match focus {
+                    MarkingFocus::Failures { cursor } => {
+                        let failures = app.journals().failed_submissions()
+                            .map(|submission| {
+                                ListItem::new(Span::styled(
+                                    format!("{} -- {}", submission.tag().student_id(), submission.error().unwrap_or("")),
+                                    Style::default().fg(Color::Red),
+                                ))
+                            })
+                            .collect::<Vec<_>>();
+
+                        (failures, format!("Failed submissions -- <{RETRY_KEY}> to retry, <{DISCARD_KEY}> to discard, <esc> to close"), *cursor)
+                    }
+                    _ => (list_items, list_title, selections.real_cursor()),
+                };
+
                 let list = List::new(list_items)
                     .block(
                         Block::default()
-                            .title("Mark")
+                            .title(list_title)
                             .borders(Borders::ALL)
                     )
                     .highlight_symbol(">> ");
                 
                 let mut list_state = ListState::default();
-                list_state.select(Some(selections.real_cursor()));
+                list_state.select(Some(list_cursor));
     
                 frame.render_stateful_widget(list, selections_chunk, &mut list_state);
 
+                let preview = Paragraph::new(choice::feedback_text(selections, mark_override.as_ref(), comment.value()))
+                    .block(
+                        Block::default()
+                            .title("Preview")
+                            .borders(Borders::ALL)
+                    )
+                    .wrap(Wrap { trim: false });
+
+                frame.render_widget(preview, preview_chunk);
+
+                let (prompt_title, prompt_input) = match focus {
+                    MarkingFocus::Choices | MarkingFocus::Comment | MarkingFocus::Failures { .. } => {
+                        (String::from("Comment"), comment)
+                    }
+                    MarkingFocus::OverrideMark { mark_input, error } => {
+                        let range = match app.globals().max_mark() {
+                            Some(max_mark) => format!("{} - {max_mark}", app.globals().min_mark()),
+                            None => format!("at least {}", app.globals().min_mark()),
+                        };
+
+                        let title = match error {
+                            Some(error) => format!("Override mark ({range}) -- {error}"),
+                            None => format!("Override mark ({range})"),
+                        };
+
+                        (title, mark_input)
+                    }
+                    MarkingFocus::OverrideReason { mark, reason_input } => {
+                        (format!("Reason for overriding mark to {mark} (required)"), reason_input)
+                    }
+                };
+
+                let prompt_block = Block::default()
+                    .borders(Borders::ALL)
+                    .title(prompt_title);
+
+                let editing = !matches!(focus, MarkingFocus::Choices | MarkingFocus::Failures { .. });
+
+                let prompt_paragraph = Paragraph::new(prompt_input.value())
+                    .block(
+                        if editing {
+                            prompt_block.border_style(Style::default().fg(Color::LightGreen))
+                        } else {
+                            prompt_block
+                        }
+                    );
+
+                frame.render_widget(prompt_paragraph, comment_chunk);
+
+                if editing {
+                    frame.set_cursor(
+                        comment_chunk.x + prompt_input.cursor() as u16 + 1,
+                        comment_chunk.y + 1,
+                    );
+                }
+
                 let progress = {
                     let n_journals = match app.opened() {
                         Opened::Automatically { n_journals_till_marked: 0 } => {
                             String::from("Final journal")
                         }
+                        #[allow(clippy::useless_format)]
                         Opened::Automatically { n_journals_till_marked: 1 } => {
                             format!("1 journal to go")
                         }
@@ -221,15 +385,105 @@ impl<B: Backend + Send + 'static> UiPage<B> for MarkingUi<B> {
                         }
                     };
 
-                    format!("{n_journals} | {sync_status}")
+                    let n_retrying   = app.journals().n_retrying();
+                    let n_failed     = app.journals().failed_submissions().count();
+                    let n_conflicted = app.journals().conflicts().count();
+
+                    let failures_key = key_name(app.globals().keys().failures);
+
+                    let retry_status = match (n_retrying, n_failed) {
+                        (0, 0) => String::new(),
+                        (n_retrying, 0) => format!(" | {n_retrying} retrying"),
+                        (0, n_failed) => format!(" | {n_failed} failed (press <{failures_key}>)"),
+                        (n_retrying, n_failed) => format!(" | {n_retrying} retrying | {n_failed} failed (press <{failures_key}>)"),
+                    };
+
+                    // only shown while something else has focus -- otherwise the conflict is on screen
+                    let conflict_status = match n_conflicted {
+                        0 => String::new(),
+                        n_conflicted => format!(" | {n_conflicted} conflicted"),
+                    };
+
+                    format!("{n_journals} | {sync_status}{retry_status}{conflict_status}")
                 };
 
                 frame.render_widget(Paragraph::new(progress), progress_chunk);
             }
         }
+
+        if app.conflict_has_focus() {
+            if let Some(submission) = app.journals().conflicts().next() {
+                draw_conflict(frame, submission, app.journals().conflicts().count(), app.conflict_choice());
+            }
+        }
     }
 
     fn update(&mut self) {
         self.ticker += 1;
     }
 }
+
+fn key_name(key: char) -> String {
+    match key {
+        ' ' => String::from("space"),
+        key => key.to_string(),
+    }
+}
+
+fn draw_conflict<B: Backend>(frame: &mut Frame<B>, submission: &Submission, n_conflicts: usize, choice: Option<Resolution>) {
+    let conflict = submission.conflict().expect("submission is conflicted");
+
+    let size = frame.size();
+    let area = Rect::new(size.width / 10, size.height / 10, size.width - size.width / 5, size.height - size.height / 5);
+
+    let changed_by = match (&conflict.theirs.stamp.by, &conflict.theirs.stamp.at) {
+        (Some(by), Some(at)) => format!("{by} at {at}"),
+        (Some(by), None)     => by.to_string(),
+        (None, _)            => String::from("someone else"),
+    };
+
+    let options = [
+        (Resolution::KeepTheirs, "keep their changes"),
+        (Resolution::Overwrite,  "overwrite them"),
+        (Resolution::Merge,      "add yours after theirs"),
+    ]
+        .iter()
+        .map(|&(resolution, label)| match choice {
+            Some(choice) if choice == resolution => format!("[>> {label} <<]"),
+            _ => format!("[ {label} ]"),
+        })
+        .collect::<Vec<_>>()
+        .join("  ");
+
+    let text = format!(
+        "{} was changed by {changed_by} since you opened it.\n\
+        Press <tab> to pick what to do, then <enter> to confirm.\n\
+        \n\
+        {options}\n\
+        \n\
+        ---- theirs ----\n\
+        {}\n\
+        ---- yours ----\n\
+        {}",
+        submission.tag().student_id(),
+        conflict.theirs.text.trim_end(),
+        conflict.ours.trim(),
+    );
+
+    let title = match n_conflicts {
+        1 => String::from("Conflict"),
+        n => format!("Conflict (1 of {n})"),
+    };
+
+    let dialog = Paragraph::new(text)
+        .block(
+            Block::default()
+                .title(title)
+                .borders(Borders::ALL)
+                .border_style(Style::default().fg(Color::Red))
+        )
+        .wrap(Wrap { trim: false });
+
+    frame.render_widget(Clear, area);
+    frame.render_widget(dialog, area);
+}
@@ -13,7 +13,7 @@ use futures_timer::Delay;
 use tokio::select;
 use tui::{Frame, backend::{Backend, CrosstermBackend}, Terminal};
 
-use crate::{app::auth::AppPreAuth, imark::Globals, term::TerminalSettings};
+use crate::{app::auth::AppPreAuth, imark::{Globals, credentials::StoredCredentials}, term::TerminalSettings};
 
 #[async_trait]
 pub trait AppPage<B> {
@@ -41,17 +41,17 @@ pub trait UiPage<B> {
     fn update(&mut self);
 }
 
-pub async fn launch(globals: Globals) -> Result<()> {
+pub async fn launch(globals: Globals, credentials: StoredCredentials) -> Result<()> {
     let mut terminal = TerminalSettings::mangle_terminal(std::io::stdout(), CrosstermBackend::new)?;
 
-    main_loop(terminal.terminal_mut(), globals).await?;
+    main_loop(terminal.terminal_mut(), globals, credentials).await?;
 
     Ok(())
 }
 
-async fn main_loop<B: Backend + Send + 'static>(terminal: &mut Terminal<B>, globals: Globals) -> Result<()> {
+async fn main_loop<B: Backend + Send + 'static>(terminal: &mut Terminal<B>, globals: Globals, credentials: StoredCredentials) -> Result<()> {
     let mut event_reader = EventStream::new();
-    let mut app: Box<dyn AppPage<B>> = Box::new(AppPreAuth::<B>::new(globals));
+    let mut app: Box<dyn AppPage<B>> = Box::new(AppPreAuth::<B>::new(globals, credentials));
 
     loop {
         let timeout = Delay::new(Duration::from_millis(10)).fuse();
@@ -60,6 +60,7 @@ async fn main_loop<B: Backend + Send + 'static>(terminal: &mut Terminal<B>, glob
         let event = select! {
             _     = timeout => None,
             event = event   => {
+                #[allow(clippy::useless_conversion)]
                 let event: Result<_> = event.ok_or(anyhow!("Couldn't read input from terminal")).into();
                 let event = event??;
                 Some(event)
@@ -81,6 +82,7 @@ async fn main_loop<B: Backend + Send + 'static>(terminal: &mut Terminal<B>, glob
     Ok(())
 }
 
+#[allow(clippy::single_match)]
 fn should_quit(event: Option<Event>) -> bool {
     match event {
         Some(Event::Key(key)) => {
@@ -2,3 +2,19 @@ pub mod task;
 pub mod tmux;
 
 pub const HOTKEYS: &str = "1234567890wertyuiop";
+
+/// FNV-1a over `parts`, starting from `seed`. Unlike `std`'s hashers,
+/// this gives the same answer on every machine and every version of flymark.
+pub fn stable_hash(seed: u64, parts: &[&str]) -> u64 {
+    let mut hash = 0xcbf29ce484222325 ^ seed;
+
+    for part in parts {
+        // a separator, so ("ab", "c") and ("a", "bc") hash differently
+        for byte in part.bytes().chain([0xff]) {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    hash
+}
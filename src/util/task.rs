@@ -38,6 +38,7 @@ impl<T: Send + 'static> Task<T> {
 }
 
 impl<T> Drop for Task<T> {
+    #[allow(clippy::collapsible_if)]
     fn drop(&mut self) {
         if self.panic_on_drop && !matches!(self.receiver.try_recv(), Err(TryRecvError::Closed)) {
             if !thread::panicking() {